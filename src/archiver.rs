@@ -1,46 +1,356 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use colored::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, hash_map::Entry};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::index::Index;
+use crate::index::{
+    BASE_CHUNK, DEDUP_CONTENT, DEDUP_HASH, DEDUP_NONE, Index, SPECIAL_BLOCK_DEVICE,
+    SPECIAL_CHAR_DEVICE, SPECIAL_FIFO, SPECIAL_SOCKET, magic_number, plain_magic_number,
+    revision_word,
+};
 use crate::serializer::SimpleBinRepr;
-use crate::utils::{GenericFile, blake3_hash_streaming, compress_and_encrypt};
+use crate::utils::{
+    Codec, GenericFile, blake3_hash_streaming, compress, compress_and_encrypt, encrypt_only,
+};
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use humansize::{DECIMAL, format_size};
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
 use rand::SeedableRng;
 use rand::seq::SliceRandom;
 use rand_chacha::ChaCha20Rng;
 
-fn list_all_files_recursive(dir: &Path) -> Result<Vec<PathBuf>> {
+type SpecialFile = (PathBuf, (u64, u64));
+/// The path of a symbolic link and its target.
+type Symlink = (PathBuf, PathBuf);
+
+/// The directory that is archived, either local or on an SFTP server.
+pub enum SourceTree {
+    Local(PathBuf),
+    /// The SFTP session, the absolute directory on the server and a description
+    /// (`user@host:path`) for messages and `--store-root`.
+    Remote(ssh2::Sftp, PathBuf, String),
+}
+
+impl SourceTree {
+    /// Opens a file given relative to the source directory.
+    pub(crate) fn open(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        match self {
+            SourceTree::Local(root) => Ok(Box::new(fs::File::open(root.join(path))?)),
+            SourceTree::Remote(sftp, root, _) => Ok(Box::new(sftp.open(root.join(path))?)),
+        }
+    }
+
+    /// The size and permission bits of a file, following symbolic links like the local
+    /// walk does.
+    pub(crate) fn file_len(&self, path: &Path) -> Result<(u64, Option<u64>)> {
+        match self {
+            SourceTree::Local(root) => {
+                let metadata = fs::metadata(root.join(path))?;
+                Ok((metadata.len(), Some(metadata.mode() as u64 & 0o7777)))
+            }
+            SourceTree::Remote(sftp, root, _) => {
+                let stat = sftp.stat(&root.join(path))?;
+                let len = stat
+                    .size
+                    .context("The server did not report the file size")?;
+                Ok((len, stat.perm.map(|p| p as u64 & 0o7777)))
+            }
+        }
+    }
+
+    /// The permission bits of a file or directory, if they can be read.
+    fn mode(&self, path: &Path) -> Option<u64> {
+        let mode = match self {
+            SourceTree::Local(root) => fs::metadata(root.join(path)).ok()?.mode(),
+            SourceTree::Remote(sftp, root, _) => sftp.stat(&root.join(path)).ok()?.perm?,
+        };
+        Some(mode as u64 & 0o7777)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        match self {
+            SourceTree::Local(root) => root.join(path).is_file(),
+            SourceTree::Remote(sftp, root, _) => {
+                sftp.stat(&root.join(path)).is_ok_and(|stat| stat.is_file())
+            }
+        }
+    }
+
+    /// Where the files come from, as recorded by `--store-root`.
+    fn describe(&self) -> Result<String> {
+        match self {
+            SourceTree::Local(root) => {
+                let root = fs::canonicalize(root).context("Source directory not found")?;
+                Ok(root
+                    .to_str()
+                    .context("Source path is not valid UTF-8")?
+                    .to_string())
+            }
+            SourceTree::Remote(_, _, description) => Ok(description.clone()),
+        }
+    }
+}
+
+pub(crate) fn list_all_files_recursive(
+    dir: &Path,
+    no_hidden: bool,
+    excludes: &GlobSet,
+    dereference: bool,
+    pruned: &mut usize,
+) -> Result<(Vec<PathBuf>, Vec<SpecialFile>, Vec<Symlink>)> {
     let mut files = Vec::new();
-    recurse_dir_files(dir, dir, &mut files)?;
-    Ok(files)
+    let mut specials = Vec::new();
+    let mut symlinks = Vec::new();
+    let mut ancestors = vec![dir_id(dir)?];
+    recurse_dir_files(
+        dir,
+        dir,
+        &mut files,
+        &mut specials,
+        &mut symlinks,
+        no_hidden,
+        excludes,
+        dereference,
+        &mut ancestors,
+        pruned,
+    )?;
+    Ok((files, specials, symlinks))
 }
-fn list_all_empty_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+pub(crate) fn list_all_empty_dirs(
+    dir: &Path,
+    no_hidden: bool,
+    excludes: &GlobSet,
+    dereference: bool,
+) -> Result<Vec<PathBuf>> {
     let mut empties = Vec::new();
-    recurse_dir_empties(dir, dir, &mut empties)?;
+    let mut ancestors = vec![dir_id(dir)?];
+    recurse_dir_empties(
+        dir,
+        dir,
+        &mut empties,
+        no_hidden,
+        excludes,
+        dereference,
+        &mut ancestors,
+    )?;
     Ok(empties)
 }
 
-fn recurse_dir_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+/// The modes of the directories that hold the given entries, and of the empty directories.
+pub(crate) fn dir_modes(
+    source: &SourceTree,
+    files: &[PathBuf],
+    empty_dirs: &[PathBuf],
+    symlinks: &[Symlink],
+) -> HashMap<PathBuf, u64> {
+    let mut dirs = HashSet::new();
+    for path in files
+        .iter()
+        .chain(empty_dirs)
+        .chain(symlinks.iter().map(|(p, _)| p))
+    {
+        dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+    }
+    dirs.extend(empty_dirs.iter().cloned());
+    dirs.remove(Path::new(""));
+    dirs.into_iter()
+        .filter_map(|dir| source.mode(&dir).map(|mode| (dir, mode)))
+        .collect()
+}
+
+/// Device and inode of a directory. Those of the directories on the way down are kept to
+/// notice symbolic links that lead back up, which would otherwise be followed forever.
+fn dir_id(dir: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(dir)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// Entries matched by an exclude pattern, either with their path relative to the source
+/// directory or with their name alone. An excluded directory is skipped with everything
+/// below it.
+fn is_excluded(root: &Path, path: &Path, excludes: &GlobSet) -> bool {
+    let Ok(relative_path) = path.strip_prefix(root) else {
+        return false;
+    };
+    excludes.is_match(relative_path)
+        || relative_path
+            .file_name()
+            .is_some_and(|name| excludes.is_match(name))
+}
+
+/// Compiles the exclude patterns into one set, so each path is matched once. `*` stays
+/// within a directory, `**` spans any number of them.
+pub fn build_excludes(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid exclude pattern: {pattern}"))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+/// Reads exclude patterns from a file, one per line. Empty lines and lines starting
+/// with `#` are ignored.
+pub fn read_exclude_file(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path).context("Exclude file could not be read")?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The number of files (and special files) below `dir`, for reporting pruned directories.
+fn count_files(dir: &Path) -> usize {
+    let Ok(ls) = fs::read_dir(dir) else {
+        return 0;
+    };
+    ls.filter_map(|e| e.ok())
+        .map(|e| {
+            if e.file_type().is_ok_and(|t| t.is_dir()) {
+                count_files(&e.path())
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
+/// Entries whose name starts with a dot. Only the name is checked, so a hidden source
+/// directory is still archived.
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    entry.file_name().as_encoded_bytes().starts_with(b".")
+}
+
+/// The paths of a `--paths-from` list, relative to the source directory.
+pub(crate) fn read_paths_from(
+    root: &SourceTree,
+    list: &Path,
+    skip_errors: bool,
+) -> Result<Vec<PathBuf>> {
+    let content = fs::read_to_string(list).context("Path list could not be read")?;
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        match checked_relative_path(root, Path::new(line)) {
+            Ok(relative_path) => {
+                if seen.insert(relative_path.clone()) {
+                    files.push(relative_path);
+                }
+            }
+            Err(e) if skip_errors => {
+                warn!("{}:\n{} ({})", "Skipping path".yellow().bold(), line, e);
+            }
+            Err(e) => return Err(e.context(format!("Invalid entry in path list: {line}"))),
+        }
+    }
+    Ok(files)
+}
+
+fn checked_relative_path(root: &SourceTree, path: &Path) -> Result<PathBuf> {
+    let root_dir = match root {
+        SourceTree::Local(dir) | SourceTree::Remote(_, dir, _) => dir,
+    };
+    let path = if path.is_absolute() {
+        path.strip_prefix(root_dir)
+            .map_err(|_| anyhow!("Path is not under the source directory"))?
+    } else {
+        path
+    };
+    let mut relative_path = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(part) => relative_path.push(part),
+            Component::CurDir => {}
+            _ => return Err(anyhow!("Path is not under the source directory")),
+        }
+    }
+    if !root.is_file(&relative_path) {
+        return Err(anyhow!("Not a file"));
+    }
+    Ok(relative_path)
+}
+
+/// Symbolic links are stored as links, or followed with `dereference`.
+#[allow(clippy::too_many_arguments)]
+fn recurse_dir_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+    specials: &mut Vec<SpecialFile>,
+    symlinks: &mut Vec<Symlink>,
+    no_hidden: bool,
+    excludes: &GlobSet,
+    dereference: bool,
+    ancestors: &mut Vec<(u64, u64)>,
+    pruned: &mut usize,
+) -> Result<()> {
     let ls = fs::read_dir(dir)?.collect::<Vec<_>>();
     for entry in ls {
         let entry = entry?;
+        if no_hidden && is_hidden(&entry) {
+            continue;
+        }
         let path = entry.path();
+        let file_type = entry.file_type()?;
+        if is_excluded(root, &path, excludes) {
+            *pruned += if file_type.is_dir() {
+                count_files(&path)
+            } else {
+                1
+            };
+            continue;
+        }
 
-        if path.is_dir() {
-            // Recurse into subdirectories
-            recurse_dir_files(root, &path, files)?;
+        if file_type.is_symlink() && !dereference {
+            if let Ok(relative_path) = path.strip_prefix(root) {
+                symlinks.push((relative_path.to_path_buf(), fs::read_link(&path)?));
+            }
+        } else if path.is_dir() {
+            let id = dir_id(&path)?;
+            if ancestors.contains(&id) {
+                warn!(
+                    "{}:\n{}",
+                    "Skipping symbolic link loop".yellow().bold(),
+                    path.to_string_lossy()
+                );
+                continue;
+            }
+            ancestors.push(id);
+            recurse_dir_files(
+                root,
+                &path,
+                files,
+                specials,
+                symlinks,
+                no_hidden,
+                excludes,
+                dereference,
+                ancestors,
+                pruned,
+            )?;
+            ancestors.pop();
         } else if path.is_file() {
             if let Ok(relative_path) = path.strip_prefix(root) {
                 files.push(relative_path.to_path_buf());
             }
+        } else if let Some(kind) = special_kind(&path) {
+            if let Ok(relative_path) = path.strip_prefix(root) {
+                specials.push((relative_path.to_path_buf(), kind));
+            }
         } else {
-            println!(
+            warn!(
                 "{}:\n{}",
                 "Ignoring non-file object".yellow().bold(),
                 path.to_string_lossy()
@@ -50,18 +360,170 @@ fn recurse_dir_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Resul
 
     Ok(())
 }
-fn recurse_dir_empties(root: &Path, dir: &Path, empties: &mut Vec<PathBuf>) -> Result<()> {
+
+/// Lists a remote directory tree like `recurse_dir_files` and `recurse_dir_empties` together.
+/// Symbolic links are stored as links, or followed with `dereference`. Loops are noticed by
+/// the resolved paths of the directories on the way down (`ancestors`), since SFTP reports
+/// no inodes. Special files can not be recreated from what SFTP reports, so they are ignored
+/// with a warning.
+#[allow(clippy::too_many_arguments)]
+fn recurse_remote_dir(
+    sftp: &ssh2::Sftp,
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+    empties: &mut Vec<PathBuf>,
+    symlinks: &mut Vec<Symlink>,
+    no_hidden: bool,
+    excludes: &GlobSet,
+    dereference: bool,
+    ancestors: &mut Vec<PathBuf>,
+    pruned: &mut usize,
+) -> Result<()> {
+    let mut is_empty = true;
+    let mut excluded = false;
+    for (path, stat) in sftp.readdir(dir)? {
+        let hidden = path
+            .file_name()
+            .is_some_and(|n| n.as_encoded_bytes().starts_with(b"."));
+        if no_hidden && hidden {
+            continue;
+        }
+        if is_excluded(root, &path, excludes) {
+            // Excluded directories are not listed just to count their files.
+            *pruned += 1;
+            excluded = true;
+            continue;
+        }
+        is_empty = false;
+        let stat = if stat.file_type().is_symlink() {
+            if !dereference {
+                if let Ok(relative_path) = path.strip_prefix(root) {
+                    symlinks.push((relative_path.to_path_buf(), sftp.readlink(&path)?));
+                }
+                continue;
+            }
+            sftp.stat(&path)?
+        } else {
+            stat
+        };
+        if stat.is_dir() {
+            let real = sftp.realpath(&path)?;
+            if ancestors.contains(&real) {
+                warn!(
+                    "{}:\n{}",
+                    "Skipping symbolic link loop".yellow().bold(),
+                    path.to_string_lossy()
+                );
+                continue;
+            }
+            ancestors.push(real);
+            recurse_remote_dir(
+                sftp,
+                root,
+                &path,
+                files,
+                empties,
+                symlinks,
+                no_hidden,
+                excludes,
+                dereference,
+                ancestors,
+                pruned,
+            )?;
+            ancestors.pop();
+        } else if stat.is_file() {
+            if let Ok(relative_path) = path.strip_prefix(root) {
+                files.push(relative_path.to_path_buf());
+            }
+        } else {
+            warn!(
+                "{}:\n{}",
+                "Ignoring non-file object".yellow().bold(),
+                path.to_string_lossy()
+            );
+        }
+    }
+    if is_empty && !excluded && dir != root {
+        if let Ok(relative_path) = dir.strip_prefix(root) {
+            empties.push(relative_path.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// The kind and device number of FIFOs, sockets and device nodes.
+fn special_kind(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_fifo() {
+        SPECIAL_FIFO
+    } else if file_type.is_char_device() {
+        SPECIAL_CHAR_DEVICE
+    } else if file_type.is_block_device() {
+        SPECIAL_BLOCK_DEVICE
+    } else if file_type.is_socket() {
+        SPECIAL_SOCKET
+    } else {
+        return None;
+    };
+    Some((kind, metadata.rdev()))
+}
+/// Loops of symbolic links were already reported while listing the files.
+fn recurse_dir_empties(
+    root: &Path,
+    dir: &Path,
+    empties: &mut Vec<PathBuf>,
+    no_hidden: bool,
+    excludes: &GlobSet,
+    dereference: bool,
+    ancestors: &mut Vec<(u64, u64)>,
+) -> Result<()> {
     let ls = fs::read_dir(dir)?.collect::<Vec<_>>();
     for entry in ls {
         let entry = entry?;
+        if no_hidden && is_hidden(&entry) {
+            continue;
+        }
         let path = entry.path();
-        if path.is_dir() {
-            if fs::read_dir(&path)?.next().is_none() {
-                if let Ok(relative_path) = path.strip_prefix(root) {
-                    empties.push(relative_path.to_path_buf());
+        if is_excluded(root, &path, excludes) {
+            continue;
+        }
+        let is_dir = if dereference {
+            path.is_dir()
+        } else {
+            entry.file_type()?.is_dir()
+        };
+        if is_dir {
+            let id = dir_id(&path)?;
+            if ancestors.contains(&id) {
+                continue;
+            }
+            // A directory with only hidden entries is stored as empty. One that only held
+            // excluded entries is dropped like them.
+            let visible = fs::read_dir(&path)?
+                .filter_map(|e| e.ok())
+                .filter(|e| !(no_hidden && is_hidden(e)))
+                .map(|e| e.path())
+                .collect::<Vec<_>>();
+            if visible.iter().all(|p| is_excluded(root, p, excludes)) {
+                if visible.is_empty() {
+                    if let Ok(relative_path) = path.strip_prefix(root) {
+                        empties.push(relative_path.to_path_buf());
+                    }
                 }
             } else {
-                recurse_dir_empties(root, &path, empties)?;
+                ancestors.push(id);
+                recurse_dir_empties(
+                    root,
+                    &path,
+                    empties,
+                    no_hidden,
+                    excludes,
+                    dereference,
+                    ancestors,
+                )?;
+                ancestors.pop();
             }
         }
     }
@@ -69,115 +531,1024 @@ fn recurse_dir_empties(root: &Path, dir: &Path, empties: &mut Vec<PathBuf>) -> R
     Ok(())
 }
 
+/// How `build_archive` archives the source. The defaults are those of `create`.
+pub(crate) struct BuildSettings<'a> {
+    pub level: i32,
+    pub long_window: Option<u32>,
+    pub codec: Codec,
+    /// Archive exactly these paths instead of listing the source.
+    pub paths_from: Option<&'a [PathBuf]>,
+    pub skip_errors: bool,
+    pub checkpoint_interval: Option<u64>,
+    pub preserve_special: bool,
+    pub preserve_hardlinks: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub verify_consistency: bool,
+    pub no_hidden: bool,
+    pub excludes: GlobSet,
+    pub dereference: bool,
+    pub hash_dedup: bool,
+    pub no_dedup: bool,
+    pub auto_level: bool,
+    pub threads: usize,
+    /// Archive the files in sorted order (unless there is a `seed`).
+    pub deterministic: bool,
+    pub seed: Option<u64>,
+    pub store_root: bool,
+    pub index_level: i32,
+    pub max_archive_size: Option<u64>,
+    pub left_out_file: Option<&'a Path>,
+    /// The base archive of a delta archive.
+    pub base: Option<&'a Index>,
+}
+
+impl Default for BuildSettings<'_> {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            long_window: None,
+            codec: Codec::Zstd,
+            paths_from: None,
+            skip_errors: false,
+            checkpoint_interval: None,
+            preserve_special: false,
+            preserve_hardlinks: false,
+            min_size: None,
+            max_size: None,
+            verify_consistency: false,
+            no_hidden: false,
+            excludes: GlobSet::empty(),
+            dereference: false,
+            hash_dedup: false,
+            no_dedup: false,
+            auto_level: false,
+            threads: 1,
+            deterministic: false,
+            seed: None,
+            store_root: false,
+            index_level: 22,
+            max_archive_size: None,
+            left_out_file: None,
+            base: None,
+        }
+    }
+}
+
 pub(crate) fn build_archive(
-    source: &Path,
+    source: &SourceTree,
     archive: &mut GenericFile,
-    recipients: Vec<Box<dyn age::Recipient + Send>>,
-    level: i32,
-) -> Result<()> {
-    let magic_number = 12219678139600706333_u64;
+    recipients: &Vec<Box<dyn age::Recipient + Send>>,
+    settings: &BuildSettings,
+) -> Result<Index> {
+    let &BuildSettings {
+        level,
+        long_window,
+        codec,
+        paths_from,
+        skip_errors,
+        checkpoint_interval,
+        preserve_special,
+        preserve_hardlinks,
+        min_size,
+        max_size,
+        verify_consistency,
+        no_hidden,
+        ref excludes,
+        dereference,
+        hash_dedup,
+        no_dedup,
+        auto_level,
+        threads,
+        deterministic,
+        seed,
+        store_root,
+        index_level,
+        max_archive_size,
+        left_out_file,
+        base,
+    } = settings;
+    let magic_number = if recipients.is_empty() {
+        plain_magic_number()
+    } else {
+        magic_number()
+    };
     magic_number.write_bin(archive)?;
-    let mut file_list =
-        list_all_files_recursive(source).context("Directory could not be listed")?;
-    let mut empty_dirs = list_all_empty_dirs(source).context("Directory could not be listed")?;
-    let mut rng = ChaCha20Rng::from_os_rng();
-
-    file_list.shuffle(&mut rng);
-    empty_dirs.shuffle(&mut rng);
+    let (mut file_list, mut empty_dirs, mut specials, mut symlinks) = match paths_from {
+        Some(list) => (list.to_vec(), vec![], vec![], vec![]),
+        None => {
+            let mut pruned = 0;
+            let (files, empty_dirs, specials, symlinks) = match source {
+                SourceTree::Local(dir) => {
+                    let (files, specials, symlinks) = list_all_files_recursive(
+                        dir,
+                        no_hidden,
+                        excludes,
+                        dereference,
+                        &mut pruned,
+                    )
+                    .context("Directory could not be listed")?;
+                    let empty_dirs = list_all_empty_dirs(dir, no_hidden, excludes, dereference)
+                        .context("Directory could not be listed")?;
+                    (files, empty_dirs, specials, symlinks)
+                }
+                SourceTree::Remote(sftp, dir, _) => {
+                    let (mut files, mut empty_dirs, mut symlinks) = (vec![], vec![], vec![]);
+                    let mut ancestors = vec![sftp.realpath(dir)?];
+                    recurse_remote_dir(
+                        sftp,
+                        dir,
+                        dir,
+                        &mut files,
+                        &mut empty_dirs,
+                        &mut symlinks,
+                        no_hidden,
+                        excludes,
+                        dereference,
+                        &mut ancestors,
+                        &mut pruned,
+                    )
+                    .context("Remote directory could not be listed")?;
+                    (files, empty_dirs, vec![], symlinks)
+                }
+            };
+            if !excludes.is_empty() {
+                info!("{} files excluded", pruned);
+            }
+            (files, empty_dirs, specials, symlinks)
+        }
+    };
+    if deterministic {
+        // The listing order depends on the file system, so everything starts out sorted.
+        file_list.sort();
+        empty_dirs.sort();
+        specials.sort();
+        symlinks.sort();
+    }
+    let dedup_mode = if no_dedup {
+        DEDUP_NONE
+    } else if hash_dedup {
+        DEDUP_HASH
+    } else {
+        DEDUP_CONTENT
+    };
+    let mut metadata = if store_root {
+        let root = source.describe()?;
+        let hostname = nix::unistd::gethostname()?;
+        vec![
+            ("source_root".to_string(), root),
+            (
+                "hostname".to_string(),
+                hostname.to_string_lossy().to_string(),
+            ),
+        ]
+    } else {
+        vec![]
+    };
+    // Chunks are read by their first bytes, this is for info (and update) only.
+    if codec != Codec::Zstd {
+        if let Some(name) = codec.to_possible_value() {
+            metadata.push(("codec".to_string(), name.get_name().to_string()));
+        }
+    }
+    let special_files = if preserve_special {
+        specials.clone()
+    } else {
+        vec![]
+    };
+    // Directory modes are read up front, file modes as the files are opened.
+    let mut modes = dir_modes(source, &file_list, &empty_dirs, &symlinks);
+    // Chunks of the base archive by hash. Files with the same hash are not stored again.
+    let base_chunks = base
+        .map(|b| {
+            b.mapping
+                .values()
+                .filter(|(i, _)| i & BASE_CHUNK == 0)
+                .filter_map(|(i, len)| {
+                    Some((*b.hashes.get(i)?, (i | BASE_CHUNK, *len, *b.sizes.get(i)?)))
+                })
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+    let mut from_base = 0;
+    match seed {
+        Some(seed) => {
+            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+            file_list.shuffle(&mut rng);
+            empty_dirs.shuffle(&mut rng);
+        }
+        None if deterministic => {}
+        None => {
+            let mut rng = ChaCha20Rng::from_os_rng();
+            file_list.shuffle(&mut rng);
+            empty_dirs.shuffle(&mut rng);
+        }
+    }
+    // Only the first path of each inode is archived, the others are linked to it at the end.
+    let hardlinks = match source {
+        _ if !preserve_hardlinks => vec![],
+        SourceTree::Local(dir) => take_hard_links(dir, &mut file_list),
+        SourceTree::Remote(..) => {
+            warn!(
+                "{}",
+                "Hard links can not be detected over SFTP".yellow().bold()
+            );
+            vec![]
+        }
+    };
 
     let mut hashes = HashMap::new();
     let mut dedup_hashes = vec![];
+    // The sizes of the stored files, to tell if a file could be a duplicate at all.
+    let mut stored_sizes = HashSet::new();
     let mut mapping = HashMap::new();
     let mut sizes = HashMap::new();
     let mut current_index = 8;
-    let pb = ProgressBar::new(file_list.len() as u64);
+    let mut skipped = 0;
+    let mut out_of_range = vec![];
+    let mut changed = 0;
+    let mut levels = BTreeMap::new();
+    let mut left_out = vec![];
+    let mut rewound = false;
+    let mut index_estimate = Index {
+        mapping: HashMap::new(),
+        hashes: HashMap::new(),
+        sizes: HashMap::new(),
+        magic_number,
+        empty_dirs: empty_dirs.clone(),
+        special_files: special_files.clone(),
+        dedup_mode,
+        metadata: metadata.clone(),
+        modes: modes.clone(),
+        symlinks: symlinks.clone(),
+        hardlinks: hardlinks.clone(),
+    }
+    .simple_bin_vec()?
+    .len() as u64
+        + hardlinks
+            .iter()
+            .map(|(link, target)| index_entry_size(link) + index_entry_size(target))
+            .sum::<u64>();
+    if let Some(max) = max_archive_size {
+        if 8 + index_bound(index_estimate, recipients.len()) > max {
+            return Err(anyhow!("Not even an empty index fits into {} bytes", max));
+        }
+    }
+    let settings = PrepareSettings {
+        min_size,
+        max_size,
+        verify_consistency,
+        level,
+        auto_level,
+        long_window,
+        codec,
+    };
+    // The progress bar counts bytes, so one large file does not stall it. Files that cannot
+    // be read here count as empty, they are reported when they are archived.
+    let file_lens: HashMap<&Path, u64> = file_list
+        .iter()
+        .map(|path| {
+            let len = source.file_len(path).map(|(len, _)| len).unwrap_or(0);
+            (path.as_path(), len)
+        })
+        .collect();
+    let mut done_bytes = 0;
+    let pb = ProgressBar::new(file_lens.values().sum());
     pb.set_style(
-        ProgressStyle::with_template("{bar:40} {pos:>7}/{len:7}\nfile: {msg}")
-            .context("Progress bar error")?,
+        ProgressStyle::with_template(
+            "{bar:40} {bytes:>10}/{total_bytes:10} {bytes_per_sec:>12} [{elapsed_precise}, eta {eta}]\nfile: {msg}",
+        )
+        .context("Progress bar error")?,
     );
     println!();
 
-    for (i, in_path) in file_list.iter().enumerate() {
-        let mut read_path = PathBuf::new();
-        read_path.push(source);
-        read_path.push(in_path);
-        // let raw = fs::read(&read_path)?;
-        // let raw_size = raw.len() as u64;
-        let raw_size = fs::metadata(&read_path)?.len();
-        pb.set_position(i as u64);
-        pb.set_message(format!(
-            "{} ({})",
-            &in_path.to_string_lossy(),
-            format_size(raw_size, DECIMAL)
-        ));
-        let hash = blake3_hash_streaming(&mut fs::File::open(&read_path)?)?;
-        // let processed = encrypt(&compress(&raw, level)?, &recipients)?;
-        // let chunk_len = processed.len() as u64;
-        let candidates = dedup_hashes
-            .iter()
-            .filter(|(_, h)| *h == hash)
-            .map(|(p, _)| p);
-
-        let mut dedup_partner = None;
-        for c in candidates {
-            let mut ref_path = PathBuf::new();
-            ref_path.push(source);
-            ref_path.push(c);
+    // The next file a worker thread takes.
+    let next = AtomicUsize::new(0);
+    // Permissions to take another file, only used with `deterministic`.
+    let (ticket_tx, ticket_rx) = std::sync::mpsc::channel();
+    let ticket_rx = std::sync::Mutex::new(ticket_rx);
+    std::thread::scope(|scope| -> Result<()> {
+        let prepared: Box<dyn Iterator<Item = (&PathBuf, Result<Prepared>)> + '_> = if threads > 1 {
+            // Workers take the next file from the shared list and compress it into memory.
+            // The chunks are encrypted and written here, in the order they are finished.
+            // With `deterministic`, they are written in list order instead. A worker then needs
+            // a ticket for each file, so it can not run ahead of the writer by more than a few
+            // files.
+            let (tx, rx) = std::sync::mpsc::sync_channel(threads);
+            let tickets = deterministic.then_some(&ticket_rx);
+            for _ in 0..2 * threads {
+                ticket_tx.send(())?;
+            }
+            for _ in 0..threads {
+                let tx = tx.clone();
+                let next = &next;
+                let file_list = &file_list;
+                let settings = &settings;
+                scope.spawn(move || {
+                    loop {
+                        if let Some(tickets) = tickets {
+                            match tickets.lock() {
+                                Ok(tickets) if tickets.recv().is_ok() => {}
+                                _ => break,
+                            }
+                        }
+                        let n = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(in_path) = file_list.get(n) else {
+                            break;
+                        };
+                        let prepared = prepare_file(source, in_path, settings, true);
+                        if tx.send((n, in_path, prepared)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            // The receiver ends once every worker is done.
+            drop(tx);
+            if deterministic {
+                let mut finished = BTreeMap::new();
+                let mut wanted = 0;
+                Box::new(std::iter::from_fn(move || {
+                    loop {
+                        if let Some(item) = finished.remove(&wanted) {
+                            wanted += 1;
+                            let _ = ticket_tx.send(());
+                            return Some(item);
+                        }
+                        let (n, in_path, prepared) = rx.recv().ok()?;
+                        finished.insert(n, (in_path, prepared));
+                    }
+                }))
+            } else {
+                Box::new(
+                    rx.into_iter()
+                        .map(|(_, in_path, prepared)| (in_path, prepared)),
+                )
+            }
+        } else {
+            Box::new(
+                file_list
+                    .iter()
+                    .map(|in_path| (in_path, prepare_file(source, in_path, &settings, false))),
+            )
+        };
 
-            if files_equal(fs::File::open(&read_path)?, fs::File::open(&ref_path)?)? {
-                dedup_partner = Some(c);
-                break;
+        for (i, (in_path, prepared)) in prepared.enumerate() {
+            let prepared = prepared?;
+            if !matches!(prepared, Prepared::Ready { .. }) {
+                done_bytes += file_lens.get(in_path.as_path()).copied().unwrap_or(0);
             }
-        }
+            let (raw_size, mode, file_level, hash_before, payload) = match prepared {
+                Prepared::OutOfRange => {
+                    out_of_range.push(in_path.clone());
+                    continue;
+                }
+                Prepared::Unreadable(e) if skip_errors => {
+                    pb.suspend(|| {
+                        warn!(
+                            "{}:\n{} ({})",
+                            "Skipping unreadable file".yellow().bold(),
+                            in_path.to_string_lossy(),
+                            e
+                        )
+                    });
+                    skipped += 1;
+                    continue;
+                }
+                Prepared::Unreadable(e) => return Err(e),
+                Prepared::Ready {
+                    raw_size,
+                    mode,
+                    file_level,
+                    hash_before,
+                    payload,
+                } => (raw_size, mode, file_level, hash_before, payload),
+            };
+            pb.set_position(done_bytes);
+            done_bytes += raw_size;
+            pb.set_message(format!(
+                "{} ({})",
+                &in_path.to_string_lossy(),
+                format_size(raw_size, DECIMAL)
+            ));
+            // With the hash known before the file is written, a duplicate or a file that is in
+            // the base archive is not compressed and written at all. Streamed files are hashed
+            // up front only if there is something they could match.
+            let early = match &payload {
+                Payload::Compressed(_, size, hash) => Some((*size, *hash)),
+                Payload::Reader(_) => hash_before.map(|hash| (raw_size, hash)).or_else(|| {
+                    let candidates =
+                        !base_chunks.is_empty() || (!no_dedup && stored_sizes.contains(&raw_size));
+                    candidates
+                        .then(|| {
+                            source
+                                .open(in_path)
+                                .and_then(|mut f| blake3_hash_streaming(&mut f))
+                        })
+                        .and_then(Result::ok)
+                        .map(|hash| (raw_size, hash))
+                }),
+            };
+            let reused = match early {
+                Some((size, hash)) => reusable_chunk(
+                    source,
+                    in_path,
+                    size,
+                    &hash,
+                    &dedup_hashes,
+                    &mapping,
+                    &sizes,
+                    hash_dedup,
+                    no_dedup,
+                    &base_chunks,
+                )?
+                .map(|chunk| (size, hash, chunk)),
+                None => None,
+            };
+            let (raw_size, hash, reused) = match reused {
+                Some((size, hash, chunk)) => (size, hash, Some(chunk)),
+                None => {
+                    let pos_start = archive.stream_position()?;
+                    let written = match payload {
+                        Payload::Reader(mut reader) => {
+                            match compress_and_encrypt(
+                                &mut reader,
+                                archive,
+                                codec,
+                                file_level,
+                                long_window,
+                                recipients,
+                            ) {
+                                Ok(()) => Ok((reader.size, *reader.hasher.finalize().as_bytes())),
+                                Err(e) => Err((e, reader.failed)),
+                            }
+                        }
+                        Payload::Compressed(data, size, hash) => {
+                            encrypt_only(&mut data.as_slice(), archive, recipients)
+                                .map(|()| (size, hash))
+                                .map_err(|e| (e, false))
+                        }
+                    };
+                    let (raw_size, hash) = match written {
+                        Ok(written) => written,
+                        Err((e, true)) if skip_errors => {
+                            pb.suspend(|| {
+                                warn!(
+                                    "{}:\n{} ({})",
+                                    "Skipping unreadable file".yellow().bold(),
+                                    in_path.to_string_lossy(),
+                                    e
+                                )
+                            });
+                            archive.seek(SeekFrom::Start(pos_start))?;
+                            rewound = true;
+                            skipped += 1;
+                            continue;
+                        }
+                        Err((e, _)) => return Err(e),
+                    };
+                    let chunk_len = archive.stream_position()? - pos_start;
+                    // The file changed after it was hashed, so the index describes what was written.
+                    if hash_before.is_some_and(|before| before != hash) {
+                        pb.suspend(|| {
+                            warn!(
+                                "{}:\n{}",
+                                "File changed while it was archived".yellow().bold(),
+                                in_path.to_string_lossy()
+                            )
+                        });
+                        changed += 1;
+                    }
 
-        match dedup_partner {
-            None => {
-                hashes.insert(current_index, hash);
-                sizes.insert(current_index, raw_size);
-                let pos_start = archive.stream_position()?;
-                compress_and_encrypt(&mut fs::File::open(read_path)?, archive, level, &recipients)?;
-                let chunk_len = archive.stream_position()? - pos_start;
-                mapping.insert(in_path.clone(), (current_index, chunk_len));
-                dedup_hashes.push((in_path.clone(), hash));
-                current_index += chunk_len;
-            }
-            Some(dedup) => {
-                let (old_i, old_len) = mapping
-                    .get(dedup)
-                    .context("Dedup partner not mapped correctly")?;
-                mapping.insert(in_path.clone(), (*old_i, *old_len));
+                    // Without the hash up front, a duplicate is only found once its chunk is
+                    // written. It is then overwritten by the next one, and whatever is left
+                    // behind the index is cut off at the end.
+                    let reused = reusable_chunk(
+                        source,
+                        in_path,
+                        raw_size,
+                        &hash,
+                        &dedup_hashes,
+                        &mapping,
+                        &sizes,
+                        hash_dedup,
+                        no_dedup,
+                        &base_chunks,
+                    )?;
+                    if reused.is_some() {
+                        archive.seek(SeekFrom::Start(pos_start))?;
+                        rewound = true;
+                    } else {
+                        let needed = pos_start
+                            + chunk_len
+                            + index_bound(
+                                index_estimate + index_entry_size(in_path),
+                                recipients.len(),
+                            );
+                        if max_archive_size.is_some_and(|max| needed > max) {
+                            archive.seek(SeekFrom::Start(pos_start))?;
+                            rewound = true;
+                            left_out.push(in_path.clone());
+                            continue;
+                        }
+                        *levels.entry(file_level).or_insert(0) += 1;
+                        hashes.insert(current_index, hash);
+                        sizes.insert(current_index, raw_size);
+                        stored_sizes.insert(raw_size);
+                        mapping.insert(in_path.clone(), (current_index, chunk_len));
+                        if !no_dedup {
+                            dedup_hashes.push((in_path.clone(), hash));
+                        }
+                        current_index += chunk_len;
+                    }
+                    (raw_size, hash, reused)
+                }
+            };
+            if let Some((chunk, in_base)) = reused {
+                if in_base {
+                    hashes.insert(chunk.0, hash);
+                    sizes.insert(chunk.0, raw_size);
+                    from_base += 1;
+                }
+                mapping.insert(in_path.clone(), chunk);
             }
-        };
+            if let Some(mode) = mode {
+                modes.insert(in_path.clone(), mode);
+            }
+            index_estimate += index_entry_size(in_path);
+            if checkpoint_interval.is_some_and(|n| (i as u64 + 1) % n == 0) {
+                // An interim index so the partial archive can be read. The final one supersedes it.
+                let mut checkpoint = Index {
+                    mapping: mapping.clone(),
+                    hashes: hashes.clone(),
+                    sizes: sizes.clone(),
+                    magic_number,
+                    empty_dirs: empty_dirs.clone(),
+                    special_files: special_files.clone(),
+                    dedup_mode,
+                    metadata: metadata.clone(),
+                    modes: modes.clone(),
+                    symlinks: symlinks.clone(),
+                    hardlinks: vec![],
+                };
+                checkpoint.hardlinks = link_files(&hardlinks, &mut checkpoint);
+                checkpoint.prune_modes();
+                write_index(&checkpoint, archive, index_level, recipients)?;
+                archive.flush()?;
+                current_index = archive.stream_position()?;
+            }
+        }
+        Ok(())
+    })?;
+    // Checked against the mapping itself, so a file that never came back from the workers
+    // is noticed even though the counters above did not see it.
+    let unaccounted = unaccounted_files(&file_list, &mapping, &[&out_of_range, &left_out]);
+    if unaccounted.len() != skipped {
+        warn!(
+            "{}: {} files listed, {} archived, {} skipped or left out, {} missing",
+            "File count mismatch".red().bold(),
+            file_list.len(),
+            mapping.len(),
+            skipped + out_of_range.len() + left_out.len(),
+            unaccounted.len().saturating_sub(skipped)
+        );
+        for path in unaccounted.iter().take(10) {
+            debug!("Not in the index: {}", path.to_string_lossy());
+        }
     }
-
-    let index = Index {
+    let mut index = Index {
         mapping,
         hashes,
         sizes,
         magic_number,
         empty_dirs,
+        special_files,
+        dedup_mode,
+        metadata,
+        modes,
+        symlinks,
+        hardlinks: vec![],
     };
+    index.hardlinks = link_files(&hardlinks, &mut index);
+    index.prune_modes();
+
+    write_index(&index, archive, index_level, recipients)?;
+    pb.finish_and_clear();
+    if let Some(max) = max_archive_size {
+        let end = archive.stream_position()?;
+        if end > max {
+            return Err(anyhow!(
+                "The index does not fit into {} bytes (the archive would be {} bytes)",
+                max,
+                end
+            ));
+        }
+    }
+    if rewound {
+        let end = archive.stream_position()?;
+        archive.set_len(end)?;
+    }
+    if skipped > 0 {
+        warn!(
+            "{}: {} of {} files",
+            "Skipped".yellow().bold(),
+            skipped,
+            file_list.len()
+        );
+    }
+    if !index.hardlinks.is_empty() {
+        info!("{} files are stored as hard links", index.hardlinks.len());
+    }
+    // Links share the fate of the file they link to.
+    for (link, target) in &hardlinks {
+        if out_of_range.contains(target) {
+            out_of_range.push(link.clone());
+        }
+        if left_out.contains(target) {
+            left_out.push(link.clone());
+        }
+    }
+    if base.is_some() {
+        info!("{} files are stored in the base archive", from_base);
+    }
+    if auto_level {
+        let levels = levels
+            .iter()
+            .map(|(level, n)| format!("level {level}: {n} files"))
+            .collect::<Vec<_>>();
+        info!("Compression levels used: {}", levels.join(", "));
+    }
+    if changed > 0 {
+        warn!(
+            "{}: {} (their index entries match the archived content)",
+            "Files changed during archiving".yellow().bold(),
+            changed
+        );
+    }
+    if !out_of_range.is_empty() {
+        warn!(
+            "{}:",
+            "Skipped files outside of the size limits".yellow().bold()
+        );
+        for path in &out_of_range {
+            warn!("{}", path.to_string_lossy());
+        }
+    }
+    if !left_out.is_empty() {
+        warn!(
+            "{}: {} files",
+            "Left out to stay within the archive size".yellow().bold(),
+            left_out.len()
+        );
+        match left_out_file {
+            Some(path) => {
+                let list: Vec<_> = left_out.iter().map(|p| p.to_string_lossy()).collect();
+                fs::write(path, list.join("\n") + "\n")
+                    .context("List of left out files could not be written")?;
+                info!(
+                    "The left out files are listed in {} (use it with --paths-from)",
+                    path.to_string_lossy()
+                );
+            }
+            None => {
+                for path in &left_out {
+                    warn!("{}", path.to_string_lossy());
+                }
+            }
+        }
+    }
+    if !preserve_special && !specials.is_empty() {
+        warn!(
+            "{}:",
+            "Skipped special files (use --preserve-special to keep them)"
+                .yellow()
+                .bold()
+        );
+        for (path, _) in &specials {
+            warn!("{}", path.to_string_lossy());
+        }
+    }
+    Ok(index)
+}
+
+/// Takes the paths out of `files` that are hard links to an earlier one. They are returned
+/// with the path of that file.
+fn take_hard_links(root: &Path, files: &mut Vec<PathBuf>) -> Vec<(PathBuf, PathBuf)> {
+    let mut first = HashMap::new();
+    let mut links = vec![];
+    files.retain(|path| {
+        let Ok(metadata) = fs::metadata(root.join(path)) else {
+            return true;
+        };
+        if metadata.nlink() < 2 {
+            return true;
+        }
+        match first.entry((metadata.dev(), metadata.ino())) {
+            Entry::Occupied(e) => {
+                links.push((path.clone(), e.get().clone()));
+                false
+            }
+            Entry::Vacant(e) => {
+                e.insert(path.clone());
+                true
+            }
+        }
+    });
+    links
+}
+
+/// Adds hard links to the index with the chunk and mode of the file they link to. Links to
+/// files that were not archived are left out; the others are returned.
+fn link_files(hardlinks: &[(PathBuf, PathBuf)], index: &mut Index) -> Vec<(PathBuf, PathBuf)> {
+    let mut linked = vec![];
+    for (link, target) in hardlinks {
+        let Some(chunk) = index.mapping.get(target).copied() else {
+            continue;
+        };
+        index.mapping.insert(link.clone(), chunk);
+        if let Some(mode) = index.modes.get(target).copied() {
+            index.modes.insert(link.clone(), mode);
+        }
+        linked.push((link.clone(), target.clone()));
+    }
+    linked
+}
+
+/// The size of the index entries of a file: its path, its chunk, the chunk's hash and size
+/// and its mode (stored with the path again).
+fn index_entry_size(path: &Path) -> u64 {
+    let path_size: u64 = path
+        .components()
+        .map(|c| 8 + c.as_os_str().len() as u64)
+        .sum();
+    8 + path_size + 16 + 8 + 32 + 8 + 8 + path_size + 8
+}
+
+/// An upper bound for the written index and footer, given the size of the serialization.
+/// Age adds a header per recipient and 16 bytes per 64 KiB, zstd hardly expands anything.
+fn index_bound(serialized: u64, recipients: usize) -> u64 {
+    serialized + serialized / 64 + 256 * (recipients as u64 + 1) + 24
+}
 
+const AUTO_LEVEL_SMALL: u64 = 4 << 10;
+const AUTO_LEVEL_LARGE: u64 = 16 << 20;
+
+/// The zstd level `--auto-level` picks for a file. Searching harder gains little on tiny
+/// files, where the frame overhead dominates, and the most on large ones.
+fn level_for_size(size: u64) -> i32 {
+    match size {
+        0..AUTO_LEVEL_SMALL => 1,
+        AUTO_LEVEL_SMALL..AUTO_LEVEL_LARGE => 6,
+        _ => 12,
+    }
+}
+
+/// Writes the index followed by the footer (index length, format revision and magic number).
+/// With level 0, the index is stored without compression.
+pub(crate) fn write_index<W: Write + Seek>(
+    index: &Index,
+    archive: &mut W,
+    level: i32,
+    recipients: &Vec<Box<dyn age::Recipient + Send>>,
+) -> Result<()> {
     let mut index_deser = vec![];
     index.write_bin(&mut index_deser)?;
     let start_pos = archive.stream_position()?;
-    compress_and_encrypt(&mut index_deser.as_slice(), archive, 22, &recipients)?;
+    if level == 0 {
+        encrypt_only(&mut index_deser.as_slice(), archive, recipients)?;
+    } else {
+        compress_and_encrypt(
+            &mut index_deser.as_slice(),
+            archive,
+            Codec::Zstd,
+            level,
+            None,
+            recipients,
+        )?;
+    }
     let index_offset = archive.stream_position()? - start_pos;
     index_offset.write_bin(archive)?;
-    magic_number.write_bin(archive)?;
-    pb.finish_and_clear();
+    revision_word().write_bin(archive)?;
+    index.magic_number.write_bin(archive)?;
     Ok(())
 }
 
+/// Files larger than this are not compressed into memory by a worker thread, but streamed
+/// into the archive by the writer, so the workers do not hold too much at once.
+const BUFFERED_FILE_MAX: u64 = 16 * 1024 * 1024;
+
+/// What `prepare_file` needs to know besides the file.
+struct PrepareSettings {
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    verify_consistency: bool,
+    level: i32,
+    auto_level: bool,
+    long_window: Option<u32>,
+    codec: Codec,
+}
+
+enum Prepared {
+    OutOfRange,
+    /// The file could not be opened (skipped with `--skip-errors`).
+    Unreadable(anyhow::Error),
+    Ready {
+        raw_size: u64,
+        mode: Option<u64>,
+        file_level: i32,
+        hash_before: Option<[u8; 32]>,
+        payload: Payload,
+    },
+}
+
+enum Payload {
+    /// The opened file, compressed while it is written.
+    Reader(ConsistencyReader<Box<dyn Read + Send>>),
+    /// The compressed content with the size and hash of the original.
+    Compressed(Vec<u8>, u64, [u8; 32]),
+}
+
+/// Checks the size of a file and opens it. With `buffer`, files up to `BUFFERED_FILE_MAX`
+/// are compressed into memory right away (this is what the worker threads do).
+fn prepare_file(
+    source: &SourceTree,
+    in_path: &Path,
+    settings: &PrepareSettings,
+    buffer: bool,
+) -> Result<Prepared> {
+    let len = source.file_len(in_path);
+    if let Ok((len, _)) = len {
+        if settings.min_size.is_some_and(|m| len < m) || settings.max_size.is_some_and(|m| len > m)
+        {
+            return Ok(Prepared::OutOfRange);
+        }
+    }
+    let (raw_size, mode, file) =
+        match len.and_then(|(len, mode)| Ok((len, mode, source.open(in_path)?))) {
+            Ok(opened) => opened,
+            Err(e) => return Ok(Prepared::Unreadable(e)),
+        };
+    // Only needed to notice changes while the file is archived. Otherwise the file is
+    // hashed while it is compressed, so it is read once.
+    let hash_before = if settings.verify_consistency {
+        match source
+            .open(in_path)
+            .and_then(|mut file| blake3_hash_streaming(&mut file))
+        {
+            Ok(hash) => Some(hash),
+            Err(e) => return Ok(Prepared::Unreadable(e)),
+        }
+    } else {
+        None
+    };
+    let file_level = if settings.auto_level {
+        level_for_size(raw_size)
+    } else {
+        settings.level
+    };
+    let mut reader = ConsistencyReader {
+        inner: file,
+        hasher: blake3::Hasher::new(),
+        size: 0,
+        failed: false,
+    };
+    let payload = if buffer && raw_size <= BUFFERED_FILE_MAX {
+        let mut data = vec![];
+        match compress(
+            &mut reader,
+            &mut data,
+            settings.codec,
+            file_level,
+            settings.long_window,
+        ) {
+            Ok(()) => Payload::Compressed(data, reader.size, *reader.hasher.finalize().as_bytes()),
+            Err(e) if reader.failed => return Ok(Prepared::Unreadable(e)),
+            Err(e) => return Err(e),
+        }
+    } else {
+        Payload::Reader(reader)
+    };
+    Ok(Prepared::Ready {
+        raw_size,
+        mode,
+        file_level,
+        hash_before,
+        payload,
+    })
+}
+
+/// An earlier file with the same content, if there is one. Files of another size can not
+/// be equal, so they are not compared. With `hash_dedup`, matching hashes are trusted
+/// without reading both files.
+#[allow(clippy::too_many_arguments)]
+fn find_dedup_partner(
+    source: &SourceTree,
+    in_path: &Path,
+    raw_size: u64,
+    hash: &[u8; 32],
+    dedup_hashes: &[(PathBuf, [u8; 32])],
+    mapping: &HashMap<PathBuf, (u64, u64)>,
+    sizes: &HashMap<u64, u64>,
+    hash_dedup: bool,
+) -> Result<Option<PathBuf>> {
+    let candidates = dedup_hashes
+        .iter()
+        .filter(|(_, h)| h == hash)
+        .map(|(p, _)| p)
+        .filter(|p| {
+            mapping
+                .get(*p)
+                .and_then(|(i, _)| sizes.get(i))
+                .is_some_and(|size| *size == raw_size)
+        });
+    for c in candidates {
+        if hash_dedup || files_equal(source.open(in_path)?, source.open(c)?)? {
+            return Ok(Some(c.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// Listed files that are neither in the mapping nor in one of the `reported` lists. Files
+/// skipped because of read errors are only counted, so they are among the result.
+fn unaccounted_files<'a>(
+    file_list: &'a [PathBuf],
+    mapping: &HashMap<PathBuf, (u64, u64)>,
+    reported: &[&Vec<PathBuf>],
+) -> Vec<&'a PathBuf> {
+    let reported = reported.iter().copied().flatten().collect::<HashSet<_>>();
+    file_list
+        .iter()
+        .filter(|p| !mapping.contains_key(*p) && !reported.contains(p))
+        .collect()
+}
+
+/// The chunk of an archived file (or, with `--base`, of a file in the base archive) with the
+/// same content, and whether it is in the base archive.
+#[allow(clippy::too_many_arguments)]
+fn reusable_chunk(
+    source: &SourceTree,
+    in_path: &Path,
+    raw_size: u64,
+    hash: &[u8; 32],
+    dedup_hashes: &[(PathBuf, [u8; 32])],
+    mapping: &HashMap<PathBuf, (u64, u64)>,
+    sizes: &HashMap<u64, u64>,
+    hash_dedup: bool,
+    no_dedup: bool,
+    base_chunks: &HashMap<[u8; 32], (u64, u64, u64)>,
+) -> Result<Option<((u64, u64), bool)>> {
+    if !no_dedup {
+        let partner = find_dedup_partner(
+            source,
+            in_path,
+            raw_size,
+            hash,
+            dedup_hashes,
+            mapping,
+            sizes,
+            hash_dedup,
+        )?;
+        if let Some(partner) = partner {
+            let chunk = mapping
+                .get(&partner)
+                .context("Dedup partner not mapped correctly")?;
+            return Ok(Some((*chunk, false)));
+        }
+    }
+    Ok(base_chunks
+        .get(hash)
+        .filter(|(_, _, size)| *size == raw_size)
+        .map(|(i, len, _)| ((*i, *len), true)))
+}
+
+/// Hashes and counts what is actually read while a file is archived.
+struct ConsistencyReader<R: Read> {
+    inner: R,
+    hasher: blake3::Hasher,
+    size: u64,
+    /// Whether reading the file failed (as opposed to writing the archive).
+    failed: bool,
+}
+
+impl<R: Read> Read for ConsistencyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf).inspect_err(|_| self.failed = true)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
 const BUF_SIZE: usize = 8192;
 
-fn files_equal(mut a: impl Read, mut b: impl Read) -> Result<bool> {
+pub(crate) fn files_equal(mut a: impl Read, mut b: impl Read) -> Result<bool> {
     let mut buf_a = [0u8; BUF_SIZE];
     let mut buf_b = [0u8; BUF_SIZE];
 
     loop {
-        let n1 = a.read(&mut buf_a)?;
-        let n2 = b.read(&mut buf_b)?;
+        let n1 = read_full(&mut a, &mut buf_a)?;
+        let n2 = read_full(&mut b, &mut buf_b)?;
 
         if n1 != n2 {
             return Ok(false);
@@ -190,3 +1561,78 @@ fn files_equal(mut a: impl Read, mut b: impl Read) -> Result<bool> {
         }
     }
 }
+
+/// Reads until `buf` is full or the end is reached. Remote files often return less than
+/// asked for, so two equal files would not line up otherwise.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unaccounted_files;
+    use crate::testutil::{create_archive, scratch_dir, write_files};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn files_missing_from_the_mapping() {
+        let listed = ["a", "b", "c", "d"].map(PathBuf::from);
+        let mapping = HashMap::from([(PathBuf::from("a"), (8, 10))]);
+        let out_of_range = vec![PathBuf::from("b")];
+        let left_out = vec![PathBuf::from("c")];
+        let unaccounted = unaccounted_files(&listed, &mapping, &[&out_of_range, &left_out]);
+        assert_eq!(unaccounted, [&PathBuf::from("d")]);
+        let mapping = HashMap::from([(PathBuf::from("a"), (8, 10)), (PathBuf::from("d"), (18, 5))]);
+        assert!(unaccounted_files(&listed, &mapping, &[&out_of_range, &left_out]).is_empty());
+    }
+
+    #[test]
+    fn exclude_node_modules_anywhere() {
+        let dir = scratch_dir("exclude-node-modules");
+        let source = dir.join("source");
+        write_files(
+            &source,
+            &[
+                ("node_modules/left-pad/index.js", b"top"),
+                ("app/node_modules/react/index.js", b"nested"),
+                ("app/src/main.js", b"main"),
+            ],
+        );
+        let index =
+            create_archive(&source, &dir.join("archive.zprt"), &["**/node_modules/**"]).unwrap();
+        let mut paths = index.mapping.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, vec![Path::new("app/src/main.js")]);
+        assert!(!index.is_dir(Path::new("node_modules")));
+        assert!(!index.is_dir(Path::new("app/node_modules")));
+    }
+
+    #[test]
+    fn drop_directories_with_only_excluded_entries() {
+        let dir = scratch_dir("exclude-whole-dir");
+        let source = dir.join("source");
+        write_files(
+            &source,
+            &[
+                ("logs/a.log", b"a"),
+                ("logs/b.log", b"b"),
+                ("keep.txt", b"keep"),
+            ],
+        );
+        std::fs::create_dir_all(source.join("empty")).unwrap();
+        let index = create_archive(&source, &dir.join("archive.zprt"), &["*.log"]).unwrap();
+        assert_eq!(index.mapping.len(), 1);
+        assert!(index.is_file(Path::new("keep.txt")));
+        assert!(!index.is_dir(Path::new("logs")));
+        // Directories that were empty to begin with are kept.
+        assert_eq!(index.empty_dirs, vec![Path::new("empty")]);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{
+    index::Index,
+    restore::{page_file, restore_from_index},
+    utils::GenericFile,
+};
+use anyhow::Result;
+use humansize::{DECIMAL, format_size};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    widgets::{Block, List, ListState, Paragraph},
+};
+use std::path::{Path, PathBuf};
+
+const HELP: &str = "enter: open  backspace: up  /: search  r: restore  q: quit";
+
+enum Mode {
+    Listing,
+    Query(String),
+    Results(String),
+}
+
+struct Browser<'a> {
+    index: &'a Index,
+    dir: PathBuf,
+    mode: Mode,
+    entries: Vec<PathBuf>,
+    state: ListState,
+    status: String,
+}
+
+impl<'a> Browser<'a> {
+    fn new(index: &'a Index) -> Result<Self> {
+        let mut browser = Self {
+            index,
+            dir: PathBuf::new(),
+            mode: Mode::Listing,
+            entries: vec![],
+            state: ListState::default(),
+            status: String::new(),
+        };
+        browser.reload()?;
+        Ok(browser)
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let mut entries: Vec<PathBuf> = match &self.mode {
+            Mode::Results(pattern) => self.index.search(pattern, false),
+            _ => self
+                .index
+                .get_direct_children(&self.dir)?
+                .into_iter()
+                .collect(),
+        };
+        entries.sort();
+        self.entries = entries;
+        self.state.select(if self.entries.is_empty() {
+            None
+        } else {
+            Some(0)
+        });
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<&PathBuf> {
+        self.state.selected().and_then(|i| self.entries.get(i))
+    }
+
+    fn label(&self, path: &Path) -> String {
+        let name = match self.mode {
+            Mode::Results(_) => path,
+            _ => path.strip_prefix(&self.dir).unwrap_or(path),
+        };
+        if self.index.is_file(path) {
+            let size = self.index.du(path).unwrap_or(0);
+            format!(
+                "{:>12} {}",
+                format_size(size, DECIMAL),
+                name.to_string_lossy()
+            )
+        } else {
+            format!("{:>12} {}/", "-", name.to_string_lossy())
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, status_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let title = match &self.mode {
+            Mode::Results(pattern) => format!(" search: {pattern} "),
+            _ => format!(" /{} ", self.dir.to_string_lossy()),
+        };
+        let items: Vec<String> = self.entries.iter().map(|p| self.label(p)).collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, list_area, &mut self.state);
+        let status = match &self.mode {
+            Mode::Query(query) => format!("/{query}"),
+            _ if !self.status.is_empty() => self.status.clone(),
+            _ => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        archive: &mut GenericFile,
+        ids: &Vec<Box<dyn age::Identity>>,
+    ) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if let Mode::Query(query) = &mut self.mode {
+                match key.code {
+                    KeyCode::Char(c) => query.push(c),
+                    KeyCode::Backspace => {
+                        query.pop();
+                    }
+                    KeyCode::Enter => {
+                        self.mode = Mode::Results(query.clone());
+                        self.reload()?;
+                    }
+                    KeyCode::Esc => {
+                        self.mode = Mode::Listing;
+                        self.reload()?;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            self.status.clear();
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Esc => match self.mode {
+                    Mode::Results(_) => {
+                        self.mode = Mode::Listing;
+                        self.reload()?;
+                    }
+                    _ => return Ok(()),
+                },
+                KeyCode::Down | KeyCode::Char('j') => self.state.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.state.select_previous(),
+                KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => {
+                    if let Mode::Results(_) = self.mode {
+                        self.mode = Mode::Listing;
+                    } else if let Some(parent) = self.dir.parent() {
+                        self.dir = parent.to_path_buf();
+                    }
+                    self.reload()?;
+                }
+                KeyCode::Char('/') => self.mode = Mode::Query(String::new()),
+                KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                    let Some(path) = self.selected().cloned() else {
+                        continue;
+                    };
+                    if self.index.is_file(&path) {
+                        ratatui::restore();
+                        let result = page_file(archive, &path, self.index, ids);
+                        *terminal = ratatui::init();
+                        if let Err(e) = result {
+                            self.status = format!("Error: {e}");
+                        }
+                    } else {
+                        self.dir = path;
+                        self.mode = Mode::Listing;
+                        self.reload()?;
+                    }
+                }
+                KeyCode::Char('r') => {
+                    let Some(path) = self.selected().cloned() else {
+                        continue;
+                    };
+                    let Some(name) = path.file_name() else {
+                        continue;
+                    };
+                    let to = PathBuf::from(name);
+                    if to.exists() {
+                        self.status = format!("{} already exists", to.to_string_lossy());
+                        continue;
+                    }
+                    ratatui::restore();
+                    let result = restore_from_index(
+                        archive, &path, &to, self.index, ids, false, false, false, None,
+                    );
+                    *terminal = ratatui::init();
+                    self.status = match result {
+                        Ok(()) => format!("Restored {}", to.to_string_lossy()),
+                        Err(e) => format!("Error: {e}"),
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn browse(
+    index: &Index,
+    archive: &mut GenericFile,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<()> {
+    let mut browser = Browser::new(index)?;
+    let mut terminal = ratatui::init();
+    let result = browser.run(&mut terminal, archive, ids);
+    ratatui::restore();
+    result
+}
@@ -1,19 +1,45 @@
+use age::cli_common::UiCallbacks;
 use anyhow::{Context, Result, anyhow};
 use colored::*;
 use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
     fs,
-    io::Seek,
+    io::{IsTerminal, Read, Seek, SeekFrom, Write},
+    mem::ManuallyDrop,
+    os::fd::{FromRawFd, RawFd},
     path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
 };
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use humansize::{DECIMAL, format_size};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{LevelFilter, debug, info, warn};
+use serde::Serialize;
 
 use crate::{
-    fuse::mount,
-    restore::{copy_file, restore_command, stream_file},
+    browse::browse,
+    fuse::{MountSettings, mount},
+    recompress::recompress,
+    recover::recover,
+    restore::{
+        RestoreFormat, RestoreSettings, copy_file, open_file, page, restore_command, stream_file,
+    },
     serializer::SimpleBinRepr,
+    ssh_config,
+    update::update_archive,
+    volumes::Volumes,
 };
+/// Environment variable that can hold an identity instead of an identity file.
+const IDENTITY_ENV: &str = "ZIPURAT_IDENTITY";
+/// Environment variable naming the directory searched for identity files.
+const IDENTITY_DIR_ENV: &str = "ZIPURAT_IDENTITY_DIR";
+/// The age stanzas of recipients that zipurat can encrypt to (other stanzas are ignored).
+const RECIPIENT_STANZAS: [&str; 3] = ["X25519", "ssh-rsa", "ssh-ed25519"];
+/// How much of a file is checked for null bytes before printing it to a terminal.
+const SNIFF_BYTES: u64 = 8000;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about =Some("Interact with zipurat archives."))]
 #[command(propagate_version = true)]
@@ -21,8 +47,68 @@ pub struct Cli {
     #[arg(help = "The archive to interact with (can be sftp://...)")]
     archive: String,
 
-    #[arg(long, short, help = "Specific age identity file")]
-    identity_file: Option<PathBuf>,
+    #[arg(
+        long,
+        short,
+        help = "Specific age identity file, - for stdin (can be repeated)"
+    )]
+    identity_file: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        hide = true,
+        value_parser = parse_magic_number,
+        help = "Use a custom magic number (decimal or 0x-prefixed hex) to namespace archives"
+    )]
+    magic_number: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "HOST",
+        help = "Read and write archives on this host (the archive is a path there), can be a ~/.ssh/config alias"
+    )]
+    sftp_host: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "USER",
+        help = "The user for remote archives (default: from ~/.ssh/config, else $USER)"
+    )]
+    sftp_user: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PORT",
+        value_parser = parse_port,
+        help = "The port for remote archives (default: from ~/.ssh/config, else 22)"
+    )]
+    sftp_port: Option<u16>,
+
+    #[arg(
+        long,
+        short,
+        global = true,
+        conflicts_with = "quiet",
+        help = "Also print debug messages (RUST_LOG takes precedence)"
+    )]
+    verbose: bool,
+
+    #[arg(
+        long,
+        short,
+        global = true,
+        help = "Only print errors (RUST_LOG takes precedence)"
+    )]
+    quiet: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value = "auto",
+        help = "When to color the output (auto = only on a terminal)"
+    )]
+    color: ColorChoice,
 
     #[command(subcommand)]
     pub command: Commands,
@@ -32,37 +118,307 @@ pub struct Cli {
 pub enum Commands {
     #[command(about = "Create an archive")]
     Create {
-        #[arg(help = "The directory to be archived")]
+        #[arg(help = "The directory to be archived (can be sftp://...)")]
         source: PathBuf,
         #[arg(short, long, help = "The zstd compression level", default_value = "3")]
         compression_level: i32,
+        #[arg(
+            long,
+            value_enum,
+            conflicts_with = "compression_level",
+            help = "Compression preset (fast = level 1, default = level 3, best = level 19 with --long)"
+        )]
+        compression: Option<CompressionPreset>,
+        #[arg(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "27",
+            value_name = "WINDOW_LOG",
+            help = "Enable zstd long-distance matching with a window of 2^WINDOW_LOG bytes"
+        )]
+        long: Option<u32>,
+        #[arg(
+            long,
+            value_enum,
+            default_value = "zstd",
+            help = "How files are compressed (none stores them as they are, gzip levels stop at 9)"
+        )]
+        codec: Codec,
+        #[arg(
+            long,
+            help = "File with newline-separated paths (relative to the source) to archive"
+        )]
+        paths_from: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Warn about and skip unusable paths instead of failing",
+            default_value = "false"
+        )]
+        skip_errors: bool,
+        #[arg(
+            long,
+            help = "Read the index back after writing and compare it",
+            default_value = "false"
+        )]
+        verify_after_create: bool,
+        #[arg(
+            long,
+            help = "Read every file back after writing and compare it with its hash",
+            default_value = "false"
+        )]
+        verify: bool,
+        #[arg(
+            long,
+            help = "Do not sync a local archive to disk before reporting success",
+            default_value = "false"
+        )]
+        no_fsync: bool,
+        #[arg(
+            long,
+            value_name = "FILES",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Write an interim index every FILES files, so the partial archive can be read or recovered"
+        )]
+        checkpoint_interval: Option<u64>,
+        #[arg(long, help = "Overwrite an existing archive", default_value = "false")]
+        force: bool,
+        #[arg(
+            long,
+            help = "Record FIFOs, sockets and device nodes (recreated on restore)",
+            default_value = "false"
+        )]
+        preserve_special: bool,
+        #[arg(
+            long,
+            help = "Record which files are hard links to each other (linked again on restore)",
+            default_value = "false"
+        )]
+        preserve_hardlinks: bool,
+        #[arg(
+            long,
+            help = "Only compress, do not encrypt (no identity file needed)",
+            default_value = "false"
+        )]
+        no_encrypt: bool,
+        #[arg(long, value_name = "BYTES", help = "Skip files smaller than this")]
+        min_file_size: Option<u64>,
+        #[arg(long, value_name = "BYTES", help = "Skip files larger than this")]
+        max_file_size: Option<u64>,
+        #[arg(
+            long,
+            help = "Detect files that change while they are archived",
+            default_value = "false"
+        )]
+        verify_consistency: bool,
+        #[arg(
+            long,
+            help = "Skip files and directories whose name starts with a dot",
+            default_value = "false"
+        )]
+        no_hidden: bool,
+        #[arg(
+            long,
+            value_name = "GLOB",
+            help = "Skip files and directories matching this pattern (matched against the relative path and the name, ** spans directories)"
+        )]
+        exclude: Vec<String>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Read exclude patterns from this file (one per line, # starts a comment)"
+        )]
+        exclude_from: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Follow symbolic links and archive their targets",
+            default_value = "false"
+        )]
+        dereference: bool,
+        #[arg(
+            long,
+            value_enum,
+            default_value = "content",
+            help = "How duplicates are detected (hash = trust matching blake3 hashes without comparing the files)"
+        )]
+        dedup: DedupMode,
+        #[arg(
+            long,
+            conflicts_with_all = ["dedup", "base"],
+            help = "Store every file, even duplicates (no files are compared)",
+            default_value = "false"
+        )]
+        no_dedup: bool,
+        #[arg(
+            long,
+            conflicts_with_all = ["compression_level", "compression"],
+            help = "Pick the level per file by its size (1 below 4 KiB, 6 below 16 MiB, 12 above)",
+            default_value = "false"
+        )]
+        auto_level: bool,
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Compress files on N threads (default: the number of logical CPUs)"
+        )]
+        threads: Option<usize>,
+        #[arg(
+            long,
+            help = "Archive files in sorted order, so identical input gives identical chunk order",
+            default_value = "false"
+        )]
+        deterministic: bool,
+        #[arg(
+            long,
+            requires = "deterministic",
+            help = "Shuffle the sorted files with this seed instead of keeping them sorted"
+        )]
+        seed: Option<u64>,
+        #[arg(
+            long,
+            help = "Record the absolute source directory and the hostname (shown by info)",
+            default_value = "false"
+        )]
+        store_root: bool,
+        #[arg(
+            long,
+            value_name = "ARCHIVE",
+            help = "Only store files that are not already in this archive (restoring needs it too)"
+        )]
+        base: Option<String>,
+        #[arg(
+            long,
+            value_name = "ARCHIVE",
+            conflicts_with = "no_encrypt",
+            help = "Encrypt to the recipients of this archive (each needs its identity file with -i)"
+        )]
+        recipients_from: Option<String>,
+        #[arg(
+            long,
+            allow_hyphen_values = true,
+            help = "The zstd level of the index (0 stores it without compression)",
+            default_value = "22"
+        )]
+        index_level: i32,
+        #[arg(
+            long,
+            conflicts_with = "index_level",
+            help = "Store the index without compression (same as --index-level 0)",
+            default_value = "false"
+        )]
+        fast_index: bool,
+        #[arg(
+            long,
+            value_name = "BYTES",
+            help = "Leave out files that would make the archive larger than this"
+        )]
+        max_archive_size: Option<u64>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            requires = "max_archive_size",
+            help = "Write the paths of files left out by --max-archive-size to this file"
+        )]
+        left_out: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "BYTES",
+            help = "Split the archive into volumes of this size (ARCHIVE.001, ARCHIVE.002, ...)"
+        )]
+        volume_size: Option<u64>,
+        #[arg(
+            long,
+            value_name = "N",
+            help = "How often a failed upload to a remote archive is started again",
+            default_value = "2"
+        )]
+        upload_retries: u32,
     },
-    #[command(about = "Show the contents of a single file", alias = "cat")]
+    #[command(about = "Show the contents of files", alias = "cat")]
     Show {
-        #[arg(help = "The path to the file")]
-        path: PathBuf,
+        #[arg(
+            required = true,
+            help = "The paths of the files (several are concatenated)"
+        )]
+        paths: Vec<PathBuf>,
         #[arg(short, long, help = "Output file (default stdout)")]
         output: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "FD",
+            value_parser = clap::value_parser!(i32).range(0..),
+            conflicts_with = "output",
+            help = "Write to an already open file descriptor (it is not closed)"
+        )]
+        output_fd: Option<RawFd>,
+        #[arg(
+            long,
+            help = "Show through $PAGER (or less) when stdout is a terminal",
+            default_value = "false"
+        )]
+        pager: bool,
+        #[arg(
+            long,
+            help = "Do not warn before printing a file that looks binary to a terminal",
+            default_value = "false"
+        )]
+        binary: bool,
+        #[arg(
+            long,
+            value_name = "BYTES_PER_SECOND",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Limit the read rate from remote archives"
+        )]
+        rate_limit: Option<u64>,
+        #[arg(
+            long,
+            help = "Print ==> path <== before each file (only on stdout)",
+            default_value = "false"
+        )]
+        with_headers: bool,
     },
     #[command(about = "List a directory", alias = "ls")]
     List {
         #[arg(help = "directory to list")]
         prefix: Option<PathBuf>,
+        #[arg(
+            long,
+            hide = true,
+            help = "Also print the chunk offset and length of each file",
+            default_value = "false"
+        )]
+        offsets: bool,
+        #[arg(long, help = "Only list directories", default_value = "false")]
+        dirs_only: bool,
+        #[arg(
+            short,
+            long,
+            help = "List everything below the directory, not only its children",
+            default_value = "false"
+        )]
+        recursive: bool,
     },
     #[command(about = "Search for files or directories", alias = "search")]
     Find {
         #[arg(help = "name to search for")]
         name: String,
+        #[arg(long = "in", help = "Only search within this directory")]
+        within: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Also ignore accents and other diacritics (cafe finds café)",
+            default_value = "false"
+        )]
+        fold: bool,
     },
     #[command(about = "Restore a file or directory from the archive")]
     Restore {
         #[arg(
             long,
-            help = "path to restore, defaults to the whole archive",
+            help = "path or glob pattern (an existing path takes precedence) to restore, defaults to the whole archive",
             alias = "path"
         )]
         from: Option<PathBuf>,
-        #[arg(help = "output")]
+        #[arg(help = "output, - streams a single file to stdout")]
         to: PathBuf,
         #[arg(
             short,
@@ -71,13 +427,97 @@ pub enum Commands {
             default_value = "false"
         )]
         trust_hashes: bool,
+        #[arg(
+            long,
+            value_name = "BYTES_PER_SECOND",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Limit the read rate from remote archives"
+        )]
+        rate_limit: Option<u64>,
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u64).range(1..),
+            default_value = "1",
+            help = "Number of connections fetching files concurrently (each is an SFTP session)"
+        )]
+        threads_io: u64,
+        #[arg(
+            long,
+            help = "Clone files that share a chunk (copy-on-write) instead of writing them again",
+            default_value = "false"
+        )]
+        reflink: bool,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Write a JSON manifest of the restored files (path, size, hash)"
+        )]
+        manifest: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "ARCHIVE",
+            help = "The base archive of a delta archive"
+        )]
+        base: Option<String>,
+        #[arg(
+            long,
+            help = "Print a JSON line per restored file instead of a progress bar",
+            default_value = "false"
+        )]
+        events: bool,
+        #[arg(
+            long,
+            value_enum,
+            help = "Write a directory tree or a tarball (default: by the extension of the output)"
+        )]
+        format: Option<RestoreFormat>,
+        #[arg(
+            long,
+            help = "Only list the files that would be written and their total size",
+            default_value = "false"
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            requires = "dry_run",
+            help = "Print the dry-run plan as JSON",
+            default_value = "false"
+        )]
+        json: bool,
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with = "from",
+            help = "File with newline-separated archive paths to restore, - for stdin"
+        )]
+        paths_from: Option<PathBuf>,
+        #[arg(
+            long,
+            requires = "paths_from",
+            help = "Warn about listed paths that are not in the archive instead of failing",
+            default_value = "false"
+        )]
+        skip_missing: bool,
+        #[arg(
+            long,
+            help = "Also restore setuid, setgid and sticky bits (only for archives you trust)",
+            default_value = "false"
+        )]
+        keep_special_bits: bool,
     },
     #[command(about = "Get the (uncompressed) size")]
     Du {
-        #[arg(help = "path")]
-        path: Option<PathBuf>,
+        #[arg(help = "paths (the whole archive if none are given)")]
+        paths: Vec<PathBuf>,
         #[arg(short, help = "Human readable", default_value = "false")]
         humansize: bool,
+        #[arg(
+            short = 'c',
+            long,
+            help = "Also print the total of all paths",
+            default_value = "false"
+        )]
+        total: bool,
     },
     #[command(about = "Mount an archive with fuse")]
     Mount {
@@ -98,6 +538,12 @@ pub enum Commands {
             default_value = "50000000"
         )]
         cached_size: usize,
+        #[arg(
+            long,
+            help = "Max number of cached lookups, attributes and listings (each)",
+            default_value = "10000"
+        )]
+        meta_cache_entries: usize,
         #[arg(long, short, help = "sub-directory to mount")]
         sub_directory: Option<PathBuf>,
         #[arg(
@@ -107,213 +553,1354 @@ pub enum Commands {
             default_value = "false"
         )]
         no_reads: bool,
+        #[arg(
+            long,
+            value_parser = parse_mode,
+            default_value = "644",
+            help = "Permission bits reported for files without a stored mode (octal)"
+        )]
+        file_mode: u16,
+        #[arg(
+            long,
+            value_parser = parse_mode,
+            default_value = "755",
+            help = "Permission bits reported for directories without a stored mode (octal)"
+        )]
+        dir_mode: u16,
+        #[arg(
+            long,
+            help = "Reads within the first HEAD_BYTES of a file only decode that part (0 disables)",
+            default_value = "50000"
+        )]
+        head_bytes: u64,
+        #[arg(
+            long,
+            value_name = "BYTES",
+            help = "Larger files are decoded for every read instead of being held in memory",
+            default_value = "1000000000"
+        )]
+        max_read_file_size: u64,
+        #[arg(
+            long,
+            value_name = "BYTES",
+            help = "Decode this much ahead when a large file is read sequentially (0 disables it)",
+            default_value = "16000000"
+        )]
+        readahead: u64,
+        #[arg(
+            long = "layer",
+            value_name = "ARCHIVE",
+            help = "Further archives shown on top of it, later ones shadow earlier ones (can be repeated)"
+        )]
+        layers: Vec<String>,
     },
     #[command(about = "Get archive information")]
-    Info {},
+    Info {
+        #[arg(long, value_enum, default_value = "text", help = "Output format")]
+        format: InfoFormat,
+        #[arg(
+            long,
+            help = "Only print which identity file decrypts the archive",
+            default_value = "false"
+        )]
+        which_identity: bool,
+        #[arg(
+            long,
+            conflicts_with = "which_identity",
+            help = "Only check if the identities can decrypt the archive (without loading the index)",
+            default_value = "false"
+        )]
+        check_access: bool,
+    },
+    #[command(about = "Browse an archive interactively")]
+    Browse {},
+    #[command(about = "Rebuild a damaged index into a new archive")]
+    Recover {
+        #[arg(help = "The repaired archive to write (can be sftp://...)")]
+        output: String,
+    },
+    #[command(about = "Write a copy of the archive compressed at another level")]
+    Recompress {
+        #[arg(help = "The new archive to write (can be sftp://...)")]
+        output: String,
+        #[arg(short = 'c', long, help = "The zstd compression level")]
+        compression_level: i32,
+        #[arg(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "27",
+            value_name = "WINDOW_LOG",
+            help = "Enable zstd long-distance matching with a window of 2^WINDOW_LOG bytes"
+        )]
+        long: Option<u32>,
+        #[arg(long, help = "Overwrite an existing archive", default_value = "false")]
+        force: bool,
+    },
+    #[command(about = "Add new and changed files to an archive in place and drop deleted ones")]
+    Update {
+        #[arg(help = "The directory the archive was created from")]
+        source: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "The zstd compression level for new files",
+            default_value = "3"
+        )]
+        compression_level: i32,
+        #[arg(
+            long,
+            help = "Warn about and skip unreadable files (keeping what was archived before)",
+            default_value = "false"
+        )]
+        skip_errors: bool,
+        #[arg(
+            long,
+            help = "Record FIFOs, sockets and device nodes (recreated on restore)",
+            default_value = "false"
+        )]
+        preserve_special: bool,
+        #[arg(
+            long,
+            help = "Skip files and directories whose name starts with a dot",
+            default_value = "false"
+        )]
+        no_hidden: bool,
+        #[arg(
+            long,
+            value_name = "GLOB",
+            help = "Skip files and directories matching this pattern (matched against the relative path and the name, ** spans directories)"
+        )]
+        exclude: Vec<String>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Read exclude patterns from this file (one per line, # starts a comment)"
+        )]
+        exclude_from: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Follow symbolic links and archive their targets",
+            default_value = "false"
+        )]
+        dereference: bool,
+        #[arg(
+            long,
+            help = "Do not sync a local archive to disk before reporting success",
+            default_value = "false"
+        )]
+        no_fsync: bool,
+    },
+    #[command(about = "List groups of identical (deduplicated) files")]
+    Duplicates {
+        #[arg(long, help = "Output as JSON", default_value = "false")]
+        json: bool,
+    },
+    #[command(about = "Report bytes in the archive that no file refers to")]
+    Analyze {},
+    #[command(about = "Count files and their (uncompressed) size")]
+    Stats {
+        #[arg(long, help = "Group the files by extension", default_value = "false")]
+        by_extension: bool,
+        #[arg(long, value_name = "N", help = "Only show the N largest groups")]
+        top: Option<usize>,
+        #[arg(long, help = "Output as JSON", default_value = "false")]
+        json: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum CompressionPreset {
+    Fast,
+    Default,
+    Best,
+}
+
+impl CompressionPreset {
+    /// The zstd level behind each preset. These are fixed and documented in the help text.
+    fn level(self) -> i32 {
+        match self {
+            CompressionPreset::Fast => 1,
+            CompressionPreset::Default => 3,
+            CompressionPreset::Best => 19,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum DedupMode {
+    Content,
+    Hash,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum InfoFormat {
+    Text,
+    Json,
+    Toml,
+}
+
+/// Everything `info` reports. The field names are the schema of the json and toml output.
+#[derive(Serialize, Debug)]
+struct ArchiveInfo {
+    /// As a hex string like in the text output; toml integers stop at `i64::MAX`.
+    #[serde(serialize_with = "serialize_hex")]
+    magic_number: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format_version: Option<&'static str>,
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_format: Option<String>,
+    zipurat_version: &'static str,
+    files: usize,
+    size_original: u64,
+    size_compressed: u64,
+    compression_ratio: f64,
+    duplicate_files: usize,
+    empty_directories: usize,
+    special_files: usize,
+    symlinks: usize,
+    hard_links: usize,
+    dedup: &'static str,
+    size_index: u64,
+    index_compressed: bool,
+    bytes_after_footer: u64,
+    /// Last, since toml writes tables after all plain values.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+}
+
+fn serialize_hex<S: serde::Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{value:X}"))
 }
 
 use crate::{
-    archiver::build_archive,
-    index::Index,
+    archiver::{
+        BuildSettings, SourceTree, build_archive, build_excludes, read_exclude_file,
+        read_paths_from,
+    },
+    index::{
+        BASE_CHUNK, DEDUP_HASH, DEDUP_NONE, EntryKind, Index, LazyIndex, PathSearch, check_access,
+        format_version, index_stanzas, is_encrypted, locate_footer, match_span, read_footer,
+        rewritten_index_level, set_magic_number,
+    },
     utils::{
-        GenericFile, open_local_archive_read, open_local_archive_write, open_remote_archive_read,
-        open_remote_archive_write,
+        ArchivePool, Codec, GenericFile, RemoteTarget, WINDOW_LOG_MAX, decrypt_and_decompress,
+        decrypt_index, is_upload_error, open_local_archive_read, open_local_archive_update,
+        open_local_archive_write, open_remote_archive_read, open_remote_archive_update,
+        open_remote_archive_write, open_remote_dir,
     },
 };
 
 fn open_general_archive_read(path: &str) -> Result<GenericFile> {
-    match parse_sftp_url(path) {
-        Ok((host, user, port, path)) => open_remote_archive_read(&host, &user, &path, port),
-        Err(_) => open_local_archive_read(path),
+    match parse_sftp_url(path)? {
+        Some(target) => open_remote_archive_read(&target),
+        None => open_local_archive_read(path),
+    }
+}
+fn open_general_archive_write(path: &str, force: bool) -> Result<GenericFile> {
+    match parse_sftp_url(path)? {
+        Some(target) => open_remote_archive_write(&target, force),
+        None => open_local_archive_write(path, force),
     }
 }
-fn open_general_archive_write(path: &str) -> Result<GenericFile> {
-    match parse_sftp_url(path) {
-        Ok((host, user, port, path)) => open_remote_archive_write(&host, &user, &path, port),
-        Err(_) => open_local_archive_write(path),
+fn open_general_archive_update(path: &str) -> Result<GenericFile> {
+    match parse_sftp_url(path)? {
+        Some(target) => open_remote_archive_update(&target),
+        None => open_local_archive_update(path),
     }
 }
 
-fn parse_sftp_url(s: &str) -> Result<(String, String, u64, String)> {
-    let s = s
-        .strip_prefix("sftp://")
-        .ok_or(anyhow!("Missing sftp:// scheme"))?;
-    let (user_host_port, path) = s.split_once(':').ok_or(anyhow!("Missing ':' after host"))?;
-    let (user, host_port) = user_host_port
-        .split_once('@')
-        .ok_or(anyhow!("Missing user@host"))?;
+/// `--sftp-host`, `--sftp-user` and `--sftp-port`, set once before any archive is opened.
+#[derive(Default)]
+struct SftpDefaults {
+    host: Option<String>,
+    user: Option<String>,
+    port: Option<u16>,
+}
 
-    let (host, port) = if let Some((h, p)) = host_port.split_once(':') {
-        (h, p.parse()?)
-    } else {
-        (host_port, 22)
+static SFTP_DEFAULTS: OnceLock<SftpDefaults> = OnceLock::new();
+
+/// Parses `sftp://[user@]host[:port]:path`. Without the scheme, the path is only remote if
+/// `--sftp-host` is given, otherwise it is local (`None`). The host can be an alias from
+/// `~/.ssh/config`; the values in the URL take precedence over the `--sftp-*` flags, which
+/// take precedence over the config. Errors in a remote path are not ignored.
+fn parse_sftp_url(s: &str) -> Result<Option<RemoteTarget>> {
+    let defaults = SFTP_DEFAULTS.get_or_init(SftpDefaults::default);
+    let (user, host, port, path) = match s.strip_prefix("sftp://") {
+        Some(s) => {
+            let (user_host, rest) = s.split_once(':').ok_or(anyhow!("Missing ':' after host"))?;
+            let (user, host) = match user_host.split_once('@') {
+                Some((user, host)) => (Some(user.to_string()), host.to_string()),
+                None => (None, user_host.to_string()),
+            };
+            // A number followed by another colon is the port.
+            let (port, path) = match rest.split_once(':') {
+                Some((p, path)) if !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()) => {
+                    (Some(parse_port(p)?), path)
+                }
+                _ => (None, rest),
+            };
+            (user, host, port, path)
+        }
+        None => match &defaults.host {
+            Some(host) => (None, host.clone(), None, s),
+            None => return Ok(None),
+        },
     };
+    let config = ssh_config::lookup(&host)?;
+    let user = user
+        .or(defaults.user.clone())
+        .or(config.user)
+        .or(std::env::var("USER").ok())
+        .context("No user for the remote archive (use user@host or --sftp-user)")?;
+    Ok(Some(RemoteTarget {
+        host: config.host_name.unwrap_or(host),
+        user,
+        path: path.to_string(),
+        port: port.or(defaults.port).or(config.port).unwrap_or(22),
+        identity: config.identity_file,
+    }))
+}
+
+fn parse_port(s: &str) -> Result<u16> {
+    match s.parse() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(anyhow!("Invalid port {s} (valid range is 1 to 65535)")),
+    }
+}
+
+fn parse_magic_number(s: &str) -> Result<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None => Ok(s.parse()?),
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u16> {
+    let mode = u16::from_str_radix(s.strip_prefix("0o").unwrap_or(s), 8)?;
+    if mode > 0o7777 {
+        return Err(anyhow!("Invalid mode {s}"));
+    }
+    Ok(mode)
+}
+
+fn check_compression_level(level: i32) -> Result<()> {
+    let range = zstd::compression_level_range();
+    if !range.contains(&level) {
+        return Err(anyhow!(
+            "Invalid compression level {} (valid range is {} to {})",
+            level,
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(())
+}
+
+fn load_recipients(paths: &[PathBuf]) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
+    let files = identity_files(paths)?;
+    if files.is_empty() {
+        return Err(anyhow!(
+            "Recipient file must be provided (or set {})",
+            IDENTITY_ENV
+        ));
+    }
+    let mut recipients = vec![];
+    for (_, file) in files {
+        recipients.append(&mut file.to_recipients()?);
+    }
+    Ok(recipients)
+}
+
+/// The recipients of an existing archive, to encrypt a new one to the same set.
+/// X25519 stanzas do not contain the public key, so the recipients cannot be read from
+/// the header. Instead, every recipient has to be given as an identity file, and each file
+/// has to open the archive. The number of recipient stanzas shows if one is missing.
+fn recipients_from_archive(
+    archive: &str,
+    paths: &[PathBuf],
+) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
+    let mut archive_file = open_general_archive_read(archive)?;
+    if !is_encrypted(&mut archive_file)? {
+        return Err(anyhow!("{archive} is not encrypted (use --no-encrypt)"));
+    }
+    let stanzas = index_stanzas(&mut archive_file)?;
+    if stanzas.iter().any(|s| s == "scrypt") {
+        return Err(anyhow!(
+            "{archive} is encrypted with a passphrase, which zipurat does not support"
+        ));
+    }
+    let expected = stanzas
+        .iter()
+        .filter(|s| RECIPIENT_STANZAS.contains(&s.as_str()))
+        .count();
+    let files = identity_files(paths)?;
+    if files.is_empty() {
+        return Err(anyhow!(
+            "The identity files of the recipients of {archive} must be provided (or set {})",
+            IDENTITY_ENV
+        ));
+    }
+    let mut recipients = vec![];
+    for (label, file) in files {
+        let mut file_recipients = file.to_recipients()?;
+        let ids = file.into_identities()?;
+        if !check_access(&mut archive_file, &ids)? {
+            return Err(anyhow!("{label} is not a recipient of {archive}"));
+        }
+        recipients.append(&mut file_recipients);
+    }
+    if recipients.len() < expected {
+        return Err(anyhow!(
+            "{archive} has {expected} recipients but only {} were given (their public keys cannot be read from the archive, so each needs -i)",
+            recipients.len()
+        ));
+    }
+    Ok(recipients)
+}
 
-    Ok((host.to_string(), user.to_string(), port, path.to_string()))
+fn check_access_command(archive: &mut GenericFile, provided: &[PathBuf]) -> Result<()> {
+    if !is_encrypted(archive)? {
+        println!("The archive is not encrypted");
+        return Ok(());
+    }
+    if check_access(archive, &load_identities(provided)?)? {
+        println!("The archive can be decrypted");
+        Ok(())
+    } else {
+        Err(anyhow!("None of the identities can decrypt the archive"))
+    }
+}
+
+/// Tries the identities one source (file or variable) at a time and prints the first that
+/// decrypts the archive.
+fn which_identity_command(archive: &mut GenericFile, provided: &[PathBuf]) -> Result<()> {
+    if !is_encrypted(archive)? {
+        return Err(anyhow!("Archive is not encrypted"));
+    }
+    for (label, ids) in labeled_identities(provided)? {
+        if check_access(archive, &ids)? {
+            println!("{label}");
+            return Ok(());
+        }
+        debug!("{label} does not decrypt the archive");
+    }
+    Err(anyhow!("None of the identities decrypts the archive"))
+}
+
+/// The identity files given with `-i` (`-` is stdin), each only once, with their names.
+/// Without any, the identity in `ZIPURAT_IDENTITY` is used if it is set.
+/// Plugins (for example for hardware keys) prompt in the terminal.
+fn identity_files(paths: &[PathBuf]) -> Result<Vec<(String, age::IdentityFile<UiCallbacks>)>> {
+    identity_files_with(paths, UiCallbacks)
+}
+
+/// Like `identity_files`, with the callbacks that answer the prompts of plugins.
+fn identity_files_with<C: age::Callbacks>(
+    paths: &[PathBuf],
+    callbacks: C,
+) -> Result<Vec<(String, age::IdentityFile<C>)>> {
+    let mut unique: Vec<&PathBuf> = vec![];
+    for p in paths {
+        if !unique.contains(&p) {
+            unique.push(p);
+        }
+    }
+    let mut files = vec![];
+    for path in unique {
+        let label = if path == Path::new("-") {
+            "stdin".to_string()
+        } else {
+            path.to_string_lossy().to_string()
+        };
+        let file = if path == Path::new("-") {
+            age::IdentityFile::from_buffer(stdin_identity()?.as_bytes())
+        } else {
+            age::IdentityFile::from_file(path.to_str().context("Invalid path for IDs")?.to_string())
+        }
+        .context(format!("Identity file {label} could not be loaded"))?;
+        files.push((label, file.with_callbacks(callbacks.clone())));
+    }
+    if files.is_empty() {
+        if let Ok(identity) = std::env::var(IDENTITY_ENV) {
+            let file = age::IdentityFile::from_buffer(identity.as_bytes())
+                .context(format!("{} could not be parsed", IDENTITY_ENV))?;
+            files.push((IDENTITY_ENV.to_string(), file.with_callbacks(callbacks)));
+        }
+    }
+    Ok(files)
 }
 
-fn load_recipients(path: &str) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
-    Ok(age::IdentityFile::from_file(path.to_string())?.to_recipients()?)
+/// Stdin can only be read once, but create reads the identities twice with --verify-after-create.
+fn stdin_identity() -> Result<&'static str> {
+    static STDIN_IDENTITY: OnceLock<String> = OnceLock::new();
+    if let Some(identity) = STDIN_IDENTITY.get() {
+        return Ok(identity);
+    }
+    let mut identity = String::new();
+    std::io::stdin()
+        .read_to_string(&mut identity)
+        .context("Identity could not be read from stdin")?;
+    Ok(STDIN_IDENTITY.get_or_init(|| identity))
 }
 
 impl Cli {
+    /// The identities for reading the archive. Unencrypted archives need none.
+    fn identities_for(&self, archive: &mut GenericFile) -> Result<Vec<Box<dyn age::Identity>>> {
+        if is_encrypted(archive)? {
+            load_identities(&self.identity_file)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// The level of messages from zipurat itself, set by `--verbose` and `--quiet`.
+    pub fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            LevelFilter::Error
+        } else if self.verbose {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        }
+    }
+
     pub fn run(&self) -> Result<()> {
+        if let Some(magic) = self.magic_number {
+            set_magic_number(magic);
+        }
+        let _ = SFTP_DEFAULTS.set(SftpDefaults {
+            host: self.sftp_host.clone(),
+            user: self.sftp_user.clone(),
+            port: self.sftp_port,
+        });
+        match self.color {
+            ColorChoice::Always => colored::control::set_override(true),
+            ColorChoice::Never => colored::control::set_override(false),
+            ColorChoice::Auto if !std::io::stdout().is_terminal() => {
+                colored::control::set_override(false)
+            }
+            ColorChoice::Auto => {}
+        }
         match &self.command {
             Commands::Create {
                 source,
                 compression_level,
+                compression,
+                long,
+                codec,
+                paths_from,
+                skip_errors,
+                verify_after_create,
+                verify,
+                no_fsync,
+                checkpoint_interval,
+                force,
+                preserve_special,
+                preserve_hardlinks,
+                no_encrypt,
+                min_file_size,
+                max_file_size,
+                verify_consistency,
+                no_hidden,
+                exclude,
+                exclude_from,
+                dereference,
+                dedup,
+                no_dedup,
+                auto_level,
+                threads,
+                deterministic,
+                seed,
+                store_root,
+                base,
+                recipients_from,
+                index_level,
+                fast_index,
+                max_archive_size,
+                left_out,
+                volume_size,
+                upload_retries,
             } => {
-                let recipients = load_recipients(
-                    self.identity_file
-                        .as_ref()
-                        .context("Recipient file must be provided")?
-                        .to_str()
-                        .context("Path not a valid string")?,
-                )?;
-                let mut archive = open_general_archive_write(&self.archive)?;
-                build_archive(source, &mut archive, recipients, *compression_level)?
+                let compression_level =
+                    compression.map(|p| p.level()).unwrap_or(*compression_level);
+                check_compression_level(compression_level)?;
+                let index_level = if *fast_index { 0 } else { *index_level };
+                if index_level != 0 {
+                    check_compression_level(index_level)?;
+                }
+                let long_window = match compression {
+                    Some(CompressionPreset::Best) => long.or(Some(27)),
+                    _ => *long,
+                };
+                if long_window.is_some() && *codec != Codec::Zstd {
+                    return Err(anyhow!("--long only works with --codec zstd"));
+                }
+                if let Some(window_log) = long_window {
+                    if !(10..=WINDOW_LOG_MAX).contains(&window_log) {
+                        return Err(anyhow!(
+                            "Invalid window log {} (valid range is 10 to {})",
+                            window_log,
+                            WINDOW_LOG_MAX
+                        ));
+                    }
+                }
+                let mut patterns = exclude.clone();
+                if let Some(file) = exclude_from {
+                    patterns.extend(read_exclude_file(file)?);
+                }
+                let excludes = build_excludes(&patterns)?;
+                let recipients = if *no_encrypt {
+                    vec![]
+                } else if let Some(previous) = recipients_from {
+                    recipients_from_archive(previous, &self.identity_file)?
+                } else {
+                    load_recipients(&self.identity_file)?
+                };
+                // Only an explicit sftp:// URL makes the source remote, --sftp-host is for archives.
+                let source = match source.to_str() {
+                    Some(url) if url.starts_with("sftp://") => {
+                        let target = parse_sftp_url(url)?.context("Missing sftp:// scheme")?;
+                        let (sftp, dir) = open_remote_dir(&target)?;
+                        let description =
+                            format!("{}@{}:{}", target.user, target.host, dir.to_string_lossy());
+                        SourceTree::Remote(sftp, dir, description)
+                    }
+                    _ => SourceTree::Local(source.clone()),
+                };
+                let base_index = match base {
+                    Some(base) => {
+                        let mut base = open_general_archive_read(base)?;
+                        if is_encrypted(&mut base)? == recipients.is_empty() {
+                            return Err(anyhow!("The base archive must use the same encryption"));
+                        }
+                        let identities = self.identities_for(&mut base)?;
+                        Some(Index::parse(&mut base, &identities).context("Base archive")?)
+                    }
+                    None => None,
+                };
+                // Remote archives are written under a temporary name, so a failed upload can
+                // simply be started again.
+                let remote_archive = parse_sftp_url(&self.archive)?.is_some();
+                let attempts = if remote_archive {
+                    *upload_retries + 1
+                } else {
+                    1
+                };
+                // Read once, a list on stdin (or a pipe) can not be read again for a retry.
+                let path_list = match paths_from {
+                    Some(list) => Some(read_paths_from(&source, list, *skip_errors)?),
+                    None => None,
+                };
+                let settings = BuildSettings {
+                    level: compression_level,
+                    long_window,
+                    codec: *codec,
+                    paths_from: path_list.as_deref(),
+                    skip_errors: *skip_errors,
+                    checkpoint_interval: *checkpoint_interval,
+                    preserve_special: *preserve_special,
+                    preserve_hardlinks: *preserve_hardlinks,
+                    min_size: *min_file_size,
+                    max_size: *max_file_size,
+                    verify_consistency: *verify_consistency,
+                    no_hidden: *no_hidden,
+                    excludes,
+                    dereference: *dereference,
+                    hash_dedup: matches!(dedup, DedupMode::Hash),
+                    no_dedup: *no_dedup,
+                    auto_level: *auto_level,
+                    threads: threads.unwrap_or_else(num_cpus::get).max(1),
+                    deterministic: *deterministic,
+                    seed: *seed,
+                    store_root: *store_root,
+                    index_level,
+                    max_archive_size: *max_archive_size,
+                    left_out_file: left_out.as_deref(),
+                    base: base_index.as_ref(),
+                };
+                let mut attempt = 1;
+                let written = loop {
+                    let mut archive = match volume_size {
+                        Some(_) if remote_archive => {
+                            return Err(anyhow!("Only local archives can be split into volumes"));
+                        }
+                        Some(size) => GenericFile::Volumes(Volumes::create(
+                            Path::new(&self.archive),
+                            *size,
+                            *force,
+                        )?),
+                        None => open_general_archive_write(&self.archive, *force)?,
+                    };
+                    let result = build_archive(&source, &mut archive, &recipients, &settings);
+                    match result {
+                        Ok(index) => {
+                            archive.persist(!*no_fsync)?;
+                            break index;
+                        }
+                        Err(e) => {
+                            archive.discard();
+                            // Errors of the source would only happen again.
+                            if attempt < attempts && is_upload_error(&e) {
+                                let delay = 1 << attempt;
+                                warn!(
+                                    "{}: {} (starting again in {} s)",
+                                    "Upload failed".yellow().bold(),
+                                    e,
+                                    delay
+                                );
+                                std::thread::sleep(Duration::from_secs(delay));
+                                attempt += 1;
+                                continue;
+                            }
+                            return Err(e);
+                        }
+                    }
+                };
+                if *verify_after_create {
+                    let mut archive = open_general_archive_read(&self.archive)?;
+                    let identities = self.identities_for(&mut archive)?;
+                    let read_back = Index::parse(&mut archive, &identities)
+                        .context("Written index could not be read back")?;
+                    if read_back.mapping != written.mapping || read_back.hashes != written.hashes {
+                        return Err(anyhow!("Written index does not match the archived files"));
+                    }
+                    info!("index verified: {} files", read_back.mapping.len());
+                }
+                if *verify {
+                    let mut archive = open_general_archive_read(&self.archive)?;
+                    let identities = self.identities_for(&mut archive)?;
+                    let read_back = Index::parse(&mut archive, &identities)
+                        .context("Written index could not be read back")?;
+                    verify_chunks(&mut archive, &read_back, &identities)?;
+                }
             }
-            Commands::Show { path, output } => {
-                let identities = load_identities(self.identity_file.as_ref())?;
+            Commands::Show {
+                paths,
+                output,
+                output_fd,
+                pager,
+                binary,
+                rate_limit,
+                with_headers,
+            } => {
                 let mut archive = open_general_archive_read(&self.archive)?;
-                show_command(&mut archive, path, identities, output)?
+                let identities = self.identities_for(&mut archive)?;
+                if let Some(rate) = rate_limit {
+                    archive = archive.rate_limited(*rate);
+                }
+                show_command(
+                    &mut archive,
+                    paths,
+                    identities,
+                    output,
+                    *output_fd,
+                    *pager,
+                    *binary,
+                    *with_headers,
+                )?
             }
-            Commands::List { prefix } => {
+            Commands::List {
+                prefix,
+                offsets,
+                dirs_only,
+                recursive,
+            } => {
                 let mut archive = open_general_archive_read(&self.archive)?;
-                let identities = load_identities(self.identity_file.as_ref())?;
+                let identities = self.identities_for(&mut archive)?;
                 let prefix = match prefix {
                     Some(p) => p.clone(),
                     None => PathBuf::new(),
                 };
 
-                list_command(&mut archive, &prefix, identities)?
+                list_command(
+                    &mut archive,
+                    &prefix,
+                    &mut std::io::stdout(),
+                    identities,
+                    *offsets,
+                    *dirs_only,
+                    *recursive,
+                )?
             }
             Commands::Mount {
                 mount_point,
                 auto_unmount,
                 cached_files,
                 cached_size,
+                meta_cache_entries,
                 sub_directory,
                 no_reads,
+                file_mode,
+                dir_mode,
+                head_bytes,
+                max_read_file_size,
+                readahead,
+                layers,
             } => {
                 let mut archive = open_general_archive_read(&self.archive)?;
-                let identities = load_identities(self.identity_file.as_ref())?;
+                let identities = self.identities_for(&mut archive)?;
                 let mut index = Index::parse(&mut archive, &identities)?;
+                if !layers.is_empty() {
+                    let encrypted = !identities.is_empty();
+                    let mut indices = vec![index];
+                    let mut files = vec![archive];
+                    for layer in layers {
+                        let mut file = open_general_archive_read(layer)?;
+                        if is_encrypted(&mut file)? != encrypted {
+                            return Err(anyhow!("All layers must use the same encryption"));
+                        }
+                        indices.push(Index::parse(&mut file, &identities).context(layer.clone())?);
+                        files.push(file);
+                    }
+                    index = Index::union(indices)?;
+                    archive = GenericFile::Union(files, 0);
+                }
                 if let Some(sub) = sub_directory {
                     index = index.subindex(sub)?;
                 }
 
+                let settings = MountSettings {
+                    auto_unmount: *auto_unmount,
+                    max_files: *cached_files,
+                    max_size: *cached_size,
+                    max_meta_entries: *meta_cache_entries,
+                    no_reads: *no_reads,
+                    file_mode: *file_mode,
+                    dir_mode: *dir_mode,
+                    head_bytes: *head_bytes,
+                    max_read_file_size: *max_read_file_size,
+                    readahead_bytes: *readahead,
+                };
                 mount(
                     &index,
                     &mut archive,
                     mount_point.to_str().context("Invalid mount point")?,
                     &identities,
-                    *auto_unmount,
-                    *cached_files,
-                    *cached_size,
-                    *no_reads,
+                    &settings,
                 )?
             }
-            Commands::Info {} => {
+            Commands::Browse {} => {
+                let mut archive = open_general_archive_read(&self.archive)?;
+                let identities = self.identities_for(&mut archive)?;
+                let mut index = Index::parse(&mut archive, &identities)?;
+                index.strip_special_bits();
+                browse(&index, &mut archive, &identities)?
+            }
+            Commands::Recover { output } => {
+                let recipients = load_recipients(&self.identity_file)?;
+                let identities = load_identities(&self.identity_file)?;
                 let mut archive = open_general_archive_read(&self.archive)?;
-                let identities = load_identities(self.identity_file.as_ref())?;
-                info_command(&mut archive, identities)?
+                let mut out = open_general_archive_write(output, false)?;
+                match recover(&mut archive, &mut out, &identities, &recipients) {
+                    Ok(()) => out.persist(true)?,
+                    Err(e) => {
+                        out.discard();
+                        return Err(e);
+                    }
+                }
             }
-            Commands::Du { path, humansize } => {
+            Commands::Recompress {
+                output,
+                compression_level,
+                long,
+                force,
+            } => {
+                check_compression_level(*compression_level)?;
+                if let Some(window_log) = long {
+                    if !(10..=WINDOW_LOG_MAX).contains(window_log) {
+                        return Err(anyhow!(
+                            "Invalid window log {} (valid range is 10 to {})",
+                            window_log,
+                            WINDOW_LOG_MAX
+                        ));
+                    }
+                }
                 let mut archive = open_general_archive_read(&self.archive)?;
-                let identities = load_identities(self.identity_file.as_ref())?;
-                du_command(
+                let identities = self.identities_for(&mut archive)?;
+                let index = Index::parse(&mut archive, &identities)?;
+                let index_level = rewritten_index_level(&mut archive, &identities)?;
+                // The copy has to be readable by everyone who could read the original.
+                let recipients = if identities.is_empty() {
+                    vec![]
+                } else {
+                    recipients_from_archive(&self.archive, &self.identity_file)?
+                };
+                let mut out = open_general_archive_write(output, *force)?;
+                match recompress(
                     &mut archive,
-                    path.as_ref().unwrap_or(&PathBuf::new()),
-                    identities,
-                    *humansize,
-                )?
+                    &mut out,
+                    &index,
+                    &identities,
+                    &recipients,
+                    *compression_level,
+                    *long,
+                    index_level,
+                ) {
+                    Ok(()) => out.persist(true)?,
+                    Err(e) => {
+                        out.discard();
+                        return Err(e);
+                    }
+                }
+            }
+            Commands::Update {
+                source,
+                compression_level,
+                skip_errors,
+                preserve_special,
+                no_hidden,
+                exclude,
+                exclude_from,
+                dereference,
+                no_fsync,
+            } => {
+                check_compression_level(*compression_level)?;
+                let mut patterns = exclude.clone();
+                if let Some(file) = exclude_from {
+                    patterns.extend(read_exclude_file(file)?);
+                }
+                let excludes = build_excludes(&patterns)?;
+                let mut archive = open_general_archive_update(&self.archive)?;
+                let identities = self.identities_for(&mut archive)?;
+                let index = Index::parse(&mut archive, &identities)?;
+                let index_level = rewritten_index_level(&mut archive, &identities)?;
+                // New chunks have to be readable by everyone who could read the old ones.
+                let recipients = if identities.is_empty() {
+                    vec![]
+                } else {
+                    recipients_from_archive(&self.archive, &self.identity_file)?
+                };
+                let old_len = archive.seek(SeekFrom::End(0))?;
+                let result = update_archive(
+                    source,
+                    &mut archive,
+                    &index,
+                    &identities,
+                    &recipients,
+                    *compression_level,
+                    index_level,
+                    *skip_errors,
+                    *preserve_special,
+                    *no_hidden,
+                    &excludes,
+                    *dereference,
+                );
+                match result {
+                    Ok(_) => archive.persist(!*no_fsync)?,
+                    Err(e) => {
+                        // Without the new footer, the old index is the valid one again.
+                        if let Err(cut) = archive.set_len(old_len) {
+                            warn!(
+                                "{}: {} (cut it back to {} bytes to read the old index)",
+                                "The archive could not be restored".red().bold(),
+                                cut,
+                                old_len
+                            );
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Commands::Duplicates { json } => {
+                let mut archive = open_general_archive_read(&self.archive)?;
+                let identities = self.identities_for(&mut archive)?;
+                duplicates_command(&mut archive, identities, *json)?
+            }
+            Commands::Stats {
+                by_extension,
+                top,
+                json,
+            } => {
+                let mut archive = open_general_archive_read(&self.archive)?;
+                let identities = self.identities_for(&mut archive)?;
+                stats_command(&mut archive, identities, *by_extension, *top, *json)?
+            }
+            Commands::Analyze {} => {
+                let mut archive = open_general_archive_read(&self.archive)?;
+                let identities = self.identities_for(&mut archive)?;
+                analyze_command(&mut archive, identities)?
+            }
+            Commands::Info {
+                format,
+                which_identity,
+                check_access,
+            } => {
+                let mut archive = open_general_archive_read(&self.archive)?;
+                if *check_access {
+                    check_access_command(&mut archive, &self.identity_file)?
+                } else if *which_identity {
+                    which_identity_command(&mut archive, &self.identity_file)?
+                } else {
+                    let identities = self.identities_for(&mut archive)?;
+                    info_command(&mut archive, identities, *format)?
+                }
+            }
+            Commands::Du {
+                paths,
+                humansize,
+                total,
+            } => {
+                let mut archive = open_general_archive_read(&self.archive)?;
+                let identities = self.identities_for(&mut archive)?;
+                du_command(&mut archive, paths, identities, *humansize, *total)?
             }
             Commands::Restore {
                 from,
                 to,
                 trust_hashes,
+                rate_limit,
+                threads_io,
+                reflink,
+                manifest,
+                base,
+                events,
+                format,
+                dry_run,
+                json,
+                paths_from,
+                skip_missing,
+                keep_special_bits,
             } => {
+                let stdin = Path::new("-");
+                if paths_from.as_deref() == Some(stdin)
+                    && self.identity_file.iter().any(|p| p == stdin)
+                {
+                    return Err(anyhow!(
+                        "The path list and an identity file can not both be read from stdin"
+                    ));
+                }
                 let mut archive = open_general_archive_read(&self.archive)?;
-                let identities = load_identities(self.identity_file.as_ref())?;
+                if let Some(base) = base {
+                    archive = archive.with_base(open_general_archive_read(base)?);
+                }
+                if let Some(rate) = rate_limit {
+                    archive = archive.rate_limited(*rate);
+                }
+                let mut pool = if *threads_io > 1 {
+                    let mut files = vec![];
+                    for _ in 0..*threads_io {
+                        let mut file = open_general_archive_read(&self.archive)?;
+                        if let Some(base) = base {
+                            file = file.with_base(open_general_archive_read(base)?);
+                        }
+                        if let Some(rate) = rate_limit {
+                            // The limit applies to all connections together.
+                            file = file.rate_limited((rate / threads_io).max(1));
+                        }
+                        files.push(file);
+                    }
+                    Some(ArchivePool::new(files))
+                } else {
+                    None
+                };
+                let identities = self.identities_for(&mut archive)?;
                 let from = match from {
                     Some(p) => p.clone(),
                     None => PathBuf::new(),
                 };
-                restore_command(&mut archive, &from, to, &identities, *trust_hashes)?
+                let settings = RestoreSettings {
+                    trust: *trust_hashes,
+                    reflink: *reflink,
+                    events: *events,
+                    format: format.unwrap_or_else(|| RestoreFormat::detect(to)),
+                    dry_run: *dry_run,
+                    json: *json,
+                    paths_from: paths_from.as_deref(),
+                    skip_missing: *skip_missing,
+                    manifest: manifest.as_deref(),
+                    special_bits: *keep_special_bits,
+                };
+                restore_command(
+                    &mut archive,
+                    &from,
+                    to,
+                    &identities,
+                    &settings,
+                    pool.as_mut(),
+                )?
             }
-            Commands::Find { name: pattern } => {
+            Commands::Find {
+                name: pattern,
+                within,
+                fold,
+            } => {
                 let mut archive = open_general_archive_read(&self.archive)?;
-                let identities = load_identities(self.identity_file.as_ref())?;
-                find_command(&mut archive, pattern, identities)?;
+                let identities = self.identities_for(&mut archive)?;
+                find_command(
+                    &mut archive,
+                    pattern,
+                    within.as_ref().unwrap_or(&PathBuf::new()),
+                    identities,
+                    *fold,
+                )?;
             }
         };
 
         Ok(())
     }
 }
+/// Several paths are concatenated. Headers are only printed to stdout.
+#[allow(clippy::too_many_arguments)]
 fn show_command(
     archive: &mut GenericFile,
-    path: &Path,
-
+    paths: &[PathBuf],
     ids: Vec<Box<dyn age::Identity>>,
     out: &Option<PathBuf>,
+    out_fd: Option<RawFd>,
+    pager: bool,
+    binary: bool,
+    headers: bool,
 ) -> Result<()> {
     let index = Index::parse(archive, &ids)?;
+    if let Some(fd) = out_fd {
+        // SAFETY: F_GETFD only reads the descriptor flags and fails with EBADF if `fd` is
+        // not open.
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(anyhow!(
+                "File descriptor {} is not open: {}",
+                fd,
+                std::io::Error::last_os_error()
+            ));
+        }
+        // SAFETY: `fd` was checked to be open above. The descriptor belongs to the parent
+        // process, so the `File` must not close it: the `ManuallyDrop` never runs its
+        // destructor, and the `File` does not outlive this block, so ownership stays with
+        // the caller and no other handle here can close or reuse the descriptor meanwhile.
+        let mut file = ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+        for path in paths {
+            stream_file(archive, path, &mut *file, &index, &ids)?;
+        }
+        file.flush()?;
+        return Ok(());
+    }
     match out {
+        Some(file) if paths.len() == 1 => {
+            copy_file(archive, &paths[0], file, &index, &ids)?;
+        }
         Some(file) => {
-            copy_file(archive, path, file, &index, &ids)?;
+            let mut file = fs::File::create(file)?;
+            for path in paths {
+                stream_file(archive, path, &mut file, &index, &ids)?;
+            }
+        }
+        None if std::io::stdout().is_terminal() => {
+            if pager && paths.len() > 1 {
+                return Err(anyhow!("The pager can only show a single file"));
+            }
+            for (i, path) in paths.iter().enumerate() {
+                // The head is only decoded once, it is written out before the rest.
+                let mut content = open_file(archive, path, &index, &ids)?;
+                let mut head = vec![];
+                (&mut content).take(SNIFF_BYTES).read_to_end(&mut head)?;
+                if !binary && head.contains(&0) {
+                    warn!(
+                        "{} looks like a binary file (use --output or a pipe to avoid this warning)",
+                        path.to_string_lossy()
+                    );
+                }
+                let mut content = head.as_slice().chain(content);
+                if headers {
+                    print_header(path, i == 0);
+                }
+                if pager {
+                    page(&mut content)?;
+                } else {
+                    std::io::copy(&mut content, &mut std::io::stdout())?;
+                }
+            }
         }
         None => {
-            let mut stdout = std::io::stdout();
-            stream_file(archive, path, &mut stdout, &index, &ids)?;
+            for (i, path) in paths.iter().enumerate() {
+                if headers {
+                    print_header(path, i == 0);
+                }
+                stream_file(archive, path, &mut std::io::stdout(), &index, &ids)?;
+            }
         }
     }
     Ok(())
 }
+
+/// Separates files on stdout like `tail` does, with an empty line before all but the first.
+fn print_header(path: &Path, first: bool) {
+    if !first {
+        println!();
+    }
+    println!("==> {} <==", path.to_string_lossy());
+}
+/// Prints the size of each path. With a single path (or none), only the size is printed;
+/// with several, each line is followed by the path, like du.
 fn du_command(
     archive: &mut GenericFile,
-    path: &Path,
+    paths: &[PathBuf],
     ids: Vec<Box<dyn age::Identity>>,
     hflag: bool,
+    total: bool,
 ) -> Result<()> {
-    let index = Index::parse(archive, &ids)?;
-    let size = index.du(path)?;
-    if hflag {
-        println!("{}", format_size(size, DECIMAL))
+    let index = LazyIndex::parse(archive, &ids)?;
+    let whole_archive = [PathBuf::new()];
+    let paths = if paths.is_empty() {
+        &whole_archive[..]
     } else {
-        println!("{size}");
+        paths
+    };
+    let format = |size: u64| {
+        if hflag {
+            format_size(size, DECIMAL)
+        } else {
+            size.to_string()
+        }
+    };
+    // Files under overlapping paths are only counted once for the total. Like du, a hard
+    // link and the file it links to are counted once.
+    let mut counted = HashSet::new();
+    let mut total_size = 0;
+    for path in paths {
+        let mut size = 0;
+        let mut seen = HashSet::new();
+        for e in index.iter_under(path) {
+            let (file, (i, _)) = e?;
+            let file_size = index.size(i)?.context("Size not in index")?;
+            let file = index
+                .hardlink_target(&file)
+                .map(Path::to_path_buf)
+                .unwrap_or(file);
+            if seen.insert(file.clone()) {
+                size += file_size;
+            }
+            if counted.insert(file) {
+                total_size += file_size;
+            }
+        }
+        if paths.len() == 1 {
+            println!("{}", format(size));
+        } else {
+            println!("{}\t{}", format(size), path.to_string_lossy());
+        }
+    }
+    if total {
+        println!("{}\ttotal", format(total_size));
     }
     Ok(())
 }
 
-fn list_command(
+fn list_command<W: Write>(
     archive: &mut GenericFile,
     prefix: &Path,
+    out: &mut W,
     ids: Vec<Box<dyn age::Identity>>,
+    offsets: bool,
+    dirs_only: bool,
+    recursive: bool,
 ) -> Result<()> {
-    let index = Index::parse(archive, &ids)?.subindex(prefix)?;
-    let mut children = vec![];
-    for path in index.mapping.keys() {
-        let first = path
-            .components()
-            .next()
-            .context("Empty entry! (It might be a file and not a directory)")?;
-        if !children.contains(&first) {
-            children.push(first);
+    let index = LazyIndex::parse(archive, &ids)?;
+    let mut files = BTreeMap::new();
+    let mut dirs = BTreeSet::new();
+    let mut found = prefix == Path::new("");
+    for entry in index.iter_under(prefix) {
+        let (path, chunk) = entry?;
+        let mut rest = path.strip_prefix(prefix)?.components();
+        let Some(name) = rest.next() else {
+            return Err(anyhow!("{} is a file", prefix.to_string_lossy()));
+        };
+        found = true;
+        if recursive {
+            files.insert(path, chunk);
+        } else if rest.next().is_none() {
+            files.insert(prefix.join(name), chunk);
+        } else {
+            dirs.insert(prefix.join(name));
+        }
+    }
+    for empty_dir in index.empty_dirs_under(prefix)? {
+        found = true;
+        if let Some(name) = empty_dir.strip_prefix(prefix)?.components().next() {
+            dirs.insert(prefix.join(name));
+        }
+    }
+    let mut others = BTreeMap::new();
+    for (path, kind) in index.others_under(prefix) {
+        let mut rest = path.strip_prefix(prefix)?.components();
+        let Some(name) = rest.next() else {
+            return Err(anyhow!(
+                "{} is not a directory in index",
+                prefix.to_string_lossy()
+            ));
+        };
+        found = true;
+        if recursive {
+            others.insert(path, kind);
+        } else if rest.next().is_none() {
+            others.insert(prefix.join(name), kind);
+        } else {
+            dirs.insert(prefix.join(name));
         }
     }
+    if !found {
+        return Err(anyhow!(
+            "{} is not a directory in index",
+            prefix.to_string_lossy()
+        ));
+    }
+    if recursive {
+        dirs = index.directories_under(prefix)?;
+    }
+    if dirs_only {
+        files.clear();
+        others.clear();
+    }
+    let mut children = files
+        .keys()
+        .chain(others.keys())
+        .chain(&dirs)
+        .cloned()
+        .collect::<Vec<_>>();
+    children.sort();
     for p in children {
-        if index.is_file(&PathBuf::new().join(p)) {
-            let size = index.du(&PathBuf::new().join(p))?;
+        let name = p.strip_prefix(prefix)?.to_string_lossy();
+        if let Some((offset, chunk_len)) = files.get(&p) {
+            let size = index.size(*offset)?.context("Size not in index")?;
             let size_fmt = format_size(size, DECIMAL);
-            println!("{:12} {}", size_fmt, p.as_os_str().to_string_lossy());
-        } else {
-            println!(
-                "{:12} {}",
+            if offsets {
+                writeln!(
+                    out,
+                    "{:12} {:>14} {:>12} {}",
+                    size_fmt, offset, chunk_len, name
+                )?;
+            } else {
+                writeln!(out, "{:12} {}", size_fmt, name)?;
+            }
+        } else if let Some(kind) = others.get(&p) {
+            let name = match kind {
+                EntryKind::Symlink { target } => {
+                    format!("{} -> {}", name, target.to_string_lossy())
+                }
+                _ => name.to_string(),
+            };
+            if offsets {
+                writeln!(out, "{:12} {:>14} {:>12} {}", "-", "-", "-", name)?;
+            } else {
+                writeln!(out, "{:12} {}", "-", name)?;
+            }
+        } else if offsets {
+            writeln!(
+                out,
+                "{:12} {:>14} {:>12} {}",
                 "-".blue().bold(),
-                p.as_os_str().to_string_lossy().blue().bold()
-            );
+                "-",
+                "-",
+                name.blue().bold()
+            )?;
+        } else {
+            writeln!(out, "{:12} {}", "-".blue().bold(), name.blue().bold())?;
         }
     }
     Ok(())
@@ -321,66 +1908,446 @@ fn list_command(
 fn find_command(
     archive: &mut GenericFile,
     pattern: &str,
+    within: &Path,
     ids: Vec<Box<dyn age::Identity>>,
+    fold: bool,
 ) -> Result<()> {
-    let index = Index::parse(archive, &ids)?;
-    let matches = index.search(pattern);
-    for p in matches {
-        if index.is_file(&p) {
-            let size = index.du(&p)?;
-            let size_fmt = format_size(size, DECIMAL);
-            println!("{:12} {}", size_fmt, p.to_string_lossy());
-        } else {
-            println!(
+    let index = LazyIndex::parse(archive, &ids)?;
+    let search = PathSearch::new(pattern, fold);
+    let not_a_directory = || anyhow!("{} is not a directory in index", within.to_string_lossy());
+    // Only the matches are kept (files with their size), not every file below `within`.
+    let mut matches: BTreeMap<PathBuf, Found> = BTreeMap::new();
+    let mut found = false;
+    for entry in index.iter_under(within) {
+        let (path, (i, _)) = entry?;
+        if path == within {
+            return Err(not_a_directory());
+        }
+        found = true;
+        let relative = path.strip_prefix(within)?;
+        for m in search.matches(relative) {
+            if m == relative {
+                let size = index.size(i)?.context("Size not in index")?;
+                matches.insert(m.to_path_buf(), Found::File(size));
+            } else {
+                matches.entry(m.to_path_buf()).or_insert(Found::Dir);
+            }
+        }
+    }
+    // Special files and links are shown like files without a size.
+    for (path, _) in index.others_under(within) {
+        if path == within {
+            return Err(not_a_directory());
+        }
+        found = true;
+        let relative = path.strip_prefix(within)?;
+        for m in search.matches(relative) {
+            if m == relative {
+                matches.insert(m.to_path_buf(), Found::Other);
+            } else {
+                matches.entry(m.to_path_buf()).or_insert(Found::Dir);
+            }
+        }
+    }
+    for path in index.empty_dirs_under(within)? {
+        found = true;
+        for m in search.matches(path.strip_prefix(within)?) {
+            matches.entry(m.to_path_buf()).or_insert(Found::Dir);
+        }
+    }
+    if within != Path::new("") && !found {
+        return Err(not_a_directory());
+    }
+    for (m, kind) in matches {
+        let p = within.join(&m);
+        match kind {
+            Found::File(size) => {
+                let size_fmt = format_size(size, DECIMAL);
+                println!("{:12} {}", size_fmt, highlighted(&p, pattern, false, fold));
+            }
+            Found::Other => println!("{:12} {}", "-", highlighted(&p, pattern, false, fold)),
+            Found::Dir => println!(
                 "{:12} {}",
                 "-".blue().bold(),
-                p.to_string_lossy().blue().bold()
-            );
+                highlighted(&p, pattern, true, fold)
+            ),
         }
     }
     Ok(())
 }
-fn info_command(archive: &mut GenericFile, ids: Vec<Box<dyn age::Identity>>) -> Result<()> {
-    archive.seek(std::io::SeekFrom::End(-16))?;
-    let index_size = u64::read_bin(archive)?;
-    let magic_number = u64::read_bin(archive)?;
 
+/// What a match of `find` is.
+enum Found {
+    File(u64),
+    /// A special file or a symbolic link.
+    Other,
+    Dir,
+}
+
+/// A search result with the part that matched in red (directories are blue).
+fn highlighted(path: &Path, pattern: &str, dir: bool, fold: bool) -> String {
+    let s = path.to_string_lossy();
+    let paint = |part: &str| {
+        if dir {
+            part.blue().bold()
+        } else {
+            part.normal()
+        }
+    };
+    match match_span(path, pattern, fold) {
+        Some(span) => format!(
+            "{}{}{}",
+            paint(&s[..span.start]),
+            s[span.clone()].red().bold(),
+            paint(&s[span.end..])
+        ),
+        None => paint(&s).to_string(),
+    }
+}
+
+fn duplicates_command(
+    archive: &mut GenericFile,
+    ids: Vec<Box<dyn age::Identity>>,
+    json: bool,
+) -> Result<()> {
     let index = Index::parse(archive, &ids)?;
+    let groups = index.duplicate_groups();
+    if json {
+        let groups = groups
+            .iter()
+            .map(|(size, paths)| {
+                serde_json::json!({
+                    "size": size,
+                    "paths": paths.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&groups)?);
+        return Ok(());
+    }
+    for (size, paths) in groups {
+        println!(
+            "{} ({} copies)",
+            format_size(size, DECIMAL).bold(),
+            paths.len()
+        );
+        for p in paths {
+            println!("  {}", p.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+/// Counts and sizes of all files, grouped by their (lowercase) extension if requested.
+/// Groups are sorted by size, largest first.
+fn stats_command(
+    archive: &mut GenericFile,
+    ids: Vec<Box<dyn age::Identity>>,
+    by_extension: bool,
+    top: Option<usize>,
+    json: bool,
+) -> Result<()> {
+    let index = LazyIndex::parse(archive, &ids)?;
+    let mut groups: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    // Hard links count as files, but their content only once. Special files and links have
+    // no content.
+    let files = index
+        .iter_under(Path::new(""))
+        .map(|entry| -> Result<(PathBuf, u64)> {
+            let (path, (i, _)) = entry?;
+            let size = match index.hardlink_target(&path) {
+                Some(_) => 0,
+                None => index.size(i)?.context("Size not in index")?,
+            };
+            Ok((path, size))
+        });
+    let others = index
+        .others_under(Path::new(""))
+        .into_iter()
+        .map(|(path, _)| Ok((path, 0)));
+    for entry in files.chain(others) {
+        let (path, size): (PathBuf, u64) = entry?;
+        let group = match path.extension() {
+            _ if !by_extension => "all".to_string(),
+            Some(ext) => ext.to_string_lossy().to_lowercase(),
+            None => "(none)".to_string(),
+        };
+        let (files, total) = groups.entry(group).or_default();
+        *files += 1;
+        *total += size;
+    }
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by(|a, b| b.1.1.cmp(&a.1.1));
+    groups.truncate(top.unwrap_or(groups.len()));
+    if json {
+        let groups = groups
+            .iter()
+            .map(|(group, (files, size))| {
+                serde_json::json!({
+                    "group": group,
+                    "files": files,
+                    "size": size,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&groups)?);
+        return Ok(());
+    }
+    for (group, (files, size)) in groups {
+        println!("{:12} {:>8} {}", format_size(size, DECIMAL), files, group);
+    }
+    Ok(())
+}
+
+fn info_command(
+    archive: &mut GenericFile,
+    ids: Vec<Box<dyn age::Identity>>,
+    format: InfoFormat,
+) -> Result<()> {
+    let info = archive_info(archive, &ids)?;
+    match format {
+        InfoFormat::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+        InfoFormat::Toml => print!("{}", toml::to_string(&info)?),
+        InfoFormat::Text => print_info(&info),
+    }
+    Ok(())
+}
+
+fn archive_info(
+    archive: &mut GenericFile,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<ArchiveInfo> {
+    let footer = locate_footer(archive)?;
+    let footer_info = read_footer(archive, footer)?;
+    let index_size = footer_info.index_len;
+    archive.seek(std::io::SeekFrom::Start(footer + footer_info.len - 8))?;
+    let magic_number = u64::read_bin(archive)?;
+    archive.seek(std::io::SeekFrom::Start(
+        footer
+            .checked_sub(index_size)
+            .context("Invalid index length")?,
+    ))?;
+    let mut age_header = vec![];
+    archive.by_ref().take(64).read_to_end(&mut age_header)?;
+    let age_version = age_header
+        .split(|b| *b == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+
+    let encrypted = is_encrypted(archive)?;
+    let index = Index::parse(archive, ids)?;
+    archive.seek(std::io::SeekFrom::Start(footer - index_size))?;
+    let index_compressed = decrypt_index(archive, &mut std::io::sink(), index_size, 0, ids)?;
     let mut total_size = 0_u64;
     for k in index.mapping.values() {
         total_size += index.sizes.get(&k.0).context("Size could not be read")?;
     }
-    let duplicats = index.mapping.len() - index.hashes.len();
+    // Files beyond the first of each chunk. Hashes do not count, an index may hold unused ones.
+    let chunks = index
+        .mapping
+        .values()
+        .map(|(i, _)| i)
+        .collect::<HashSet<_>>();
+    let duplicats = index.mapping.len() - chunks.len();
     let compressed_size = archive.seek(std::io::SeekFrom::End(0))?;
-    println!("magic number: {:X}", magic_number);
-    println!("files: {}", index.mapping.len());
-    println!("size original: {}", format_size(total_size, DECIMAL));
-    println!("size compressed: {}", format_size(compressed_size, DECIMAL));
+    let info = ArchiveInfo {
+        magic_number,
+        format_version: format_version(magic_number, footer_info.revision),
+        encrypted,
+        age_format: encrypted.then(|| age_version.to_string()),
+        zipurat_version: env!("CARGO_PKG_VERSION"),
+        files: index.mapping.len(),
+        size_original: total_size,
+        size_compressed: compressed_size,
+        compression_ratio: (total_size as f64) / (compressed_size as f64),
+        duplicate_files: duplicats,
+        empty_directories: index.empty_dirs.len(),
+        special_files: index.special_files.len(),
+        symlinks: index.symlinks.len(),
+        hard_links: index.hardlinks.len(),
+        dedup: match index.dedup_mode {
+            DEDUP_HASH => "hash",
+            DEDUP_NONE => "none",
+            _ => "content",
+        },
+        size_index: index_size,
+        index_compressed,
+        bytes_after_footer: compressed_size - footer - footer_info.len,
+        metadata: index.metadata.iter().cloned().collect(),
+    };
+    Ok(info)
+}
+
+/// Decompresses every chunk of the archive and compares it with its hash in the index.
+/// Deduplicated files share a chunk, so each chunk is read once. Chunks of a base archive
+/// (delta archives) are not part of this archive and are skipped.
+fn verify_chunks(
+    archive: &mut GenericFile,
+    index: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<()> {
+    let mut chunks: BTreeMap<(u64, u64), Vec<&PathBuf>> = BTreeMap::new();
+    for (path, chunk) in &index.mapping {
+        if chunk.0 & BASE_CHUNK == 0 {
+            chunks.entry(*chunk).or_default().push(path);
+        }
+    }
+    let pb = ProgressBar::new(chunks.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template("{bar:40} {pos:>7}/{len:7} [{elapsed_precise}, eta {eta}]")
+            .context("Progress bar error")?,
+    );
+    let mut failed = 0;
+    for (n, ((start, len), paths)) in chunks.iter().enumerate() {
+        pb.set_position(n as u64);
+        let expected = index.hashes.get(start).map(|h| blake3::Hash::from(*h));
+        let mut hasher = blake3::Hasher::new();
+        let actual = archive
+            .seek(SeekFrom::Start(*start))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| decrypt_and_decompress(archive, &mut hasher, *len, ids))
+            .map(|_| hasher.finalize());
+        let problem = match (expected, actual) {
+            (Some(expected), Ok(actual)) if expected == actual => continue,
+            (Some(expected), Ok(actual)) => {
+                format!("expected {}, got {}", expected.to_hex(), actual.to_hex())
+            }
+            (None, _) => "no hash in the index".to_string(),
+            (Some(_), Err(e)) => format!("could not be read: {e}"),
+        };
+        failed += 1;
+        pb.suspend(|| {
+            for path in paths {
+                warn!(
+                    "{}:\n{} ({})",
+                    "Verification failed".red().bold(),
+                    path.to_string_lossy(),
+                    problem
+                );
+            }
+        });
+    }
+    pb.finish_and_clear();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} chunks do not match their hash",
+            failed,
+            chunks.len()
+        ));
+    }
+    info!("verified: {} chunks", chunks.len());
+    Ok(())
+}
+
+/// Compares the space between the leading magic number and the index with the chunks the
+/// index refers to. The rest (for example interim indices from checkpoints) is wasted.
+/// Only the index is decrypted.
+fn analyze_command(archive: &mut GenericFile, ids: Vec<Box<dyn age::Identity>>) -> Result<()> {
+    let footer = locate_footer(archive)?;
+    let footer_info = read_footer(archive, footer)?;
+    let index_size = footer_info.index_len;
+    let index_start = footer
+        .checked_sub(index_size)
+        .context("Invalid index length")?;
+    let index = Index::parse(archive, &ids)?;
+    let chunks = index
+        .mapping
+        .values()
+        .filter(|(i, _)| i & BASE_CHUNK == 0)
+        .collect::<HashSet<_>>();
+    let referenced: u64 = chunks.iter().map(|(_, len)| len).sum();
+    let data = index_start - 8;
+    let wasted = data.saturating_sub(referenced);
+    println!("data section: {}", format_size(data, DECIMAL));
+    println!(
+        "referenced chunks: {} ({} chunks)",
+        format_size(referenced, DECIMAL),
+        chunks.len()
+    );
     println!(
-        "compression ratio: {:.2}",
-        (total_size as f64) / (compressed_size as f64)
+        "unreferenced: {} ({:.1}%)",
+        format_size(wasted, DECIMAL),
+        100.0 * wasted as f64 / data.max(1) as f64
     );
-    println!("duplicate files: {}", duplicats);
-    println!("empty directories: {}", index.empty_dirs.len());
-    println!("size index: {}", format_size(index_size, DECIMAL));
+    let trailing = archive.seek(std::io::SeekFrom::End(0))? - footer - footer_info.len;
+    if trailing > 0 {
+        println!("bytes after footer: {}", trailing);
+    }
     Ok(())
 }
 
-fn load_identities(provided: Option<&PathBuf>) -> Result<Vec<Box<dyn age::Identity>>> {
-    if let Some(file) = provided {
-        let ids = age::IdentityFile::from_file(
-            file.to_str().context("Invalid path for IDs")?.to_string(),
-        )
-        .context("Indentity file could not be loaded")?
-        .into_identities()?;
+fn print_info(info: &ArchiveInfo) {
+    println!("magic number: {:X}", info.magic_number);
+    println!(
+        "format version: {}",
+        info.format_version.unwrap_or("unknown")
+    );
+    match &info.age_format {
+        Some(age_format) => println!("age format: {}", age_format),
+        None => println!("encrypted: no"),
+    }
+    println!("zipurat version: {}", info.zipurat_version);
+    println!("files: {}", info.files);
+    println!(
+        "size original: {}",
+        format_size(info.size_original, DECIMAL)
+    );
+    println!(
+        "size compressed: {}",
+        format_size(info.size_compressed, DECIMAL)
+    );
+    println!("compression ratio: {:.2}", info.compression_ratio);
+    println!("duplicate files: {}", info.duplicate_files);
+    println!("empty directories: {}", info.empty_directories);
+    println!("special files: {}", info.special_files);
+    println!("symbolic links: {}", info.symlinks);
+    println!("hard links: {}", info.hard_links);
+    println!("deduplication: by {}", info.dedup);
+    for (key, value) in &info.metadata {
+        println!("{}: {}", key.replace('_', " "), value);
+    }
+    println!(
+        "size index: {}{}",
+        format_size(info.size_index, DECIMAL),
+        if info.index_compressed {
+            ""
+        } else {
+            " (store-only)"
+        }
+    );
+    if info.bytes_after_footer > 0 {
+        println!(
+            "bytes after footer: {} (trailing data or archive still being written)",
+            info.bytes_after_footer
+        );
+    }
+}
+
+fn load_identities(provided: &[PathBuf]) -> Result<Vec<Box<dyn age::Identity>>> {
+    Ok(labeled_identities(provided)?
+        .into_iter()
+        .flat_map(|(_, ids)| ids)
+        .collect())
+}
+
+/// The identities grouped by the file (or variable) they were loaded from.
+fn labeled_identities(provided: &[PathBuf]) -> Result<Vec<(String, Vec<Box<dyn age::Identity>>)>> {
+    let files = identity_files(provided)?;
+    if !files.is_empty() {
+        let mut ids = vec![];
+        for (label, file) in files {
+            let identities = file
+                .into_identities()
+                .context(format!("Identity file {label} contains no usable identity"))?;
+            ids.push((label, identities));
+        }
         return Ok(ids);
     }
     let mut all_ids = vec![];
-    let dir = dirs::config_dir()
-        .map(|cfg| cfg.join("age"))
-        .context("Home directory not found")?;
+    let dir = identity_dir()?;
     let entries: Vec<_> = fs::read_dir(&dir)
-        .context(format!("{} not found", dir.to_string_lossy()))?
+        .context(format!("{} could not be read", dir.to_string_lossy()))?
         .filter_map(Result::ok)
         .map(|entry| entry.path())
         .filter(|path| path.is_file())
@@ -392,16 +2359,292 @@ fn load_identities(provided: Option<&PathBuf>) -> Result<Vec<Box<dyn age::Identi
                 .to_string(),
         );
         if let Ok(idf) = idf {
-            if let Ok(mut ids) = idf.into_identities() {
-                all_ids.append(&mut ids);
+            if let Ok(ids) = idf.with_callbacks(UiCallbacks).into_identities() {
+                all_ids.push((f.to_string_lossy().to_string(), ids));
             }
         }
     }
     if all_ids.is_empty() {
         return Err(anyhow!(
-            "No valid age IDs found in {}",
+            "No valid age IDs found in {} (use --identity-file)",
             dir.to_string_lossy()
         ));
     }
     Ok(all_ids)
 }
+
+/// The directory searched for identity files when none are given: `ZIPURAT_IDENTITY_DIR`
+/// if it is set, otherwise the first existing one of `<config dir>/age` and `~/.config/age`.
+fn identity_dir() -> Result<PathBuf> {
+    let candidates = match std::env::var_os(IDENTITY_DIR_ENV) {
+        Some(dir) => vec![PathBuf::from(dir)],
+        None => {
+            let mut candidates = vec![];
+            if let Some(cfg) = dirs::config_dir() {
+                candidates.push(cfg.join("age"));
+            }
+            if let Some(home) = dirs::home_dir() {
+                let dir = home.join(".config").join("age");
+                if !candidates.contains(&dir) {
+                    candidates.push(dir);
+                }
+            }
+            candidates
+        }
+    };
+    if let Some(dir) = candidates.iter().find(|d| d.is_dir()) {
+        return Ok(dir.clone());
+    }
+    let tried = candidates
+        .iter()
+        .map(|d| d.to_string_lossy())
+        .collect::<Vec<_>>();
+    let tried = if tried.is_empty() {
+        format!("no home directory, {IDENTITY_DIR_ENV} is not set")
+    } else {
+        tried.join(", ")
+    };
+    Err(anyhow!(
+        "No identity directory found (tried: {tried}). Use --identity-file or set {IDENTITY_ENV}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::plain_magic_number;
+    use crate::testutil::{create_archive, open_archive, replace_index, scratch_dir, write_files};
+    use crate::utils::encrypt_only;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
+
+    /// Records what a plugin asks for instead of prompting in the terminal.
+    #[derive(Clone, Default)]
+    struct RecordingCallbacks(Arc<Mutex<Vec<String>>>);
+
+    impl age::Callbacks for RecordingCallbacks {
+        fn display_message(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+
+        fn confirm(&self, message: &str, _yes: &str, _no: Option<&str>) -> Option<bool> {
+            self.0.lock().unwrap().push(message.to_string());
+            Some(true)
+        }
+
+        fn request_public_string(&self, description: &str) -> Option<String> {
+            self.0.lock().unwrap().push(description.to_string());
+            None
+        }
+
+        fn request_passphrase(&self, description: &str) -> Option<age::secrecy::SecretString> {
+            self.0.lock().unwrap().push(description.to_string());
+            None
+        }
+    }
+
+    /// A recipient plugin that asks the user to touch their key and then wraps the file
+    /// key into a fake stanza (see the age plugin protocol, recipient-v1).
+    const FAKE_PLUGIN: &str = r#"#!/bin/sh
+while read -r line; do
+    if [ "$line" = "-> done" ]; then
+        read -r _
+        break
+    fi
+done
+printf -- '-> msg\nVG91Y2ggeW91ciBrZXk\n'
+read -r _
+read -r _
+printf -- '-> recipient-stanza 0 zprttest\nZmFrZQ\n'
+read -r _
+read -r _
+printf -- '-> done\n\n'
+"#;
+
+    #[test]
+    fn plugin_prompts_reach_the_callbacks() {
+        let dir = scratch_dir("plugin");
+        let plugin = dir.join("age-plugin-zprttest");
+        std::fs::write(&plugin, FAKE_PLUGIN).unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![dir.clone()];
+        paths.extend(std::env::split_paths(&path));
+        // SAFETY: no other test reads PATH, and none of them runs C code that reads the
+        // environment while it is set.
+        unsafe { std::env::set_var("PATH", std::env::join_paths(paths).unwrap()) };
+        let identity = dir.join("identity.txt");
+        std::fs::write(
+            &identity,
+            "AGE-PLUGIN-ZPRTTEST-1QQQSYQCYQ5RQWZQFPG9SCRGWPUXNH05Y\n",
+        )
+        .unwrap();
+
+        let callbacks = RecordingCallbacks::default();
+        let files = identity_files_with(&[identity], callbacks.clone()).unwrap();
+        let recipients = files[0].1.to_recipients().unwrap();
+        let mut encrypted = vec![];
+        encrypt_only(&mut b"content".as_slice(), &mut encrypted, &recipients).unwrap();
+        assert_eq!(*callbacks.0.lock().unwrap(), vec!["Touch your key"]);
+        let header = String::from_utf8_lossy(&encrypted[..encrypted.len().min(200)]);
+        assert!(header.contains("-> zprttest"), "{header}");
+    }
+
+    #[test]
+    fn overlarge_port() {
+        let error = parse_sftp_url("sftp://u@h:99999:p").unwrap_err();
+        assert!(error.to_string().contains("Invalid port 99999"));
+        // Not mistaken for a local path.
+        let error = open_general_archive_read("sftp://u@h:99999:p")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("Invalid port 99999"));
+        assert!(parse_sftp_url("sftp://u@h:65535:p").unwrap().is_some());
+        assert!(parse_sftp_url("local/archive.zprt").unwrap().is_none());
+    }
+
+    #[test]
+    fn list_root_with_empty_directories() {
+        let dir = scratch_dir("list-empty-dirs");
+        let source = dir.join("source");
+        for empty in ["a", "b/c"] {
+            std::fs::create_dir_all(source.join(empty)).unwrap();
+        }
+        let archive = dir.join("only-dirs.zprt");
+        create_archive(&source, &archive, &[]).unwrap();
+        let index = Index::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        assert!(index.mapping.is_empty());
+        let children = index.get_direct_children(Path::new("")).unwrap();
+        assert_eq!(
+            children,
+            HashSet::from([PathBuf::from("a"), PathBuf::from("b")])
+        );
+        let lazy = LazyIndex::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        let dirs = lazy.directories_under(Path::new("")).unwrap();
+        assert_eq!(
+            dirs.into_iter().collect::<Vec<_>>(),
+            [Path::new("a"), Path::new("b"), Path::new("b/c")]
+        );
+        colored::control::set_override(false);
+        let listing = |archive: &Path, prefix: &str, recursive: bool| {
+            let mut out = vec![];
+            list_command(
+                &mut open_archive(archive),
+                Path::new(prefix),
+                &mut out,
+                vec![],
+                false,
+                false,
+                recursive,
+            )
+            .unwrap();
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| line.split_whitespace().last().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(listing(&archive, "", false), ["a", "b"]);
+        assert_eq!(listing(&archive, "", true), ["a", "b", "b/c"]);
+
+        // Empty directories next to files.
+        write_files(&source, &[("b/file.txt", b"content")]);
+        let archive = dir.join("mixed.zprt");
+        create_archive(&source, &archive, &[]).unwrap();
+        let index = Index::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        let children = index.get_direct_children(Path::new("")).unwrap();
+        assert_eq!(
+            children,
+            HashSet::from([PathBuf::from("a"), PathBuf::from("b")])
+        );
+        let children = index.get_direct_children(Path::new("b")).unwrap();
+        assert_eq!(
+            children,
+            HashSet::from([PathBuf::from("b/c"), PathBuf::from("b/file.txt")])
+        );
+        assert_eq!(listing(&archive, "b", false), ["c", "file.txt"]);
+        assert_eq!(listing(&archive, "", false), ["a", "b"]);
+    }
+
+    #[test]
+    fn create_without_encryption() {
+        let dir = scratch_dir("no-encrypt");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("sub/b.txt", b"world")]);
+        let archive = dir.join("archive.zprt");
+        let run = |args: &[&Path]| {
+            let mut argv = vec![Path::new("zipurat"), archive.as_path()];
+            argv.extend(args.iter().copied());
+            Cli::try_parse_from(argv).unwrap().run()
+        };
+        run(&[Path::new("create"), &source, Path::new("--no-encrypt")]).unwrap();
+        let mut file = open_archive(&archive);
+        assert!(!is_encrypted(&mut file).unwrap());
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(u64::read_bin(&mut file).unwrap(), plain_magic_number());
+        // No identity file is needed to read it back.
+        let to = dir.join("restored");
+        run(&[Path::new("restore"), &to]).unwrap();
+        assert_eq!(std::fs::read(to.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(to.join("sub/b.txt")).unwrap(), b"world");
+    }
+
+    #[test]
+    fn show_to_a_closed_descriptor() {
+        let dir = scratch_dir("show-bad-fd");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello")]);
+        let archive = dir.join("archive.zprt");
+        create_archive(&source, &archive, &[]).unwrap();
+        let err = show_command(
+            &mut open_archive(&archive),
+            &[PathBuf::from("a.txt")],
+            vec![],
+            &None,
+            Some(4095),
+            false,
+            false,
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("4095 is not open"), "{err}");
+    }
+
+    #[test]
+    fn info_as_toml() {
+        let dir = scratch_dir("info-toml");
+        let source = dir.join("source");
+        write_files(
+            &source,
+            &[
+                ("a.txt", b"hello"),
+                ("copy.txt", b"hello"),
+                ("sub/b.txt", b"world"),
+            ],
+        );
+        let archive = dir.join("archive.zprt");
+        let mut index = create_archive(&source, &archive, &[]).unwrap();
+        let info = archive_info(&mut open_archive(&archive), &vec![]).unwrap();
+        let table: toml::Table = toml::from_str(&toml::to_string(&info).unwrap()).unwrap();
+        assert_eq!(table["files"].as_integer(), Some(3));
+        assert_eq!(table["duplicate_files"].as_integer(), Some(1));
+        assert_eq!(table["size_original"].as_integer(), Some(15));
+        assert_eq!(table["encrypted"].as_bool(), Some(false));
+        assert_eq!(table["dedup"].as_str(), Some("content"));
+        assert_eq!(table["index_compressed"].as_bool(), Some(true));
+        assert_eq!(table["bytes_after_footer"].as_integer(), Some(0));
+        let magic = format!("{:X}", plain_magic_number());
+        assert_eq!(table["magic_number"].as_str(), Some(magic.as_str()));
+        assert!(!table.contains_key("age_format"));
+
+        // Hashes that no file uses are not duplicates (or negative ones).
+        for i in 0..4 {
+            index.hashes.insert(1_000_000 + i, [0; 32]);
+            index.sizes.insert(1_000_000 + i, 0);
+        }
+        replace_index(&archive, &index).unwrap();
+        let info = archive_info(&mut open_archive(&archive), &vec![]).unwrap();
+        assert_eq!(info.files, 3);
+        assert_eq!(info.duplicate_files, 1);
+    }
+}
@@ -1,36 +1,91 @@
-use crate::index::Index;
+use crate::index::{Index, SPECIAL_BLOCK_DEVICE, SPECIAL_CHAR_DEVICE, SPECIAL_FIFO};
 use crate::restore::stream_file;
 use crate::restore::stream_file_head;
-use crate::utils::GenericFile;
+use crate::utils::{GenericFile, owned_decoding_reader};
 use anyhow::Context;
 use anyhow::Result;
+use anyhow::anyhow;
 use bimap::BiMap;
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
+    FUSE_ROOT_ID, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, Request,
 };
 use indexmap::IndexMap;
-use libc::ENOENT;
+use libc::{EIO, ENOENT};
+use log::debug;
 use nix::unistd::Uid;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
+use std::hash::Hash;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::time::{Duration, UNIX_EPOCH};
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
-const HEADBYTES: u32 = 50000;
 
 struct ZipuratFS<'a> {
     index: &'a Index,
-    archive: &'a mut GenericFile,
+    archive: Rc<RefCell<&'a mut GenericFile>>,
     ids: &'a Vec<Box<dyn age::Identity>>,
     ino_table: BiMap<u64, PathBuf>,
     read_cache: FuseCache,
-    lookup_cache: HashMap<(u64, String), FileAttr>,
-    listing_cache: HashMap<u64, Vec<(u64, FileType, String)>>,
-    attribute_cache: HashMap<u64, FileAttr>,
+    lookup_cache: MetaCache<(u64, String), FileAttr>,
+    listing_cache: MetaCache<u64, Vec<(u64, FileType, String)>>,
+    attribute_cache: MetaCache<u64, FileAttr>,
     head_cache: HashMap<u64, Vec<u8>>,
+    /// The targets of the symbolic links, which are looked up for every `getattr`.
+    symlinks: HashMap<&'a Path, &'a Path>,
+    /// The kind and device number of the special files by path.
+    specials: HashMap<&'a Path, (u64, u64)>,
     no_reads: bool,
+    file_mode: u16,
+    dir_mode: u16,
+    head_bytes: u64,
+    max_read_file_size: u64,
+    readahead_bytes: u64,
+    /// The decoders of large files per open file handle, so sequential reads continue
+    /// where the last one stopped.
+    decoders: HashMap<u64, Decoding<'a>>,
+    /// How often a file had to be decoded from its start.
+    decoders_started: u64,
+    next_fh: u64,
+}
+
+/// How an archive is mounted. The defaults are those of `mount`.
+pub struct MountSettings {
+    pub auto_unmount: bool,
+    /// The number and the total size of the small files kept in memory.
+    pub max_files: usize,
+    pub max_size: usize,
+    /// The entries of each of the lookup, attribute and listing caches.
+    pub max_meta_entries: usize,
+    pub no_reads: bool,
+    /// Permission bits of files and directories without a stored mode.
+    pub file_mode: u16,
+    pub dir_mode: u16,
+    pub head_bytes: u64,
+    pub max_read_file_size: u64,
+    pub readahead_bytes: u64,
+}
+
+impl Default for MountSettings {
+    fn default() -> Self {
+        Self {
+            auto_unmount: false,
+            max_files: 30,
+            max_size: 50_000_000,
+            max_meta_entries: 10_000,
+            no_reads: false,
+            file_mode: 0o644,
+            dir_mode: 0o755,
+            head_bytes: 50_000,
+            max_read_file_size: 1_000_000_000,
+            readahead_bytes: 16_000_000,
+        }
+    }
 }
 
 impl<'a> ZipuratFS<'a> {
@@ -38,30 +93,21 @@ impl<'a> ZipuratFS<'a> {
         index: &'a Index,
         archive: &'a mut GenericFile,
         ids: &'a Vec<Box<dyn age::Identity>>,
-        max_files: usize,
-        max_size: usize,
-        no_reads: bool,
+        settings: &MountSettings,
     ) -> Result<Self> {
         let mut ino_table = BiMap::new();
-        ino_table.insert(1, Path::new("").to_path_buf());
+        ino_table.insert(FUSE_ROOT_ID, Path::new("").to_path_buf());
         let mut ino: u64 = 2;
-        for filepath in index.mapping.keys() {
-            ino_table.insert(ino, filepath.clone());
+        let paths = index
+            .mapping
+            .keys()
+            .chain(&index.empty_dirs)
+            .chain(index.special_files.iter().map(|(p, _)| p))
+            .chain(index.symlinks.iter().map(|(p, _)| p));
+        for path in paths {
+            ino_table.insert(ino, path.clone());
             ino += 1;
-            let mut parent_path = filepath.clone();
-
-            while let Some(parent) = parent_path.parent() {
-                parent_path = parent.to_path_buf();
-                if !ino_table.contains_right(&parent_path) {
-                    ino_table.insert(ino, parent_path.clone());
-                    ino += 1;
-                }
-            }
-        }
-        for emptydirpath in &index.empty_dirs {
-            ino_table.insert(ino, emptydirpath.clone());
-            ino += 1;
-            let mut parent_path = emptydirpath.clone();
+            let mut parent_path = path.clone();
 
             while let Some(parent) = parent_path.parent() {
                 parent_path = parent.to_path_buf();
@@ -73,15 +119,33 @@ impl<'a> ZipuratFS<'a> {
         }
         Ok(Self {
             index,
-            archive,
+            archive: Rc::new(RefCell::new(archive)),
             ino_table,
             ids,
-            read_cache: FuseCache::new(max_size, max_files),
-            lookup_cache: HashMap::new(),
-            listing_cache: HashMap::new(),
-            attribute_cache: HashMap::new(),
+            read_cache: FuseCache::new(settings.max_size, settings.max_files),
+            lookup_cache: MetaCache::new(settings.max_meta_entries),
+            listing_cache: MetaCache::new(settings.max_meta_entries),
+            attribute_cache: MetaCache::new(settings.max_meta_entries),
             head_cache: HashMap::new(),
-            no_reads,
+            symlinks: index
+                .symlinks
+                .iter()
+                .map(|(p, target)| (p.as_path(), target.as_path()))
+                .collect(),
+            specials: index
+                .special_files
+                .iter()
+                .map(|(p, special)| (p.as_path(), *special))
+                .collect(),
+            no_reads: settings.no_reads,
+            file_mode: settings.file_mode,
+            dir_mode: settings.dir_mode,
+            head_bytes: settings.head_bytes,
+            max_read_file_size: settings.max_read_file_size,
+            readahead_bytes: settings.readahead_bytes,
+            decoders: HashMap::new(),
+            decoders_started: 0,
+            next_fh: 1,
         })
     }
     fn get_size_by_ino(&self, ino: u64) -> Result<u64> {
@@ -108,7 +172,60 @@ impl<'a> ZipuratFS<'a> {
             ctime: UNIX_EPOCH,
             crtime: UNIX_EPOCH,
             kind: FileType::RegularFile,
-            perm: 0o644,
+            perm: self.stored_mode(path).unwrap_or(self.file_mode),
+            nlink: 1,
+            uid: Uid::current().into(),
+            gid: 20,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        })
+    }
+
+    /// The permission bits stored in the archive, if any.
+    fn stored_mode(&self, path: &Path) -> Option<u16> {
+        self.index.modes.get(path).map(|m| *m as u16)
+    }
+
+    /// FIFOs, sockets and device nodes have no content, only their kind and device number.
+    fn get_special_attr(&self, path: &Path, kind: u64, rdev: u64) -> Result<FileAttr> {
+        Ok(FileAttr {
+            ino: *self
+                .ino_table
+                .get_by_right(path)
+                .context("innode not found")?,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: special_type(kind),
+            perm: self.file_mode,
+            nlink: 1,
+            uid: Uid::current().into(),
+            gid: 20,
+            rdev: encode_rdev(rdev),
+            flags: 0,
+            blksize: 512,
+        })
+    }
+
+    /// Links report the length of their target as their size, like `lstat` does.
+    fn get_symlink_attr(&self, path: &Path, target: &Path) -> Result<FileAttr> {
+        Ok(FileAttr {
+            ino: *self
+                .ino_table
+                .get_by_right(path)
+                .context("innode not found")?,
+            size: target.as_os_str().len() as u64,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Symlink,
+            perm: 0o777,
             nlink: 1,
             uid: Uid::current().into(),
             gid: 20,
@@ -119,12 +236,13 @@ impl<'a> ZipuratFS<'a> {
     }
 
     fn get_dir_attr(&self, path: &Path) -> Result<FileAttr> {
-        let direct_children = self.index.get_direct_children(path)?;
-        let num_links = if path.parent().is_some() {
-            direct_children.len() + 2
-        } else {
-            direct_children.len() + 1
-        };
+        // `.`, the entry in the parent (the root's `..` is itself) and every `..` below.
+        let subdirs = self
+            .children(path)?
+            .iter()
+            .filter(|c| self.file_type(c) == FileType::Directory)
+            .count();
+        let num_links = subdirs + 2;
         Ok(FileAttr {
             ino: *self
                 .ino_table
@@ -137,7 +255,7 @@ impl<'a> ZipuratFS<'a> {
             ctime: UNIX_EPOCH,
             crtime: UNIX_EPOCH,
             kind: FileType::Directory,
-            perm: 0o755,
+            perm: self.stored_mode(path).unwrap_or(self.dir_mode),
             nlink: num_links as u32,
             uid: Uid::current().into(),
             gid: 20,
@@ -149,13 +267,157 @@ impl<'a> ZipuratFS<'a> {
     fn get_general_attr(&self, path: &Path) -> Result<FileAttr> {
         if self.index.is_file(path) {
             self.get_file_attr(path)
+        } else if let Some((kind, rdev)) = self.special(path) {
+            self.get_special_attr(path, kind, rdev)
+        } else if let Some(target) = self.symlinks.get(path) {
+            self.get_symlink_attr(path, target)
         } else {
             self.get_dir_attr(path)
         }
     }
+    fn special(&self, path: &Path) -> Option<(u64, u64)> {
+        self.specials.get(path).copied()
+    }
+    fn file_type(&self, path: &Path) -> FileType {
+        if self.index.is_file(path) {
+            FileType::RegularFile
+        } else if let Some((kind, _)) = self.special(path) {
+            special_type(kind)
+        } else if self.symlinks.contains_key(path) {
+            FileType::Symlink
+        } else {
+            FileType::Directory
+        }
+    }
+    /// Files, directories, special files and symbolic links directly in `path`.
+    /// Directories that only hold special files or links are not directories to the index.
+    fn children(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut children = if self.index.is_dir(path) {
+            self.index
+                .get_direct_children(path)?
+                .into_iter()
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        let others = self
+            .index
+            .special_files
+            .iter()
+            .map(|(p, _)| p)
+            .chain(self.index.symlinks.iter().map(|(p, _)| p));
+        for other in others {
+            let Ok(relative) = other.strip_prefix(path) else {
+                continue;
+            };
+            if let Some(first) = relative.components().next() {
+                children.push(path.join(first));
+            }
+        }
+        children.sort();
+        children.dedup();
+        Ok(children)
+    }
+    /// A range within the head of a file. The head is decoded once and then kept.
+    fn read_head(&mut self, ino: u64, path: &Path, offset: u64, size: u64) -> Result<&[u8]> {
+        if !self.head_cache.contains_key(&ino) {
+            debug!("loading head {:?}", path);
+            let mut head = vec![];
+            stream_file_head(
+                &mut self.archive.borrow_mut(),
+                path,
+                &mut head,
+                self.index,
+                self.head_bytes,
+                self.ids,
+            )?;
+            self.head_cache.insert(ino, head);
+        }
+        let head = &self.head_cache[&ino];
+        let start = (offset as usize).min(head.len());
+        let end = (start + size as usize).min(head.len());
+        Ok(&head[start..end])
+    }
+    /// Decodes `size` bytes of a file from `offset` with the decoder of the file handle.
+    /// The decoder is only started again from the beginning if the read goes back before
+    /// its window.
+    fn read_range(
+        &mut self,
+        fh: u64,
+        ino: u64,
+        path: &Path,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>> {
+        let reusable = self
+            .decoders
+            .get(&fh)
+            .is_some_and(|d| d.ino == ino && d.window_start() <= offset);
+        if !reusable {
+            self.decoders_started += 1;
+            debug!(
+                "decoding {:?} from the start ({} decoders started)",
+                path, self.decoders_started
+            );
+            let (start, len, _) = self.index.index_length_and_hash(path)?;
+            let cursor = ArchiveCursor {
+                archive: self.archive.clone(),
+                position: start,
+            };
+            let reader = owned_decoding_reader(cursor, len, self.ids)?;
+            self.decoders.insert(
+                fh,
+                Decoding {
+                    ino,
+                    reader,
+                    position: 0,
+                    window: vec![],
+                    next: 0,
+                },
+            );
+        }
+        let decoding = self.decoders.get_mut(&fh).context("Decoder not found")?;
+        let result = decoding.read(offset, size, self.readahead_bytes);
+        if result.is_err() {
+            self.decoders.remove(&fh);
+        }
+        result
+    }
+    /// The entries of a directory as `readdir` lists them, starting with `.` and `..`.
+    fn dir_entries(&self, ino: u64) -> Result<Vec<(u64, FileType, String)>> {
+        let path = self.ino_table.get_by_left(&ino).context("Ino not found")?;
+        if self.file_type(path) != FileType::Directory {
+            return Err(anyhow!("Not a directory"));
+        }
+        let parent_ino = match self.get_parent_inode(path) {
+            Some(i) => i,
+            _ => ino,
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (parent_ino, FileType::Directory, "..".to_string()),
+        ];
+        for c in &self.children(path)? {
+            if let Some(i) = self.ino_table.get_by_right(c) {
+                let ft = self.file_type(c);
+                let name = c
+                    .strip_prefix(path)
+                    .expect("File prefix error")
+                    .to_str()
+                    .expect("must be utf8");
+                entries.push((*i, ft, name.to_string()));
+            }
+        }
+        Ok(entries)
+    }
+    /// The size of all files in blocks of 512 bytes and the number of inodes, for `statfs`.
+    fn usage(&self) -> (u64, u64) {
+        let size: u64 = self.index.sizes.values().sum();
+        (size.div_ceil(512), self.ino_table.len() as u64)
+    }
     fn get_parent_inode(&self, path: &Path) -> Option<u64> {
         if path == Path::new("") {
-            Some(1)
+            Some(FUSE_ROOT_ID)
         } else {
             let p = path.parent()?;
             self.ino_table.get_by_right(p).copied()
@@ -204,7 +466,7 @@ impl<'a> Filesystem for ZipuratFS<'a> {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -226,40 +488,42 @@ impl<'a> Filesystem for ZipuratFS<'a> {
         let mut buffer: Vec<u8> = vec![];
         let file_size = self.get_size_by_ino(ino).expect("Could not get file size");
         let read_size = std::cmp::min(size, file_size.saturating_sub(offset as u64) as u32);
-        if offset == 0 && size < HEADBYTES {
-            if let Some(cached) = self.head_cache.get(&ino) {
-                buffer = cached.clone();
-            } else {
-                println!("loading head {:?}", path);
-                if stream_file_head(
-                    self.archive,
-                    path,
-                    &mut buffer,
-                    self.index,
-                    HEADBYTES as u64,
-                    self.ids,
-                )
-                .is_err()
-                {
-                    reply.error(ENOENT);
-                    return;
-                }
-                self.head_cache.insert(ino, buffer.clone());
+        // Reads that lie completely within the head of the file only need the head.
+        if offset as u64 + size as u64 <= self.head_bytes {
+            let path = path.clone();
+            match self.read_head(ino, &path, offset as u64, read_size as u64) {
+                Ok(data) => reply.data(data),
+                Err(_) => reply.error(ENOENT),
             }
-
-            reply.data(&buffer.as_slice()[offset as usize..offset as usize + read_size as usize]);
             return;
         }
 
         if let Some(cached) = self.read_cache.get(path) {
             reply.data(&cached[offset as usize..offset as usize + read_size as usize]);
+        } else if file_size > self.max_read_file_size {
+            // Only a window of the file is kept, which does not need memory for the whole file.
+            // The decoder of the file handle continues from the end of the last read, so only
+            // reads that go backwards decode the file from the start again. Sequential reads
+            // decode a larger window, so the following reads are served from it.
+            let path = path.clone();
+            match self.read_range(fh, ino, &path, offset as u64, read_size as u64) {
+                Ok(data) => reply.data(&data),
+                Err(_) => reply.error(EIO),
+            }
         } else {
-            println!(
+            debug!(
                 "loading {:?} ({})",
                 path,
                 humansize::format_size(file_size, humansize::DECIMAL)
             );
-            if stream_file(self.archive, path, &mut buffer, self.index, self.ids).is_err() {
+            let result = stream_file(
+                &mut self.archive.borrow_mut(),
+                path,
+                &mut buffer,
+                self.index,
+                self.ids,
+            );
+            if result.is_err() {
                 reply.error(ENOENT);
                 return;
             }
@@ -268,6 +532,43 @@ impl<'a> Filesystem for ZipuratFS<'a> {
         }
     }
 
+    fn open(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        reply.opened(fh, 0);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.decoders.remove(&fh);
+        reply.ok();
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let target = self
+            .ino_table
+            .get_by_left(&ino)
+            .and_then(|path| self.symlinks.get(path.as_path()));
+        match target {
+            Some(target) => reply.data(target.as_os_str().as_bytes()),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    /// A read-only filesystem the size of the original files, without free space.
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let (blocks, files) = self.usage();
+        reply.statfs(blocks, 0, 0, files, 0, 512, 255, 512);
+    }
+
     fn readdir(
         &mut self,
         _req: &Request,
@@ -285,44 +586,10 @@ impl<'a> Filesystem for ZipuratFS<'a> {
             reply.ok();
             return;
         }
-        let Some(path) = self.ino_table.get_by_left(&ino) else {
-            reply.error(ENOENT);
-            return;
-        };
-        if !self.index.is_dir(path) {
-            reply.error(ENOENT);
-            return;
-        }
-
-        let parent_ino = match self.get_parent_inode(path) {
-            Some(i) => i,
-            _ => ino,
-        };
-        let mut entries = vec![
-            (ino, FileType::Directory, ".".to_string()),
-            (parent_ino, FileType::Directory, "..".to_string()),
-        ];
-        let Ok(children) = self.index.get_direct_children(path) else {
+        let Ok(entries) = self.dir_entries(ino) else {
             reply.error(ENOENT);
             return;
         };
-        let mut sorted: Vec<PathBuf> = children.iter().map(|p| p.to_owned()).collect();
-        sorted.sort();
-        for c in &sorted {
-            if let Some(i) = self.ino_table.get_by_right(c) {
-                let ft = if self.index.is_file(c) {
-                    FileType::RegularFile
-                } else {
-                    FileType::Directory
-                };
-                let name = c
-                    .strip_prefix(path)
-                    .expect("File prefix error")
-                    .to_str()
-                    .expect("must be utf8");
-                entries.push((*i, ft, name.to_string()));
-            }
-        }
         self.listing_cache.insert(ino, entries.clone());
 
         for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
@@ -334,23 +601,106 @@ impl<'a> Filesystem for ZipuratFS<'a> {
     }
 }
 
-#[allow(clippy::too_many_arguments)]
+/// A decoder of one file, how far into the file it has decoded and the window of decoded
+/// bytes that ends there.
+struct Decoding<'a> {
+    ino: u64,
+    reader: Box<dyn Read + 'a>,
+    position: u64,
+    window: Vec<u8>,
+    /// Where the next read starts if the file is read sequentially.
+    next: u64,
+}
+
+impl Decoding<'_> {
+    fn window_start(&self) -> u64 {
+        self.position - self.window.len() as u64
+    }
+
+    /// Returns up to `size` bytes from `offset`, which must not lie before the window.
+    /// What the window lacks is decoded by continuing from `position`, and a sequential
+    /// read decodes at least `ahead` bytes, so the window always ends where the decoder is.
+    fn read(&mut self, offset: u64, size: u64, ahead: u64) -> Result<Vec<u8>> {
+        let end = offset + size;
+        if end > self.position {
+            let sequential = offset == self.next && offset > 0;
+            if offset >= self.position {
+                let skip = offset - self.position;
+                self.position +=
+                    std::io::copy(&mut (&mut self.reader).take(skip), &mut std::io::sink())?;
+                self.window.clear();
+                if self.position < offset {
+                    return Ok(vec![]);
+                }
+            } else {
+                // Only what is still ahead of this read is kept.
+                let keep = (self.position - offset) as usize;
+                self.window.drain(..self.window.len() - keep);
+            }
+            let target = if sequential {
+                end.max(offset + ahead)
+            } else {
+                end
+            };
+            let decoded = (&mut self.reader)
+                .take(target - self.position)
+                .read_to_end(&mut self.window)?;
+            self.position += decoded as u64;
+        }
+        self.next = end;
+        let window_start = self.window_start();
+        let start = ((offset - window_start) as usize).min(self.window.len());
+        let stop = ((end - window_start) as usize).min(self.window.len());
+        Ok(self.window[start..stop].to_vec())
+    }
+}
+
+/// Reads the archive from its own position, so several decoders can share the archive.
+struct ArchiveCursor<'a> {
+    archive: Rc<RefCell<&'a mut GenericFile>>,
+    position: u64,
+}
+
+impl Read for ArchiveCursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut archive = self.archive.borrow_mut();
+        archive.seek(SeekFrom::Start(self.position))?;
+        let n = archive.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+fn special_type(kind: u64) -> FileType {
+    match kind {
+        SPECIAL_FIFO => FileType::NamedPipe,
+        SPECIAL_CHAR_DEVICE => FileType::CharDevice,
+        SPECIAL_BLOCK_DEVICE => FileType::BlockDevice,
+        _ => FileType::Socket,
+    }
+}
+
+/// The kernel only takes 32 bit device numbers from FUSE (12 bit major, 20 bit minor),
+/// so the stored 64 bit number is split and packed again the way `new_encode_dev` does it.
+fn encode_rdev(rdev: u64) -> u32 {
+    let major = libc::major(rdev as libc::dev_t) as u32;
+    let minor = libc::minor(rdev as libc::dev_t) as u32;
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
 pub fn mount(
     index: &Index,
     archive: &mut GenericFile,
     mountpoint: &str,
     ids: &Vec<Box<dyn age::Identity>>,
-    auto: bool,
-    max_files: usize,
-    max_size: usize,
-    no_reads: bool,
+    settings: &MountSettings,
 ) -> Result<()> {
     let mut options = vec![MountOption::RO, MountOption::FSName("zipurat".to_string())];
-    if auto {
+    if settings.auto_unmount {
         options.push(MountOption::AutoUnmount);
     }
     fuser::mount2(
-        ZipuratFS::new(index, archive, ids, max_files, max_size, no_reads)?,
+        ZipuratFS::new(index, archive, ids, settings)?,
         mountpoint,
         &options,
     )?;
@@ -391,3 +741,223 @@ impl FuseCache {
         self.content.insert(path.to_path_buf(), data.to_vec());
     }
 }
+
+/// A least recently used cache. Every access stamps the entry with a new generation,
+/// so the oldest generation in `order` is the entry to evict.
+struct MetaCache<K, V> {
+    max_entries: usize,
+    content: HashMap<K, (u64, V)>,
+    order: BTreeMap<u64, K>,
+    generation: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> MetaCache<K, V> {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            content: HashMap::new(),
+            order: BTreeMap::new(),
+            generation: 0,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let (generation, value) = self.content.get_mut(key)?;
+        self.generation += 1;
+        let key = self.order.remove(&*generation)?;
+        *generation = self.generation;
+        self.order.insert(self.generation, key);
+        Some(value)
+    }
+    fn insert(&mut self, key: K, value: V) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if let Some((generation, _)) = self.content.remove(&key) {
+            self.order.remove(&generation);
+        }
+        while self.content.len() >= self.max_entries {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.content.remove(&oldest);
+        }
+        self.generation += 1;
+        self.order.insert(self.generation, key.clone());
+        self.content.insert(key, (self.generation, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{create_archive, open_archive, scratch_dir, write_files};
+
+    #[test]
+    fn meta_cache_evicts_least_recently_used() {
+        let mut cache = MetaCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+        cache.insert(3, "c");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+        cache.insert(3, "d");
+        cache.insert(4, "e");
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&3), Some(&"d"));
+        assert_eq!(cache.content.len(), 2);
+        assert_eq!(cache.order.len(), 2);
+    }
+
+    #[test]
+    fn large_device_numbers() {
+        // NVMe partitions get minors above 255, which are split around the major.
+        let rdev = libc::makedev(259, 65536) as u64;
+        let encoded = encode_rdev(rdev);
+        assert_eq!((encoded >> 8) & 0xfff, 259);
+        assert_eq!((encoded & 0xff) | ((encoded >> 12) & 0xfff00), 65536);
+        assert_eq!(encode_rdev(libc::makedev(1, 3) as u64), (1 << 8) | 3);
+    }
+
+    #[test]
+    fn root_inode() {
+        let dir = scratch_dir("fuse-root");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("sub/b.txt", b"world!")]);
+        std::fs::create_dir_all(source.join("empty")).unwrap();
+        let archive = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive, &[]).unwrap();
+        let mut archive = open_archive(&archive);
+        let ids = vec![];
+        let fs = ZipuratFS::new(&index, &mut archive, &ids, &MountSettings::default()).unwrap();
+
+        // getattr
+        assert!(!index.is_file(Path::new("")));
+        let root = fs.ino_table.get_by_left(&FUSE_ROOT_ID).unwrap();
+        let attr = fs.get_general_attr(root).unwrap();
+        assert_eq!(attr.ino, FUSE_ROOT_ID);
+        assert_eq!(attr.kind, FileType::Directory);
+        // `.`, `..` and the `..` of `sub` and `empty`.
+        assert_eq!(attr.nlink, 4);
+
+        // readdir
+        let entries = fs.dir_entries(FUSE_ROOT_ID).unwrap();
+        let names = entries.iter().map(|e| e.2.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![".", "..", "a.txt", "empty", "sub"]);
+        assert_eq!(entries[0].0, FUSE_ROOT_ID);
+        assert_eq!(entries[1].0, FUSE_ROOT_ID);
+        let kinds = entries.iter().map(|e| e.1).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                FileType::Directory,
+                FileType::Directory,
+                FileType::RegularFile,
+                FileType::Directory,
+                FileType::Directory
+            ]
+        );
+
+        // statfs: 11 bytes in one block, and the root, two files and two directories.
+        assert_eq!(fs.usage(), (1, 5));
+    }
+
+    /// A filesystem for the readahead tests, with a window of 10000 bytes.
+    fn readahead_fs<'a>(
+        index: &'a Index,
+        archive: &'a mut GenericFile,
+        ids: &'a Vec<Box<dyn age::Identity>>,
+    ) -> ZipuratFS<'a> {
+        let settings = MountSettings {
+            head_bytes: 0,
+            max_read_file_size: 0,
+            readahead_bytes: 10_000,
+            ..Default::default()
+        };
+        ZipuratFS::new(index, archive, ids, &settings).unwrap()
+    }
+
+    #[test]
+    fn sequential_reads_continue_the_decoder() {
+        let dir = scratch_dir("fuse-readahead");
+        let source = dir.join("source");
+        let content = (0..50_000).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        write_files(&source, &[("data.bin", &content)]);
+        let archive_path = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive_path, &[]).unwrap();
+        let mut archive = open_archive(&archive_path);
+        let ids = vec![];
+        let mut fs = readahead_fs(&index, &mut archive, &ids);
+        let path = PathBuf::from("data.bin");
+        let ino = *fs.ino_table.get_by_right(&path).unwrap();
+        // 4096 does not divide the window, so reads keep running past its end.
+        let mut offset = 0;
+        while offset < content.len() {
+            let data = fs.read_range(1, ino, &path, offset as u64, 4096).unwrap();
+            let end = (offset + 4096).min(content.len());
+            assert_eq!(data, &content[offset..end]);
+            offset = end;
+        }
+        assert_eq!(fs.decoders_started, 1);
+        assert!(fs.decoders[&1].window.len() <= 10_000 + 4096);
+        // Going back before the window starts over.
+        let data = fs.read_range(1, ino, &path, 0, 10).unwrap();
+        assert_eq!(data, &content[..10]);
+        assert_eq!(fs.decoders_started, 2);
+    }
+
+    #[test]
+    fn readahead_per_file_handle() {
+        let dir = scratch_dir("fuse-readahead-handles");
+        let source = dir.join("source");
+        let first = (0..30_000).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        let second = (0..30_000).map(|i| (i % 241) as u8).collect::<Vec<_>>();
+        write_files(&source, &[("a.bin", &first), ("b.bin", &second)]);
+        let archive_path = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive_path, &[]).unwrap();
+        let mut archive = open_archive(&archive_path);
+        let ids = vec![];
+        let mut fs = readahead_fs(&index, &mut archive, &ids);
+        let (a, b) = (PathBuf::from("a.bin"), PathBuf::from("b.bin"));
+        let ino_a = *fs.ino_table.get_by_right(&a).unwrap();
+        let ino_b = *fs.ino_table.get_by_right(&b).unwrap();
+        // Both files are read at once, each through its own handle.
+        for offset in (0..30_000).step_by(3000) {
+            let data = fs.read_range(1, ino_a, &a, offset as u64, 3000).unwrap();
+            assert_eq!(data, &first[offset..offset + 3000]);
+            let data = fs.read_range(2, ino_b, &b, offset as u64, 3000).unwrap();
+            assert_eq!(data, &second[offset..offset + 3000]);
+        }
+        assert_eq!(fs.decoders_started, 2);
+    }
+
+    #[test]
+    fn sequential_reads_within_the_head() {
+        let dir = scratch_dir("fuse-head");
+        let source = dir.join("source");
+        let content = (0..100).collect::<Vec<u8>>();
+        write_files(&source, &[("data.bin", &content)]);
+        let archive_path = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive_path, &[]).unwrap();
+        let mut archive = open_archive(&archive_path);
+        let ids = vec![];
+        let settings = MountSettings {
+            head_bytes: 50,
+            ..Default::default()
+        };
+        let mut fs = ZipuratFS::new(&index, &mut archive, &ids, &settings).unwrap();
+        let path = PathBuf::from("data.bin");
+        let ino = *fs.ino_table.get_by_right(&path).unwrap();
+        for offset in (0..50).step_by(10) {
+            let data = fs.read_head(ino, &path, offset, 10).unwrap();
+            assert_eq!(data, &content[offset as usize..offset as usize + 10]);
+        }
+        assert_eq!(fs.head_cache.len(), 1);
+        // Later reads are served from the cached head instead of the archive.
+        fs.head_cache.get_mut(&ino).unwrap()[45] = 0xff;
+        let data = fs.read_head(ino, &path, 45, 5).unwrap();
+        assert_eq!(data, &[0xff, 46, 47, 48, 49]);
+    }
+}
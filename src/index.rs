@@ -1,15 +1,192 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io::{Seek, SeekFrom},
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
     path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
 };
 
 use anyhow::anyhow;
 use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use log::warn;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
 
-use crate::serializer::SimpleBinRepr;
+use crate::serializer::{SimpleBinRepr, read_optional};
 
-use crate::utils::{GenericFile, decrypt_and_decompress};
+use crate::utils::{GenericFile, decrypt_index};
+
+pub const MAGIC_NUMBER: u64 = 12219678139600706333;
+
+static MAGIC_OVERRIDE: OnceLock<u64> = OnceLock::new();
+
+/// Uses a different magic number for all archives, so forks can keep their
+/// archives apart from regular ones. Has to be called before any archive is touched.
+pub fn set_magic_number(magic: u64) {
+    let _ = MAGIC_OVERRIDE.set(magic);
+}
+
+/// The magic number archives are written with and expected to have.
+pub fn magic_number() -> u64 {
+    MAGIC_OVERRIDE.get().copied().unwrap_or(MAGIC_NUMBER)
+}
+
+/// The magic number of archives that are only compressed, not encrypted.
+pub fn plain_magic_number() -> u64 {
+    !magic_number()
+}
+
+/// The format revision written into the footer. Archives from before it was recorded have
+/// a shorter footer and are revision 1.
+pub const FORMAT_REVISION: u64 = 2;
+
+/// The upper half of the revision word in the footer. Index lengths never come close to it,
+/// so the word before the trailing magic number tells the two kinds of footers apart.
+const REVISION_TAG: u64 = 0x7a70_7274 << 32;
+const REVISION_MASK: u64 = 0xffff_ffff << 32;
+
+/// The revision word of the footer, `REVISION_TAG` combined with the revision.
+pub fn revision_word() -> u64 {
+    REVISION_TAG | FORMAT_REVISION
+}
+
+/// The format version an archive with the given magic number and footer revision follows.
+pub fn format_version(magic_number: u64, revision: u64) -> Option<&'static str> {
+    if magic_number != MAGIC_NUMBER && magic_number != !MAGIC_NUMBER {
+        return None;
+    }
+    match revision {
+        1 => Some("1.0"),
+        2 => Some("1.1"),
+        _ => None,
+    }
+}
+
+/// The footer behind the index: the index length, the format revision (since revision 2)
+/// and the trailing magic number.
+pub struct Footer {
+    pub index_len: u64,
+    pub revision: u64,
+    /// 24 bytes, or 16 for revision 1.
+    pub len: u64,
+}
+
+/// Reads the footer at `pos` (see `locate_footer`).
+pub fn read_footer(archive: &mut GenericFile, pos: u64) -> Result<Footer> {
+    archive.seek(SeekFrom::Start(pos))?;
+    let words = <[u8; 16]>::read_bin(archive)?;
+    Ok(parse_footer(&words))
+}
+
+/// The level a rewritten index of this archive should use: 0 if the index is stored
+/// without compression (`--index-level 0`), otherwise 22 (the level itself is not recorded).
+pub fn rewritten_index_level(
+    archive: &mut GenericFile,
+    keys: &Vec<Box<dyn age::Identity>>,
+) -> Result<i32> {
+    let footer = locate_footer(archive)?;
+    let index_len = read_footer(archive, footer)?.index_len;
+    archive.seek(SeekFrom::Start(
+        footer
+            .checked_sub(index_len)
+            .context("Invalid index length")?,
+    ))?;
+    let compressed = decrypt_index(archive, &mut std::io::sink(), index_len, 0, keys)?;
+    Ok(if compressed { 22 } else { 0 })
+}
+
+/// Parses the first two words of a footer.
+fn parse_footer(words: &[u8; 16]) -> Footer {
+    let index_len = u64::from_le_bytes(words[..8].try_into().expect("8 bytes"));
+    let second = u64::from_le_bytes(words[8..].try_into().expect("8 bytes"));
+    if is_revision_word(second) {
+        Footer {
+            index_len,
+            revision: second & !REVISION_MASK,
+            len: 24,
+        }
+    } else {
+        Footer {
+            index_len,
+            revision: 1,
+            len: 16,
+        }
+    }
+}
+
+fn is_revision_word(word: u64) -> bool {
+    word & REVISION_MASK == REVISION_TAG
+}
+
+/// Reads the leading magic number, which is either the encrypted or the plain one.
+pub fn archive_magic(archive: &mut GenericFile) -> Result<u64> {
+    archive.seek(SeekFrom::Start(0))?;
+    let magic = u64::read_bin(archive).context("Archive is too small or truncated")?;
+    if magic != magic_number() && magic != plain_magic_number() {
+        return Err(anyhow!("Unrecognized archive format"));
+    }
+    Ok(magic)
+}
+
+/// Whether the chunks of an archive are encrypted. Unencrypted archives are read without identities.
+pub fn is_encrypted(archive: &mut GenericFile) -> Result<bool> {
+    Ok(archive_magic(archive)? == magic_number())
+}
+
+pub const SPECIAL_FIFO: u64 = 1;
+pub const SPECIAL_CHAR_DEVICE: u64 = 2;
+pub const SPECIAL_BLOCK_DEVICE: u64 = 3;
+pub const SPECIAL_SOCKET: u64 = 4;
+
+/// How the writer decided that two files are duplicates: by comparing their content after
+/// the hashes matched, or by the hash alone. With `DEDUP_NONE`, every file was stored.
+pub const DEDUP_CONTENT: u64 = 1;
+pub const DEDUP_HASH: u64 = 2;
+pub const DEDUP_NONE: u64 = 3;
+
+/// Flags chunk offsets in a delta archive that refer to a chunk of its base archive.
+/// Real offsets never come close to it.
+pub const BASE_CHUNK: u64 = 1 << 63;
+
+/// In a union of archives, the layer a chunk belongs to is stored in the offset bits
+/// from here on (below `BASE_CHUNK`).
+pub const LAYER_SHIFT: u32 = 48;
+
+/// The leading magic number plus the footer (index length and magic number).
+const MIN_ARCHIVE_SIZE: u64 = 24;
+
+/// How much of the index chunk is searched for the end of its age header.
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+
+/// How often loading the index of a remote archive is attempted before giving up.
+const INDEX_READ_ATTEMPTS: u32 = 3;
+
+/// How far from the end we look for the footer if there is trailing data.
+const TRAILER_SEARCH: u64 = 4096;
+
+/// Block size used when searching the whole archive backwards for the latest footer.
+const SEARCH_BLOCK: u64 = 4096;
+
+/// What an entry of `Index::entries` is. Files carry their original size and their chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File {
+        size: u64,
+        offset: u64,
+        len: u64,
+    },
+    Dir,
+    /// A special file with its kind (`SPECIAL_*`) and device number.
+    Special {
+        kind: u64,
+        rdev: u64,
+    },
+    /// A symbolic link with its target.
+    Symlink {
+        target: PathBuf,
+    },
+}
 
 #[derive(Clone, Debug)]
 pub struct Index {
@@ -18,18 +195,141 @@ pub struct Index {
     pub sizes: HashMap<u64, u64>,
     pub empty_dirs: Vec<PathBuf>,
     pub magic_number: u64,
+    /// Paths of special files with their kind (`SPECIAL_*`) and device number.
+    pub special_files: Vec<(PathBuf, (u64, u64))>,
+    /// `DEDUP_CONTENT`, `DEDUP_HASH` or `DEDUP_NONE`. Older archives always compared the content.
+    pub dedup_mode: u64,
+    /// Informational key-value pairs (like `source_root` and `hostname`) that restoring ignores.
+    pub metadata: Vec<(String, String)>,
+    /// Permission bits of files and directories. Older archives have none.
+    pub modes: HashMap<PathBuf, u64>,
+    /// Symbolic links with their targets, which are stored as they were read.
+    pub symlinks: Vec<(PathBuf, PathBuf)>,
+    /// Files that were hard links to another file, with the path of that file. Both are
+    /// also in `mapping` with the same chunk, so readers that ignore this see two copies.
+    pub hardlinks: Vec<(PathBuf, PathBuf)>,
 }
 
 impl Index {
     pub fn parse(archive: &mut GenericFile, keys: &Vec<Box<dyn age::Identity>>) -> Result<Self> {
-        archive.seek(SeekFrom::End(-16))?;
-        let index_offset = u64::read_bin(archive)?;
-        archive.seek(SeekFrom::Current(-(index_offset as i64) - 8))?;
-        let mut content = vec![];
-        decrypt_and_decompress(archive, &mut content, index_offset, keys)?;
-
-        let deser = Self::read_bin(&mut content.as_slice())?;
-        Ok(deser)
+        with_retries(archive, |archive| {
+            let (content, magic, index_start) = read_index_content(archive, keys)?;
+            let deser = Self::read_bin(&mut content.as_slice())?;
+            if deser.magic_number != magic {
+                return Err(anyhow!("Unrecognized archive format (index magic number)"));
+            }
+            deser.check_chunks(index_start)?;
+            deser.check_paths()?;
+            Ok(deser)
+        })
+    }
+    /// All chunks have to lie between the leading magic number and the index, and have a
+    /// hash and size. Chunks of a base archive can not be checked against the layout here.
+    fn check_chunks(&self, index_start: u64) -> Result<()> {
+        for (path, (i, len)) in &self.mapping {
+            if !self.hashes.contains_key(i) || !self.sizes.contains_key(i) {
+                return Err(anyhow!(
+                    "Corrupt index: no hash or size for {}",
+                    path.to_string_lossy()
+                ));
+            }
+            if i & BASE_CHUNK != 0 {
+                continue;
+            }
+            // Even an empty file is stored as a non-empty chunk.
+            if *i < 8 || *len == 0 || i.checked_add(*len).is_none_or(|end| end > index_start) {
+                return Err(anyhow!(
+                    "Corrupt archive: chunk of {} out of range",
+                    path.to_string_lossy()
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// A path is either a file or a directory: no stored file may contain other entries,
+    /// and empty directories are neither files nor contain anything.
+    fn check_paths(&self) -> Result<()> {
+        // The root (inode 1 when mounted) is always a directory.
+        if self.is_file(Path::new("")) {
+            return Err(anyhow!("Corrupt index: the root is stored as a file"));
+        }
+        let empty_dirs = self
+            .empty_dirs
+            .iter()
+            .map(|d| d.as_path())
+            .collect::<HashSet<_>>();
+        let symlinks = self
+            .symlinks
+            .iter()
+            .map(|(p, _)| p.as_path())
+            .collect::<HashSet<_>>();
+        if let Some(dir) = self.empty_dirs.iter().find(|d| self.is_file(d)) {
+            return Err(anyhow!(
+                "Corrupt index: {} is both a file and an empty directory",
+                dir.to_string_lossy()
+            ));
+        }
+        if let Some((link, _)) = self
+            .symlinks
+            .iter()
+            .find(|(p, _)| self.is_file(p) || empty_dirs.contains(p.as_path()))
+        {
+            return Err(anyhow!(
+                "Corrupt index: {} is both a symbolic link and a file or directory",
+                link.to_string_lossy()
+            ));
+        }
+        let entries = self
+            .mapping
+            .keys()
+            .chain(&self.empty_dirs)
+            .chain(self.special_files.iter().map(|(p, _)| p))
+            .chain(self.symlinks.iter().map(|(p, _)| p));
+        for entry in entries {
+            if let Some(file) = entry.ancestors().skip(1).find(|a| self.is_file(a)) {
+                return Err(anyhow!(
+                    "Corrupt index: {} is a file but contains {}",
+                    file.to_string_lossy(),
+                    entry.to_string_lossy()
+                ));
+            }
+            // Restoring would otherwise write through the link.
+            let link = entry.ancestors().skip(1).find(|a| symlinks.contains(a));
+            if let Some(link) = link {
+                return Err(anyhow!(
+                    "Corrupt index: {} is a symbolic link but contains {}",
+                    link.to_string_lossy(),
+                    entry.to_string_lossy()
+                ));
+            }
+            let empty_dir = entry.ancestors().skip(1).find(|a| empty_dirs.contains(a));
+            if let Some(dir) = empty_dir {
+                return Err(anyhow!(
+                    "Corrupt index: {} is an empty directory but contains {}",
+                    dir.to_string_lossy(),
+                    entry.to_string_lossy()
+                ));
+            }
+        }
+        if let Some((link, _)) = self
+            .hardlinks
+            .iter()
+            .find(|(p, t)| !self.is_hard_link(p, t))
+        {
+            return Err(anyhow!(
+                "Corrupt index: {} is not a hard link to a file with the same content",
+                link.to_string_lossy()
+            ));
+        }
+        Ok(())
+    }
+    /// Whether both paths are files stored in the same chunk, as hard links have to be.
+    fn is_hard_link(&self, link: &Path, target: &Path) -> bool {
+        link != target
+            && self
+                .mapping
+                .get(link)
+                .is_some_and(|m| self.mapping.get(target) == Some(m))
     }
     pub fn index(&self, path: &Path) -> Option<(u64, u64)> {
         self.mapping.get(path).copied()
@@ -43,15 +343,41 @@ impl Index {
         Ok((index.0, index.1, *hash))
     }
 
+    /// Clears the setuid, setgid and sticky bits, which an archive from elsewhere should
+    /// not be able to grant.
+    pub fn strip_special_bits(&mut self) {
+        for mode in self.modes.values_mut() {
+            *mode &= 0o777;
+        }
+    }
+
     pub fn is_file(&self, path: &Path) -> bool {
         self.mapping.contains_key(path)
     }
-    #[allow(unused)]
+    pub fn symlink_target(&self, path: &Path) -> Option<&Path> {
+        self.symlinks
+            .iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, target)| target.as_path())
+    }
     pub fn is_dir(&self, path: &Path) -> bool {
         if self.is_file(path) {
             return false;
         }
-        self.mapping.keys().any(|k| k.starts_with(path))
+        if path == Path::new("") {
+            return true;
+        }
+        // A directory that only holds special files or links is a directory as well.
+        let others = self
+            .special_files
+            .iter()
+            .map(|(p, _)| p)
+            .chain(self.symlinks.iter().map(|(p, _)| p));
+        self.mapping
+            .keys()
+            .chain(&self.empty_dirs)
+            .any(|k| k.starts_with(path))
+            || others.any(|k| k != path && k.starts_with(path))
     }
     pub fn du(&self, path: &Path) -> Result<u64> {
         if self.is_file(path) {
@@ -61,13 +387,11 @@ impl Index {
                 .context("Size not in index")
                 .copied()
         } else {
-            let children = self
-                .mapping
-                .keys()
-                .filter(|k| k.starts_with(path))
-                .map(|f| self.du(f))
-                .collect::<Result<Vec<_>>>()?;
-            Ok(children.iter().sum())
+            self.mapping
+                .iter()
+                .filter(|(k, _)| k.starts_with(path))
+                .map(|(_, (i, _))| self.sizes.get(i).context("Size not in index"))
+                .sum()
         }
     }
     pub fn subindex(&self, subpath: &Path) -> Result<Self> {
@@ -78,6 +402,12 @@ impl Index {
                 sizes: HashMap::new(),
                 empty_dirs: vec![],
                 magic_number: self.magic_number,
+                special_files: vec![],
+                dedup_mode: self.dedup_mode,
+                metadata: self.metadata.clone(),
+                modes: HashMap::new(),
+                symlinks: vec![],
+                hardlinks: vec![],
             });
         }
         if !self.is_dir(subpath) {
@@ -101,6 +431,32 @@ impl Index {
             .map(|p| (p.strip_prefix(subpath)))
             .map(|r| r.map(|e| e.to_path_buf()))
             .collect::<std::result::Result<Vec<_>, _>>()?;
+        let new_specials = self
+            .special_files
+            .iter()
+            .filter(|(p, _)| p.starts_with(subpath))
+            .map(|(p, s)| p.strip_prefix(subpath).map(|p| (p.to_path_buf(), *s)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let new_symlinks = self
+            .symlinks
+            .iter()
+            .filter(|(p, _)| p.starts_with(subpath))
+            .map(|(p, t)| {
+                p.strip_prefix(subpath)
+                    .map(|p| (p.to_path_buf(), t.clone()))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        // Links to files outside of `subpath` are restored as copies.
+        let new_hardlinks = self
+            .hardlinks
+            .iter()
+            .filter_map(|(p, t)| {
+                Some((
+                    p.strip_prefix(subpath).ok()?.to_path_buf(),
+                    t.strip_prefix(subpath).ok()?.to_path_buf(),
+                ))
+            })
+            .collect();
         let selected = new_mappings.values().map(|i| i.0).collect::<Vec<_>>();
         let new_hashes = self
             .hashes
@@ -121,8 +477,231 @@ impl Index {
             sizes: new_sizes,
             empty_dirs: new_empties,
             magic_number: self.magic_number,
+            special_files: new_specials,
+            dedup_mode: self.dedup_mode,
+            metadata: self.metadata.clone(),
+            modes: self
+                .modes
+                .iter()
+                .filter_map(|(p, m)| Some((p.strip_prefix(subpath).ok()?.to_path_buf(), *m)))
+                .filter(|(p, _)| p != Path::new(""))
+                .collect(),
+            symlinks: new_symlinks,
+            hardlinks: new_hardlinks,
         })
     }
+    /// The part of the index matching a glob pattern (`*` stays within a directory).
+    /// Like with `selected`, a matching directory brings everything below it, including
+    /// its empty directories. Directories that could hold a match (below `photos` and
+    /// one level deep for `photos/*/*.jpg`) but hold none are kept as empty directories,
+    /// so the layout is restored as well.
+    pub fn matching(&self, pattern: &Pattern) -> Self {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let mut matched = self.filtered(|p| {
+            p.ancestors()
+                .any(|a| a != Path::new("") && pattern.matches_path_with(a, options))
+        });
+        if matched.mapping.is_empty()
+            && matched.empty_dirs.is_empty()
+            && matched.special_files.is_empty()
+            && matched.symlinks.is_empty()
+        {
+            return matched;
+        }
+        let pattern_path = Path::new(pattern.as_str());
+        let base = pattern_path
+            .components()
+            .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect::<PathBuf>();
+        let depth = if pattern.as_str().contains("**") {
+            usize::MAX
+        } else {
+            pattern_path.components().count()
+        };
+        let parents = self
+            .mapping
+            .keys()
+            .chain(self.special_files.iter().map(|(p, _)| p))
+            .chain(self.symlinks.iter().map(|(p, _)| p))
+            .flat_map(|p| p.ancestors().skip(1));
+        let dirs = parents
+            .chain(self.empty_dirs.iter().flat_map(|d| d.ancestors()))
+            .filter(|d| *d != base && d.starts_with(&base) && d.components().count() < depth)
+            .collect::<BTreeSet<_>>();
+        let emptied = dirs
+            .into_iter()
+            .filter(|d| !matched.is_dir(d))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        // Only the deepest ones are stored, their parents are created with them.
+        for (i, dir) in emptied.iter().enumerate() {
+            if emptied.get(i + 1).is_none_or(|next| !next.starts_with(dir)) {
+                matched.empty_dirs.push(dir.clone());
+            }
+        }
+        matched.modes = self.modes.clone();
+        matched.prune_modes();
+        matched
+    }
+    /// The part of the index at or below any of `paths`.
+    pub fn selected(&self, paths: &HashSet<PathBuf>) -> Self {
+        self.filtered(|p| p.ancestors().any(|a| paths.contains(a)))
+    }
+    /// The part of the index whose paths are kept by `keep`, with their full paths.
+    fn filtered(&self, keep: impl Fn(&Path) -> bool) -> Self {
+        let mut filtered = Self {
+            hashes: self.hashes.clone(),
+            mapping: self
+                .mapping
+                .iter()
+                .filter(|(p, _)| keep(p))
+                .map(|(p, m)| (p.clone(), *m))
+                .collect(),
+            sizes: self.sizes.clone(),
+            empty_dirs: self
+                .empty_dirs
+                .iter()
+                .filter(|p| keep(p))
+                .cloned()
+                .collect(),
+            magic_number: self.magic_number,
+            special_files: self
+                .special_files
+                .iter()
+                .filter(|(p, _)| keep(p))
+                .cloned()
+                .collect(),
+            dedup_mode: self.dedup_mode,
+            metadata: self.metadata.clone(),
+            modes: self.modes.clone(),
+            symlinks: self
+                .symlinks
+                .iter()
+                .filter(|(p, _)| keep(p))
+                .cloned()
+                .collect(),
+            hardlinks: self
+                .hardlinks
+                .iter()
+                .filter(|(p, t)| keep(p) && keep(t))
+                .cloned()
+                .collect(),
+        };
+        filtered.prune_modes();
+        filtered
+    }
+    /// Merges the indices of several archives into one view, later layers shadowing
+    /// earlier ones. Chunk offsets are tagged with their layer (see `LAYER_SHIFT`), so the
+    /// archives have to be read through `GenericFile::Union` in the same order.
+    /// Chunks that delta archives take from their base refer to the first layer.
+    pub fn union(layers: Vec<Index>) -> Result<Self> {
+        let Some(first) = layers.first() else {
+            return Err(anyhow!("No archives to merge"));
+        };
+        if layers.len() >= 1 << (63 - LAYER_SHIFT) {
+            return Err(anyhow!("Too many archives to merge"));
+        }
+        if first.mapping.values().any(|(i, _)| i & BASE_CHUNK != 0) {
+            return Err(anyhow!("The first archive must not be a delta archive"));
+        }
+        let mut merged = Index {
+            hashes: HashMap::new(),
+            mapping: HashMap::new(),
+            sizes: HashMap::new(),
+            empty_dirs: vec![],
+            magic_number: first.magic_number,
+            special_files: vec![],
+            dedup_mode: DEDUP_CONTENT,
+            metadata: vec![],
+            modes: HashMap::new(),
+            symlinks: vec![],
+            hardlinks: vec![],
+        };
+        for (layer, index) in layers.into_iter().enumerate() {
+            let tag = |i: u64| {
+                if i & BASE_CHUNK != 0 {
+                    i & !BASE_CHUNK
+                } else {
+                    i | ((layer as u64) << LAYER_SHIFT)
+                }
+            };
+            for (i, hash) in index.hashes {
+                merged.hashes.insert(tag(i), hash);
+            }
+            for (i, size) in index.sizes {
+                merged.sizes.insert(tag(i), size);
+            }
+            for (path, (i, len)) in index.mapping {
+                merged.mapping.insert(path, (tag(i), len));
+            }
+            merged.empty_dirs.extend(index.empty_dirs);
+            merged.special_files.extend(index.special_files);
+            merged.dedup_mode = merged.dedup_mode.max(index.dedup_mode);
+            merged.modes.extend(index.modes);
+            merged.symlinks.extend(index.symlinks);
+            merged.hardlinks.extend(index.hardlinks);
+        }
+        // Whatever contains other entries is a directory, so shadowed files and no longer
+        // empty directories are dropped.
+        let mut containers = HashSet::new();
+        for path in merged
+            .mapping
+            .keys()
+            .chain(&merged.empty_dirs)
+            .chain(merged.special_files.iter().map(|(p, _)| p))
+            .chain(merged.symlinks.iter().map(|(p, _)| p))
+        {
+            containers.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+        }
+        merged.mapping.retain(|p, _| !containers.contains(p));
+        merged.empty_dirs.sort();
+        merged.empty_dirs.dedup();
+        let mapping = &merged.mapping;
+        merged
+            .empty_dirs
+            .retain(|d| !containers.contains(d) && !mapping.contains_key(d));
+        merged
+            .special_files
+            .retain(|(p, _)| !containers.contains(p) && !mapping.contains_key(p));
+        // A later layer may replace a link; the last one wins.
+        let mut seen = HashSet::new();
+        merged.symlinks.reverse();
+        merged.symlinks.retain(|(p, _)| {
+            !containers.contains(p) && !mapping.contains_key(p) && seen.insert(p.clone())
+        });
+        merged.symlinks.reverse();
+        // Links whose file was replaced by a later layer are separate files now.
+        let hardlinks = std::mem::take(&mut merged.hardlinks);
+        merged.hardlinks = hardlinks
+            .into_iter()
+            .filter(|(p, t)| merged.is_hard_link(p, t))
+            .collect();
+        merged.hardlinks.sort();
+        merged.hardlinks.dedup();
+        merged.prune_modes();
+        Ok(merged)
+    }
+    /// Drops the modes of paths that are no longer in the index, keeping those of the
+    /// directories above its entries.
+    pub fn prune_modes(&mut self) {
+        let mut present = HashSet::new();
+        for path in self
+            .mapping
+            .keys()
+            .chain(&self.empty_dirs)
+            .chain(self.special_files.iter().map(|(p, _)| p))
+        {
+            present.extend(path.ancestors().map(Path::to_path_buf));
+        }
+        // Setting the mode of a link would change its target.
+        for (path, _) in &self.symlinks {
+            present.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+        }
+        self.modes.retain(|p, _| present.contains(p));
+    }
     pub fn get_direct_children(&self, path: &Path) -> Result<HashSet<PathBuf>> {
         let mut children = HashSet::new();
         let si = self.subindex(path)?;
@@ -140,26 +719,809 @@ impl Index {
         Ok(children)
     }
 
-    pub fn search(&self, pattern: &str) -> HashSet<PathBuf> {
-        let mut matches = HashSet::new();
-        let pattern = pattern.to_lowercase();
-        for c in self.mapping.keys().chain(&self.empty_dirs) {
-            if let Some(f) = c.file_name().and_then(|f| f.to_str()) {
-                if f.to_lowercase().contains(&pattern) {
-                    matches.insert(c.to_path_buf());
+    /// All files, empty directories, special files and symbolic links, sorted by path.
+    /// Directories that contain something are not listed themselves.
+    pub fn entries(&self) -> Result<Vec<(PathBuf, EntryKind)>> {
+        let mut entries = self
+            .mapping
+            .iter()
+            .map(|(path, (offset, len))| {
+                let size = *self.sizes.get(offset).context("Size not in index")?;
+                let (offset, len) = (*offset, *len);
+                Ok((path.clone(), EntryKind::File { size, offset, len }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        entries.extend(self.empty_dirs.iter().map(|d| (d.clone(), EntryKind::Dir)));
+        entries.extend(self.special_files.iter().map(|(p, (kind, rdev))| {
+            (
+                p.clone(),
+                EntryKind::Special {
+                    kind: *kind,
+                    rdev: *rdev,
+                },
+            )
+        }));
+        entries.extend(self.symlinks.iter().map(|(p, target)| {
+            (
+                p.clone(),
+                EntryKind::Symlink {
+                    target: target.clone(),
+                },
+            )
+        }));
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    /// Groups of paths that share a chunk, largest files first.
+    pub fn duplicate_groups(&self) -> Vec<(u64, Vec<PathBuf>)> {
+        let mut by_chunk: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        for (path, (i, _)) in &self.mapping {
+            by_chunk.entry(*i).or_default().push(path.clone());
+        }
+        let mut groups = by_chunk
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(i, mut paths)| {
+                paths.sort();
+                (self.sizes.get(&i).copied().unwrap_or(0), paths)
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        groups
+    }
+
+    /// Files and directories whose name contains the pattern (case insensitive), sorted by path.
+    pub fn search(&self, pattern: &str, fold: bool) -> Vec<PathBuf> {
+        search_paths(self.mapping.keys().chain(&self.empty_dirs), pattern, fold)
+    }
+}
+
+/// Lowercases `s` for searching. With `fold`, diacritics are removed as well (`Café` becomes
+/// `cafe`, whether the accent is a separate combining character or not). Each byte of the
+/// result is paired with the position of the character of `s` it came from.
+fn search_key(s: &str, fold: bool) -> (String, Vec<usize>) {
+    let mut key = String::with_capacity(s.len());
+    let mut positions = Vec::with_capacity(s.len());
+    for (i, c) in s.char_indices() {
+        let before = key.len();
+        if fold {
+            c.nfd()
+                .filter(|d| !is_combining_mark(*d))
+                .flat_map(char::to_lowercase)
+                .for_each(|d| key.push(d));
+        } else {
+            key.extend(c.to_lowercase());
+        }
+        positions.resize(positions.len() + key.len() - before, i);
+    }
+    (key, positions)
+}
+
+/// Where a search for `pattern` hit the last component of `path`, as a byte range of the
+/// whole path.
+pub fn match_span(path: &Path, pattern: &str, fold: bool) -> Option<Range<usize>> {
+    let full = path.to_str()?;
+    let name = path.file_name()?.to_str()?;
+    let (key, positions) = search_key(name, fold);
+    let (pattern, _) = search_key(pattern, fold);
+    let start = key.find(&pattern)?;
+    let end = start + pattern.len();
+    // The match ends where the character after it begins.
+    let name_end = positions.get(end).copied().unwrap_or(name.len());
+    let offset = full.len() - name.len();
+    Some(offset + positions.get(start).copied()?..offset + name_end)
+}
+
+/// Paths whose name or parent directory name contains the pattern (case insensitive, and
+/// ignoring diacritics with `fold`), sorted. Matching parents are returned as directories.
+pub fn search_paths<'a>(
+    paths: impl Iterator<Item = &'a PathBuf>,
+    pattern: &str,
+    fold: bool,
+) -> Vec<PathBuf> {
+    let search = PathSearch::new(pattern, fold);
+    let mut matches = HashSet::new();
+    for c in paths {
+        matches.extend(search.matches(c).map(Path::to_path_buf));
+    }
+    let mut matches = matches.into_iter().collect::<Vec<_>>();
+    matches.sort();
+    matches
+}
+
+/// The search of `search_paths` for one path at a time, so the paths need not be collected.
+pub struct PathSearch {
+    key: String,
+    fold: bool,
+}
+
+impl PathSearch {
+    pub fn new(pattern: &str, fold: bool) -> Self {
+        Self {
+            key: search_key(pattern, fold).0,
+            fold,
+        }
+    }
+
+    /// The path itself if its name matches, and its parent if the name of the parent does.
+    pub fn matches<'p>(&self, path: &'p Path) -> impl Iterator<Item = &'p Path> + use<'p> {
+        let name_matches = |p: &Path| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| search_key(n, self.fold).0.contains(&self.key))
+        };
+        let file = Some(path).filter(|p| name_matches(*p));
+        let parent = path.parent().filter(|d| name_matches(*d));
+        file.into_iter().chain(parent)
+    }
+}
+
+/// Runs `parse` again on a new connection when reading a remote archive fails.
+/// Errors that do not come from the connection (wrong key, corrupt index) are returned at once.
+fn with_retries<T>(
+    archive: &mut GenericFile,
+    parse: impl Fn(&mut GenericFile) -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match parse(archive) {
+            Err(e) if attempt < INDEX_READ_ATTEMPTS && archive.is_remote() && is_io_error(&e) => {
+                warn!("Reading the index failed ({e}), reconnecting");
+                std::thread::sleep(Duration::from_secs(attempt as u64));
+                // A failed reconnect shows up as another failed attempt.
+                let _ = archive.reconnect();
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Errors of the connection or the file, which may go away when trying again.
+pub fn is_io_error(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.is::<std::io::Error>() || cause.is::<ssh2::Error>())
+}
+
+/// Reads and decrypts the index, returning it with the archive's magic number and the
+/// position the index starts at.
+fn read_index_content(
+    archive: &mut GenericFile,
+    keys: &Vec<Box<dyn age::Identity>>,
+) -> Result<(Vec<u8>, u64, u64)> {
+    let magic = archive_magic(archive)?;
+    if magic == magic_number() && keys.is_empty() {
+        return Err(anyhow!("Archive is encrypted but no identities were given"));
+    }
+    if magic == plain_magic_number() && !keys.is_empty() {
+        return Err(anyhow!(
+            "Archive is not encrypted, no identities are needed"
+        ));
+    }
+    let (index_start, index_offset) = index_location(archive, magic)?;
+    archive.seek(SeekFrom::Start(index_start))?;
+    let mut content = vec![];
+    decrypt_index(archive, &mut content, index_offset, u64::MAX, keys)?;
+    Ok((content, magic, index_start))
+}
+
+/// The start and length of the index chunk.
+fn index_location(archive: &mut GenericFile, magic: u64) -> Result<(u64, u64)> {
+    let end = archive.seek(SeekFrom::End(0))?;
+    if end < MIN_ARCHIVE_SIZE {
+        return Err(anyhow!(
+            "Archive is too small or truncated ({end} bytes, at least {MIN_ARCHIVE_SIZE} expected)"
+        ));
+    }
+    // The whole footer is fetched in one read (a revision 1 footer is 8 bytes shorter).
+    let tail_len = (end - 8).min(24);
+    archive.seek(SeekFrom::Start(end - tail_len))?;
+    let mut tail = vec![0_u8; tail_len as usize];
+    archive.read_exact(&mut tail)?;
+    let n = tail.len();
+    let word = |at: usize| u64::from_le_bytes(tail[at..at + 8].try_into().expect("8 bytes"));
+    let (footer, index_offset) = if word(n - 8) == magic {
+        if n == 24 && is_revision_word(word(8)) {
+            (end - 24, word(0))
+        } else {
+            (end - 16, word(n - 16))
+        }
+    } else {
+        let footer = locate_footer(archive)?;
+        (footer, read_footer(archive, footer)?.index_len)
+    };
+    // The index has to fit between the leading magic number and the footer.
+    let index_start = footer
+        .checked_sub(index_offset)
+        .filter(|start| *start >= 8 && index_offset > 0)
+        .context("Corrupt archive: index offset out of range")?;
+    Ok((index_start, index_offset))
+}
+
+/// Whether the keys can decrypt the index. Only the first bytes of the index are decrypted,
+/// which answers "is this my archive?" without loading a large (remote) index.
+/// Other errors than a missing key are returned.
+pub fn check_access(archive: &mut GenericFile, keys: &Vec<Box<dyn age::Identity>>) -> Result<bool> {
+    let magic = archive_magic(archive)?;
+    if magic == magic_number() && keys.is_empty() {
+        return Ok(false);
+    }
+    let (index_start, index_offset) = index_location(archive, magic)?;
+    archive.seek(SeekFrom::Start(index_start))?;
+    match decrypt_index(archive, &mut std::io::sink(), index_offset, 0, keys) {
+        Ok(_) => Ok(true),
+        Err(e)
+            if matches!(
+                e.downcast_ref::<age::DecryptError>(),
+                Some(age::DecryptError::NoMatchingKeys)
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The types of the recipient stanzas (`X25519`, `scrypt`, ...) in the age header of the
+/// index. Every chunk is encrypted to the same recipients, so this describes the archive.
+pub fn index_stanzas(archive: &mut GenericFile) -> Result<Vec<String>> {
+    let magic = archive_magic(archive)?;
+    if magic != magic_number() {
+        return Err(anyhow!("Archive is not encrypted"));
+    }
+    let (index_start, index_offset) = index_location(archive, magic)?;
+    archive.seek(SeekFrom::Start(index_start))?;
+    let mut header = vec![];
+    archive
+        .take(index_offset.min(MAX_HEADER_SIZE))
+        .read_to_end(&mut header)?;
+    let mut stanzas = vec![];
+    for line in header.split(|b| *b == b'\n') {
+        if line.starts_with(b"---") {
+            return Ok(stanzas);
+        }
+        if let Some(stanza) = line.strip_prefix(b"-> ") {
+            let kind = stanza.split(|b| *b == b' ').next().unwrap_or_default();
+            stanzas.push(String::from_utf8_lossy(kind).to_string());
+        }
+    }
+    Err(anyhow!(
+        "Corrupt archive: age header of the index not found"
+    ))
+}
+
+/// The serialized index, queried by scanning it instead of building the maps of `Index`.
+/// This is much cheaper for single queries (list, find, du) on huge archives.
+/// Unlike `Index::parse`, the paths are not validated.
+pub struct LazyIndex {
+    content: Vec<u8>,
+    files: usize,
+    /// The trailing sections that matter for listing, which are small enough to keep parsed.
+    special_files: Vec<(PathBuf, (u64, u64))>,
+    symlinks: Vec<(PathBuf, PathBuf)>,
+    hardlinks: HashMap<PathBuf, PathBuf>,
+    /// Positions of the sections in `content` (after their length prefix for the files).
+    chunks_at: usize,
+    paths_at: usize,
+    empty_dirs_at: usize,
+    hash_indices_at: usize,
+    sizes_at: usize,
+    /// The number of chunks with a hash and a size.
+    chunks: usize,
+    /// Indices written before the chunks were sorted are looked up in a map instead.
+    unsorted_sizes: Option<HashMap<u64, u64>>,
+}
+
+impl LazyIndex {
+    pub fn parse(archive: &mut GenericFile, keys: &Vec<Box<dyn age::Identity>>) -> Result<Self> {
+        with_retries(archive, |archive| Self::from_archive(archive, keys))
+    }
+
+    fn from_archive(archive: &mut GenericFile, keys: &Vec<Box<dyn age::Identity>>) -> Result<Self> {
+        let (content, magic, _) = read_index_content(archive, keys)?;
+        let mut reader = content.as_slice();
+        if u64::read_bin(&mut reader)? != magic {
+            return Err(anyhow!("Unrecognized archive format (index magic number)"));
+        }
+        let files = u64::read_bin(&mut reader)? as usize;
+        let chunks_at = content.len() - reader.len();
+        reader = reader
+            .get(files.checked_mul(16).context("Malformed index")?..)
+            .context("Malformed index")?;
+        if u64::read_bin(&mut reader)? as usize != files {
+            return Err(anyhow!("Malformed index"));
+        }
+        let paths_at = content.len() - reader.len();
+        for _ in 0..files {
+            skip_path(&mut reader)?;
+        }
+        let empty_dirs_at = content.len() - reader.len();
+        for _ in 0..u64::read_bin(&mut reader)? {
+            skip_path(&mut reader)?;
+        }
+        let hash_indices_at = content.len() - reader.len();
+        let hashes = u64::read_bin(&mut reader)? as usize;
+        // The hash indices, the hashes and the sizes all have `hashes` entries of fixed size.
+        let hashes_at = hashes
+            .checked_mul(8)
+            .and_then(|n| n.checked_add(hash_indices_at + 8))
+            .context("Malformed index")?;
+        let sizes_at = hashes
+            .checked_mul(32)
+            .and_then(|n| n.checked_add(hashes_at + 8))
+            .context("Malformed index")?;
+        let end = hashes
+            .checked_mul(8)
+            .and_then(|n| n.checked_add(sizes_at + 8))
+            .context("Malformed index")?;
+        if content.len() < end
+            || u64::read_bin(&mut &content[hashes_at..])? as usize != hashes
+            || u64::read_bin(&mut &content[sizes_at..])? as usize != hashes
+        {
+            return Err(anyhow!("Malformed index"));
+        }
+        // The same optional sections as in `Index::read_bin`. The modes hold a path per
+        // file, so they are skipped instead of read.
+        let mut rest = &content[end..];
+        let special_files = read_optional(&mut rest)?.unwrap_or_default();
+        let _dedup_mode: Option<u64> = read_optional(&mut rest)?;
+        let _metadata: Option<Vec<(String, String)>> = read_optional(&mut rest)?;
+        if !rest.is_empty() {
+            for _ in 0..u64::read_bin(&mut rest)? {
+                skip_path(&mut rest)?;
+                u64::read_bin(&mut rest)?;
+            }
+        }
+        let symlinks = read_optional(&mut rest)?.unwrap_or_default();
+        let hardlinks: Vec<(PathBuf, PathBuf)> = read_optional(&mut rest)?.unwrap_or_default();
+        let mut index = Self {
+            content,
+            files,
+            special_files,
+            symlinks,
+            hardlinks: hardlinks.into_iter().collect(),
+            chunks_at,
+            paths_at,
+            empty_dirs_at,
+            hash_indices_at,
+            sizes_at,
+            chunks: hashes,
+            unsorted_sizes: None,
+        };
+        let mut sorted = true;
+        for n in 1..hashes {
+            if index.chunk_at(n - 1)? >= index.chunk_at(n)? {
+                sorted = false;
+                break;
+            }
+        }
+        if !sorted {
+            let indices: Vec<u64> = Vec::read_bin(&mut &index.content[hash_indices_at..])?;
+            let sizes: Vec<u64> = Vec::read_bin(&mut &index.content[sizes_at..])?;
+            index.unsorted_sizes = Some(indices.into_iter().zip(sizes).collect());
+        }
+        Ok(index)
+    }
+
+    /// All files below (or at) `prefix` with their chunk, in the order they are stored.
+    pub fn iter_under<'a>(
+        &'a self,
+        prefix: &'a Path,
+    ) -> impl Iterator<Item = Result<(PathBuf, (u64, u64))>> + 'a {
+        let mut paths = &self.content[self.paths_at..];
+        let mut chunks = &self.content[self.chunks_at..];
+        (0..self.files).filter_map(move |_| {
+            let entry = PathBuf::read_bin(&mut paths)
+                .and_then(|p| Ok((p, <(u64, u64)>::read_bin(&mut chunks)?)));
+            match entry {
+                Ok((path, _)) if !path.starts_with(prefix) => None,
+                e => Some(e),
+            }
+        })
+    }
+
+    pub fn contains(&self, path: &Path) -> Result<bool> {
+        for entry in self.iter_under(path) {
+            if entry?.0 == path {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// All directories below `prefix`: the parents of the files, special files and links and
+    /// the empty directories with their parents.
+    pub fn directories_under(&self, prefix: &Path) -> Result<BTreeSet<PathBuf>> {
+        let mut dirs = BTreeSet::new();
+        let mut add_ancestors = |path: &Path| {
+            for dir in path.ancestors() {
+                if dir == prefix || !dir.starts_with(prefix) || !dirs.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        };
+        for entry in self.iter_under(prefix) {
+            let (path, _) = entry?;
+            if let Some(parent) = path.parent() {
+                add_ancestors(parent);
+            }
+        }
+        for (path, _) in self.others_under(prefix) {
+            if let Some(parent) = path.parent() {
+                add_ancestors(parent);
+            }
+        }
+        for empty_dir in self.empty_dirs_under(prefix)? {
+            add_ancestors(&empty_dir);
+        }
+        Ok(dirs)
+    }
+    /// The empty directories below (or at) `prefix`.
+    pub fn empty_dirs_under(&self, prefix: &Path) -> Result<Vec<PathBuf>> {
+        let empty_dirs: Vec<PathBuf> = Vec::read_bin(&mut &self.content[self.empty_dirs_at..])?;
+        Ok(empty_dirs
+            .into_iter()
+            .filter(|p| p.starts_with(prefix))
+            .collect())
+    }
+
+    /// Special files and symbolic links below (or at) `prefix`, sorted by path.
+    pub fn others_under(&self, prefix: &Path) -> Vec<(PathBuf, EntryKind)> {
+        let specials = self.special_files.iter().map(|(p, (kind, rdev))| {
+            (
+                p.clone(),
+                EntryKind::Special {
+                    kind: *kind,
+                    rdev: *rdev,
+                },
+            )
+        });
+        let symlinks = self.symlinks.iter().map(|(p, target)| {
+            (
+                p.clone(),
+                EntryKind::Symlink {
+                    target: target.clone(),
+                },
+            )
+        });
+        let mut others = specials
+            .chain(symlinks)
+            .filter(|(p, _)| p.starts_with(prefix))
+            .collect::<Vec<_>>();
+        others.sort_by(|a, b| a.0.cmp(&b.0));
+        others
+    }
+
+    /// The file `path` is a hard link to, if it is one.
+    pub fn hardlink_target(&self, path: &Path) -> Option<&Path> {
+        self.hardlinks.get(path).map(PathBuf::as_path)
+    }
+
+    /// The uncompressed size of a chunk, found by a binary search in the sorted chunks.
+    pub fn size(&self, chunk: u64) -> Result<Option<u64>> {
+        if let Some(sizes) = &self.unsorted_sizes {
+            return Ok(sizes.get(&chunk).copied());
+        }
+        let (mut low, mut high) = (0, self.chunks);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.chunk_at(mid)?.cmp(&chunk) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => {
+                    return u64::read_bin(&mut &self.content[self.sizes_at + 8 + 8 * mid..])
+                        .map(Some);
                 }
             }
-            if let Some(d) = c
-                .parent()
-                .and_then(|d| d.file_name())
-                .and_then(|d| d.to_str())
-            {
-                if d.to_lowercase().contains(&pattern) {
-                    let parent = c.parent().expect("Must have parent to match").to_path_buf();
-                    matches.insert(parent);
+        }
+        Ok(None)
+    }
+
+    /// The chunk index of the `n`th hash.
+    fn chunk_at(&self, n: usize) -> Result<u64> {
+        u64::read_bin(&mut &self.content[self.hash_indices_at + 8 + 8 * n..])
+    }
+}
+
+fn skip_path(reader: &mut &[u8]) -> Result<()> {
+    for _ in 0..u64::read_bin(reader)? {
+        let len = u64::read_bin(reader)? as usize;
+        *reader = reader.get(len..).context("Malformed index")?;
+    }
+    Ok(())
+}
+
+/// Returns the position of the footer (the index length, the revision and the magic number).
+/// Usually it is the last 24 (or, for revision 1, 16) bytes, but a small trailer after the
+/// archive is tolerated. An archive that is still being written (or was never finished)
+/// has no footer at the end, then the footer of its latest checkpoint is used.
+pub fn locate_footer(archive: &mut GenericFile) -> Result<u64> {
+    if let Some(footer) = search_footer(archive, TRAILER_SEARCH)? {
+        return Ok(footer);
+    }
+    let footer = locate_latest_footer(archive)?;
+    warn!("The archive has no final index, reading its latest checkpoint");
+    Ok(footer)
+}
+
+/// Like `locate_footer`, but always searches the whole archive for the latest footer.
+pub fn locate_latest_footer(archive: &mut GenericFile) -> Result<u64> {
+    search_footer(archive, u64::MAX)?
+        .context("Unrecognized archive format (trailing magic number not found)")
+}
+
+/// Searches the last `limit` bytes backwards for the trailing magic number.
+fn search_footer(archive: &mut GenericFile, limit: u64) -> Result<Option<u64>> {
+    let end = archive.seek(SeekFrom::End(0))?;
+    if end < MIN_ARCHIVE_SIZE {
+        return Err(anyhow!(
+            "Archive is too small or truncated ({end} bytes, at least {MIN_ARCHIVE_SIZE} expected)"
+        ));
+    }
+    let expected = archive_magic(archive)?;
+    archive.seek(SeekFrom::Start(end - 16))?;
+    let (word, magic) = <(u64, u64)>::read_bin(archive)?;
+    if magic == expected {
+        return Ok(Some(if is_revision_word(word) && end >= 32 {
+            end - 24
+        } else {
+            end - 16
+        }));
+    }
+    let magic = expected.to_le_bytes();
+    let search_start = end.saturating_sub(limit);
+    let mut block_end = end;
+    while block_end >= 16 {
+        let block_start = block_end.saturating_sub(SEARCH_BLOCK).max(search_start);
+        archive.seek(SeekFrom::Start(block_start))?;
+        let mut block = vec![0_u8; (block_end - block_start) as usize];
+        archive.read_exact(&mut block)?;
+        if let Some(pos) = block.windows(magic.len()).rposition(|w| w == magic) {
+            let magic_pos = block_start + pos as u64;
+            // The leading magic number and the index length (and the revision) come before it.
+            if magic_pos >= 24 {
+                archive.seek(SeekFrom::Start(magic_pos - 8))?;
+                if is_revision_word(u64::read_bin(archive)?) {
+                    return Ok(Some(magic_pos - 16));
                 }
             }
+            if magic_pos >= 16 {
+                return Ok(Some(magic_pos - 8));
+            }
+        }
+        if block_start == search_start {
+            break;
+        }
+        // Overlap the blocks so a magic number on the border is not missed.
+        block_end = block_start + magic.len() as u64 - 1;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{create_archive, open_archive, replace_index, scratch_dir, write_files};
+
+    #[test]
+    fn chunk_beyond_the_index() {
+        let dir = scratch_dir("chunk-range");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("b.txt", b"world")]);
+        let archive = dir.join("archive.zprt");
+        let mut index = create_archive(&source, &archive, &[]).unwrap();
+        let (offset, _) = index.mapping[Path::new("b.txt")];
+        // Reaches into the index and the footer.
+        index
+            .mapping
+            .insert(PathBuf::from("b.txt"), (offset, 1 << 20));
+        replace_index(&archive, &index).unwrap();
+        let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+        assert!(format!("{error:#}").contains("chunk of b.txt out of range"));
+    }
+
+    #[test]
+    fn symlink_clashing_with_a_file() {
+        let dir = scratch_dir("symlink-clash");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("b.txt", b"world")]);
+        let archive = dir.join("archive.zprt");
+        let mut index = create_archive(&source, &archive, &[]).unwrap();
+        index
+            .symlinks
+            .push((PathBuf::from("b.txt"), PathBuf::from("a.txt")));
+        replace_index(&archive, &index).unwrap();
+        let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+        assert!(format!("{error:#}").contains("b.txt is both a symbolic link and a file"));
+    }
+
+    #[test]
+    fn read_a_revision_1_footer() {
+        let dir = scratch_dir("revision-1");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("b/c.txt", b"world")]);
+        let archive = dir.join("archive.zprt");
+        let written = create_archive(&source, &archive, &[]).unwrap();
+        // [index length][revision word][magic number] becomes [index length][magic number].
+        let mut content = std::fs::read(&archive).unwrap();
+        let end = content.len();
+        let word = u64::from_le_bytes(content[end - 16..end - 8].try_into().unwrap());
+        assert_eq!(word, revision_word());
+        content.drain(end - 16..end - 8);
+        std::fs::write(&archive, content).unwrap();
+
+        let mut file = open_archive(&archive);
+        let footer = locate_footer(&mut file).unwrap();
+        let parsed = read_footer(&mut file, footer).unwrap();
+        assert_eq!((parsed.revision, parsed.len), (1, 16));
+        let index = Index::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        assert_eq!(index.mapping, written.mapping);
+        let lazy = LazyIndex::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        assert_eq!(lazy.iter_under(Path::new("")).count(), 2);
+    }
+
+    #[test]
+    fn read_the_latest_checkpoint() {
+        let dir = scratch_dir("in-progress");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("b/c.txt", b"world")]);
+        let archive = dir.join("archive.zprt");
+        let written = create_archive(&source, &archive, &[]).unwrap();
+        // Chunks written after the checkpoint, more than the trailer search covers.
+        let mut content = std::fs::read(&archive).unwrap();
+        content.extend([0xab; 2 * TRAILER_SEARCH as usize]);
+        std::fs::write(&archive, content).unwrap();
+        let index = Index::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        assert_eq!(index.mapping, written.mapping);
+        let lazy = LazyIndex::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        assert_eq!(lazy.iter_under(Path::new("")).count(), 2);
+    }
+
+    #[test]
+    fn lazy_sizes_without_a_map() {
+        let dir = scratch_dir("lazy-sizes");
+        let source = dir.join("source");
+        let files = (0..50)
+            .map(|i| (format!("dir{}/f{i}.txt", i % 7), vec![b'x'; i * 3]))
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(p, c)| (p.as_str(), c.as_slice()))
+            .collect::<Vec<_>>();
+        write_files(&source, &files);
+        let archive = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive, &[]).unwrap();
+        let lazy = LazyIndex::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        // The chunks are sorted, so sizes are searched in the serialized index.
+        assert!(lazy.unsorted_sizes.is_none());
+        for entry in lazy.iter_under(Path::new("")) {
+            let (path, (i, _)) = entry.unwrap();
+            assert_eq!(lazy.size(i).unwrap(), Some(index.sizes[&i]), "{path:?}");
+        }
+        assert_eq!(lazy.size(1).unwrap(), None);
+        assert_eq!(lazy.size(u64::MAX).unwrap(), None);
+    }
+
+    #[test]
+    fn path_both_file_and_directory() {
+        let dir = scratch_dir("file-and-dir");
+        let source = dir.join("source");
+        write_files(&source, &[("a", b"a"), ("b/c.txt", b"c")]);
+        let archive = dir.join("archive.zprt");
+        let original = create_archive(&source, &archive, &[]).unwrap();
+        let chunk = original.mapping[Path::new("a")];
+
+        let mut index = original.clone();
+        index.mapping.insert(PathBuf::from("a/inner.txt"), chunk);
+        replace_index(&archive, &index).unwrap();
+        let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+        assert!(format!("{error:#}").contains("a is a file but contains a/inner.txt"));
+
+        let mut index = original.clone();
+        index.empty_dirs.push(PathBuf::from("a"));
+        replace_index(&archive, &index).unwrap();
+        let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+        assert!(format!("{error:#}").contains("a is both a file and an empty directory"));
+
+        let mut index = original.clone();
+        index.empty_dirs.push(PathBuf::from("b"));
+        replace_index(&archive, &index).unwrap();
+        let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+        assert!(format!("{error:#}").contains("b is an empty directory but contains b/c.txt"));
+
+        replace_index(&archive, &original).unwrap();
+        let index = Index::parse(&mut open_archive(&archive), &vec![]).unwrap();
+        assert!(index.is_file(Path::new("a")) && !index.is_dir(Path::new("a")));
+        assert!(index.is_dir(Path::new("b")) && !index.is_file(Path::new("b")));
+    }
+
+    #[test]
+    fn archives_too_small() {
+        let dir = scratch_dir("too-small");
+        let mut with_magic = plain_magic_number().to_le_bytes().to_vec();
+        with_magic.extend([0; 12]);
+        for (name, content) in [
+            ("empty", vec![]),
+            ("three", vec![1, 2, 3]),
+            ("magic-only", with_magic),
+        ] {
+            let archive = dir.join(name);
+            std::fs::write(&archive, content).unwrap();
+            let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("too small or truncated"),
+                "{name}: {error:#}"
+            );
+            let error = LazyIndex::parse(&mut open_archive(&archive), &vec![])
+                .err()
+                .unwrap();
+            assert!(format!("{error:#}").contains("too small or truncated"));
+            let error = locate_footer(&mut open_archive(&archive)).unwrap_err();
+            assert!(format!("{error:#}").contains("too small or truncated"));
+        }
+    }
+
+    /// Run with `cargo test --release du_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn du_benchmark() {
+        let files = 1_000_000;
+        let mut index = Index {
+            hashes: HashMap::new(),
+            mapping: HashMap::new(),
+            sizes: HashMap::new(),
+            empty_dirs: vec![],
+            magic_number: magic_number(),
+            special_files: vec![],
+            dedup_mode: DEDUP_CONTENT,
+            metadata: vec![],
+            modes: HashMap::new(),
+            symlinks: vec![],
+            hardlinks: vec![],
+        };
+        for i in 0..files {
+            let path = PathBuf::from(format!("dir{}/sub{}/file{i}", i % 100, i % 7));
+            let offset = 8 + i * 16;
+            index.mapping.insert(path, (offset, 16));
+            index.hashes.insert(offset, [0; 32]);
+            index.sizes.insert(offset, i % 1000);
+        }
+        for dir in ["", "dir7", "dir7/sub3"] {
+            let start = std::time::Instant::now();
+            let size = index.du(Path::new(dir)).unwrap();
+            println!(
+                "du {dir:?} of {files} files: {size} bytes in {:?}",
+                start.elapsed()
+            );
+        }
+        let total = index.du(Path::new("")).unwrap();
+        assert_eq!(total, (0..files).map(|i| i % 1000).sum::<u64>());
+    }
+
+    #[test]
+    fn tampered_footer() {
+        use std::io::Write;
+        let dir = scratch_dir("tampered-footer");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello")]);
+        let archive = dir.join("archive.zprt");
+        create_archive(&source, &archive, &[]).unwrap();
+        let len = std::fs::metadata(&archive).unwrap().len();
+        for index_len in [u64::MAX - 7, len, 0] {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&archive)
+                .unwrap();
+            // The index length is the first word of the 24-byte footer.
+            file.seek(SeekFrom::Start(len - 24)).unwrap();
+            file.write_all(&index_len.to_le_bytes()).unwrap();
+            drop(file);
+            let error = Index::parse(&mut open_archive(&archive), &vec![]).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("index offset out of range"),
+                "{index_len}: {error:#}"
+            );
         }
-        matches
     }
 }
@@ -1,15 +1,34 @@
 use clap::Parser;
 use colored::*;
+use log::LevelFilter;
+use std::io::Write;
 
 mod archiver;
+mod browse;
 mod cli;
 mod fuse;
 mod index;
+mod recompress;
+mod recover;
 mod restore;
 mod serializer;
+mod ssh_config;
+#[cfg(test)]
+mod testutil;
+mod update;
 mod utils;
+mod volumes;
 fn main() {
-    let result = cli::Cli::parse().run();
+    let cli = cli::Cli::parse();
+    // Other crates only get to warn, and messages are printed without decoration.
+    let level = cli.log_level();
+    env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module("zipurat", level)
+        .parse_default_env()
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+    let result = cli.run();
     if let Err(e) = result {
         eprintln!("Error: {}", format!("{}", e).red());
     }
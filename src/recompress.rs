@@ -0,0 +1,125 @@
+use crate::{
+    archiver::write_index,
+    index::{BASE_CHUNK, Index},
+    serializer::SimpleBinRepr,
+    utils::{Codec, GenericFile, compress_and_encrypt, decoding_reader},
+};
+use anyhow::{Context, Result, anyhow};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{Read, Seek, SeekFrom},
+};
+
+/// Writes a copy of an archive with every chunk compressed again with zstd at `level`.
+/// The chunks are decrypted and decompressed one at a time and streamed into the new
+/// archive, so the source tree is not needed. Each chunk is checked against its hash.
+/// Chunks of a base archive (delta archives) are left where they are.
+pub fn recompress(
+    archive: &mut GenericFile,
+    out: &mut GenericFile,
+    index: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+    recipients: &Vec<Box<dyn age::Recipient + Send>>,
+    level: i32,
+    long_window: Option<u32>,
+    index_level: i32,
+) -> Result<()> {
+    let chunks: BTreeSet<(u64, u64)> = index
+        .mapping
+        .values()
+        .filter(|(i, _)| i & BASE_CHUNK == 0)
+        .copied()
+        .collect();
+    index.magic_number.write_bin(out)?;
+    let pb = ProgressBar::new(chunks.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template("{bar:40} {pos:>7}/{len:7} [{elapsed_precise}, eta {eta}]")
+            .context("Progress bar error")?,
+    );
+    let mut moved = HashMap::new();
+    let (mut before, mut after) = (0, 0);
+    for (n, (start, len)) in chunks.iter().enumerate() {
+        pb.set_position(n as u64);
+        archive.seek(SeekFrom::Start(*start))?;
+        let mut source = HashingReader {
+            inner: decoding_reader(archive, *len, ids)?,
+            hasher: blake3::Hasher::new(),
+        };
+        let new_start = out.stream_position()?;
+        compress_and_encrypt(
+            &mut source,
+            out,
+            Codec::Zstd,
+            level,
+            long_window,
+            recipients,
+        )?;
+        if index.hashes.get(start) != Some(source.hasher.finalize().as_bytes()) {
+            return Err(anyhow!(
+                "The chunk at {} does not match its hash (archive damaged?)",
+                start
+            ));
+        }
+        let new_len = out.stream_position()? - new_start;
+        moved.insert(*start, (new_start, new_len));
+        before += len;
+        after += new_len;
+    }
+    pb.finish_and_clear();
+
+    let moved_index = |i: &u64| moved.get(i).map(|(new, _)| *new).unwrap_or(*i);
+    let recompressed = Index {
+        mapping: index
+            .mapping
+            .iter()
+            .map(|(path, chunk)| (path.clone(), moved.get(&chunk.0).copied().unwrap_or(*chunk)))
+            .collect(),
+        hashes: index
+            .hashes
+            .iter()
+            .map(|(i, hash)| (moved_index(i), *hash))
+            .collect(),
+        sizes: index
+            .sizes
+            .iter()
+            .map(|(i, size)| (moved_index(i), *size))
+            .collect(),
+        magic_number: index.magic_number,
+        empty_dirs: index.empty_dirs.clone(),
+        special_files: index.special_files.clone(),
+        dedup_mode: index.dedup_mode,
+        metadata: index
+            .metadata
+            .iter()
+            .filter(|(key, _)| key != "codec")
+            .cloned()
+            .collect(),
+        modes: index.modes.clone(),
+        symlinks: index.symlinks.clone(),
+        hardlinks: index.hardlinks.clone(),
+    };
+    write_index(&recompressed, out, index_level, recipients)?;
+    info!(
+        "Recompressed {} chunks at level {}: {} -> {} bytes",
+        chunks.len(),
+        level,
+        before,
+        after
+    );
+    Ok(())
+}
+
+pub(crate) struct HashingReader<R: Read> {
+    pub(crate) inner: R,
+    pub(crate) hasher: blake3::Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    archiver::write_index,
+    index::{
+        DEDUP_CONTENT, Index, locate_latest_footer, magic_number, plain_magic_number, read_footer,
+    },
+    serializer::SimpleBinRepr,
+    utils::{GenericFile, decrypt_and_decompress, decrypt_index},
+};
+use anyhow::{Context, Result, anyhow};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// Every chunk (and the index) is an age file starting with this line.
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+const SCAN_BLOCK: u64 = 1 << 20;
+/// Upper bound for decoding a candidate index into memory.
+const MAX_INDEX_SIZE: u64 = 1 << 30;
+
+/// Writes a repaired copy of an archive whose index or footer is damaged.
+/// If the index itself can still be decrypted, nothing is lost. An archive that was not
+/// finished keeps the files of its latest checkpoint. Otherwise a new index is built from
+/// all chunks that decrypt, named `chunk_0001` and so on.
+/// Only encrypted archives can be recovered, since the chunks are found by their age header.
+pub fn recover(
+    archive: &mut GenericFile,
+    out: &mut GenericFile,
+    ids: &Vec<Box<dyn age::Identity>>,
+    recipients: &Vec<Box<dyn age::Recipient + Send>>,
+) -> Result<()> {
+    archive.seek(SeekFrom::Start(0))?;
+    if u64::read_bin(archive).ok() == Some(plain_magic_number()) {
+        return Err(anyhow!("Unencrypted archives can not be recovered"));
+    }
+    let end = archive.seek(SeekFrom::End(0))?;
+    let starts = find_chunk_starts(archive, end)?;
+    let Some(last) = starts.last().copied() else {
+        return Err(anyhow!("No chunks found"));
+    };
+    if let Some(index) = try_read_index(archive, last, end, ids) {
+        copy_prefix(archive, out, last)?;
+        write_index(&index, out, 22, recipients)?;
+        info!("The index is intact, all paths were recovered");
+        return Ok(());
+    }
+    if let Some((index_start, index)) = latest_checkpoint(archive, ids) {
+        copy_prefix(archive, out, index_start)?;
+        write_index(&index, out, 22, recipients)?;
+        info!(
+            "Recovered the {} files of the latest checkpoint",
+            index.mapping.len()
+        );
+        return Ok(());
+    }
+
+    let mut mapping = HashMap::new();
+    let mut hashes = HashMap::new();
+    let mut sizes = HashMap::new();
+    let mut data_end = 8;
+    let mut failed = 0;
+    let pb = ProgressBar::new(starts.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template("{bar:40} {pos:>7}/{len:7} [{elapsed_precise}, eta {eta}]")
+            .context("Progress bar error")?,
+    );
+    for (n, start) in starts.iter().enumerate() {
+        pb.set_position(n as u64);
+        let next = starts.get(n + 1).copied().unwrap_or(end);
+        let Some((len, hash, size)) = hash_chunk(archive, *start, next - start, ids) else {
+            failed += 1;
+            continue;
+        };
+        if len < next - start && is_checkpoint_footer(archive, start + len)? {
+            continue;
+        }
+        let name = format!("chunk_{:04}", mapping.len() + 1);
+        mapping.insert(PathBuf::from(name), (*start, len));
+        hashes.insert(*start, hash);
+        sizes.insert(*start, size);
+        data_end = start + len;
+    }
+    pb.finish_and_clear();
+
+    copy_prefix(archive, out, data_end)?;
+    let recovered = mapping.len();
+    let index = Index {
+        mapping,
+        hashes,
+        sizes,
+        empty_dirs: vec![],
+        magic_number: magic_number(),
+        special_files: vec![],
+        dedup_mode: DEDUP_CONTENT,
+        metadata: vec![],
+        modes: HashMap::new(),
+        symlinks: vec![],
+        hardlinks: vec![],
+    };
+    write_index(&index, out, 22, recipients)?;
+    info!(
+        "Recovered {} files without their paths ({} chunks could not be decrypted)",
+        recovered, failed
+    );
+    Ok(())
+}
+
+fn find_chunk_starts(archive: &mut GenericFile, end: u64) -> Result<Vec<u64>> {
+    let mut starts = vec![];
+    let mut pos = 8;
+    while pos < end {
+        let block_end = (pos + SCAN_BLOCK).min(end);
+        archive.seek(SeekFrom::Start(pos))?;
+        let mut block = vec![0_u8; (block_end - pos) as usize];
+        archive.read_exact(&mut block)?;
+        for (i, w) in block.windows(AGE_HEADER.len()).enumerate() {
+            if w == AGE_HEADER {
+                starts.push(pos + i as u64);
+            }
+        }
+        if block_end == end {
+            break;
+        }
+        // Overlap the blocks so a header on the border is not missed.
+        pos = block_end - (AGE_HEADER.len() as u64 - 1);
+    }
+    Ok(starts)
+}
+
+/// Candidate lengths for an age file starting at `start`, with or without a footer behind it
+/// (24 bytes, or 16 for revision 1 archives).
+fn candidate_lengths(len: u64) -> Vec<u64> {
+    [0, 24, 16]
+        .into_iter()
+        .filter(|footer| len > *footer)
+        .map(|footer| len - footer)
+        .collect()
+}
+
+fn try_read_index(
+    archive: &mut GenericFile,
+    start: u64,
+    end: u64,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Option<Index> {
+    for len in candidate_lengths(end - start) {
+        archive.seek(SeekFrom::Start(start)).ok()?;
+        let mut content = vec![];
+        if decrypt_index(archive, &mut content, len, MAX_INDEX_SIZE, ids).is_err() {
+            continue;
+        }
+        if let Ok(index) = Index::read_bin(&mut content.as_slice()) {
+            if index.magic_number == magic_number() {
+                return Some(index);
+            }
+        }
+    }
+    None
+}
+
+/// The start and the content of the index of the latest footer anywhere in the archive.
+fn latest_checkpoint(
+    archive: &mut GenericFile,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Option<(u64, Index)> {
+    let footer = locate_latest_footer(archive).ok()?;
+    let index_len = read_footer(archive, footer).ok()?.index_len;
+    let index_start = footer.checked_sub(index_len).filter(|start| *start >= 8)?;
+    let index = try_read_index(archive, index_start, footer, ids)?;
+    Some((index_start, index))
+}
+
+fn hash_chunk(
+    archive: &mut GenericFile,
+    start: u64,
+    len: u64,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Option<(u64, [u8; 32], u64)> {
+    for len in candidate_lengths(len) {
+        archive.seek(SeekFrom::Start(start)).ok()?;
+        let mut sink = HashingSink {
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        };
+        if decrypt_and_decompress(archive, &mut sink, len, ids).is_ok() {
+            return Some((len, *sink.hasher.finalize().as_bytes(), sink.size));
+        }
+    }
+    None
+}
+
+/// Interim indices written during create are followed by a footer.
+fn is_checkpoint_footer(archive: &mut GenericFile, pos: u64) -> Result<bool> {
+    let footer = read_footer(archive, pos)?;
+    archive.seek(SeekFrom::Start(pos + footer.len - 8))?;
+    Ok(u64::read_bin(archive)? == magic_number())
+}
+
+fn copy_prefix(archive: &mut GenericFile, out: &mut GenericFile, data_end: u64) -> Result<()> {
+    magic_number().write_bin(out)?;
+    archive.seek(SeekFrom::Start(8))?;
+    std::io::copy(&mut archive.by_ref().take(data_end - 8), out)?;
+    Ok(())
+}
+
+struct HashingSink {
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl Write for HashingSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
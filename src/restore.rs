@@ -1,30 +1,419 @@
 use crate::{
-    index::Index,
+    index::{
+        EntryKind, Index, SPECIAL_BLOCK_DEVICE, SPECIAL_CHAR_DEVICE, SPECIAL_FIFO, SPECIAL_SOCKET,
+    },
+    recompress::HashingReader,
     utils::{
-        GenericFile, blake3_hash_streaming, decrypt_and_decompress, decrypt_and_decompress_head,
+        ArchivePool, GenericFile, blake3_hash_streaming, decoding_reader, decrypt_and_decompress,
+        decrypt_and_decompress_head,
     },
 };
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use colored::*;
+use flate2::{Compression, write::GzEncoder};
+use glob::Pattern;
 use humansize::{DECIMAL, format_size};
 use indicatif::{ProgressBar, ProgressStyle};
+use log::warn;
+use nix::sys::stat::{Mode, SFlag, mknod};
 use std::{
+    collections::{HashMap, HashSet},
     fs,
-    io::{Seek, Write},
-    path::Path,
+    io::{Read, Seek, Write},
+    os::{fd::AsRawFd, unix::fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    time::Instant,
 };
 
+/// What `restore` writes to its output.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum RestoreFormat {
+    #[default]
+    Dir,
+    Tar,
+    TarGz,
+}
+
+impl RestoreFormat {
+    /// The format an output path asks for by its extension.
+    pub fn detect(to: &Path) -> Self {
+        let name = to.to_string_lossy();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            RestoreFormat::TarGz
+        } else if name.ends_with(".tar") {
+            RestoreFormat::Tar
+        } else {
+            RestoreFormat::Dir
+        }
+    }
+}
+
+/// How `restore_command` restores. The defaults are those of `restore` writing a directory.
+#[derive(Default)]
+pub struct RestoreSettings<'a> {
+    /// Skip files that exist with the right hash.
+    pub trust: bool,
+    pub reflink: bool,
+    /// Print a JSON line per restored file.
+    pub events: bool,
+    pub format: RestoreFormat,
+    pub dry_run: bool,
+    pub json: bool,
+    /// Restore the paths listed in this file instead of `from`.
+    pub paths_from: Option<&'a Path>,
+    pub skip_missing: bool,
+    pub manifest: Option<&'a Path>,
+    /// Keep setuid, setgid and sticky bits.
+    pub special_bits: bool,
+}
+
+/// Restores `from`, which is either a path in the archive or, if no such path exists,
+/// a glob pattern. Matches are restored with their full archive path below `to`.
+/// Setuid, setgid and sticky bits are only restored with `special_bits`.
 pub fn restore_command(
     archive: &mut GenericFile,
     from: &Path,
     to: &Path,
     ids: &Vec<Box<dyn age::Identity>>,
+    settings: &RestoreSettings,
+    pool: Option<&mut ArchivePool>,
+) -> Result<()> {
+    let &RestoreSettings {
+        trust,
+        reflink,
+        events,
+        format,
+        dry_run,
+        json,
+        paths_from,
+        skip_missing,
+        manifest,
+        special_bits,
+    } = settings;
+    let mut index = Index::parse(archive, ids)?;
+    if !special_bits {
+        index.strip_special_bits();
+    }
+    let mut from = from;
+    if let Some(list) = paths_from {
+        let paths = read_path_list(list, &index, skip_missing)?;
+        index = index.selected(&paths);
+        if index.mapping.is_empty() && index.empty_dirs.is_empty() {
+            return Err(anyhow!("None of the listed paths are in the archive"));
+        }
+        from = Path::new("");
+    } else if !index.is_file(from) && !index.is_dir(from) && is_pattern(from) {
+        let pattern = from.to_str().context("Pattern is not valid UTF-8")?;
+        index = index.matching(&Pattern::new(pattern)?);
+        if index.mapping.is_empty() && index.empty_dirs.is_empty() {
+            return Err(anyhow!("No paths match {pattern}"));
+        }
+        from = Path::new("");
+    }
+    if dry_run {
+        return print_dry_run(from, to, &index, trust, json);
+    }
+    match format {
+        RestoreFormat::Dir => {
+            restore_from_index(archive, from, to, &index, ids, trust, reflink, events, pool)?
+        }
+        RestoreFormat::Tar => {
+            let file = fs::File::create(to).context("Tarball could not be created")?;
+            write_tarball(archive, from, file, &index, ids)?;
+        }
+        RestoreFormat::TarGz => {
+            let file = fs::File::create(to).context("Tarball could not be created")?;
+            let gz = GzEncoder::new(file, Compression::default());
+            write_tarball(archive, from, gz, &index, ids)?.finish()?;
+        }
+    }
+    if let Some(manifest) = manifest {
+        write_manifest(manifest, from, &index)?;
+    }
+    Ok(())
+}
+
+/// Lists what restoring `from` to `to` would write, without decrypting anything.
+/// With `trust`, existing files are hashed to tell which ones would be skipped.
+fn print_dry_run(from: &Path, to: &Path, index: &Index, trust: bool, json: bool) -> Result<()> {
+    let plan = plan_restore(from, to, index, trust)?;
+    let (mut total, mut skipped) = (0, 0);
+    for (_, size, unchanged) in &plan {
+        if *unchanged {
+            skipped += 1;
+        } else {
+            total += size;
+        }
+    }
+    if json {
+        let files = plan
+            .iter()
+            .map(|(to_path, size, unchanged)| {
+                serde_json::json!({
+                    "path": to_path.to_string_lossy(),
+                    "size": size,
+                    "action": if *unchanged { "skip" } else { "restore" },
+                })
+            })
+            .collect::<Vec<_>>();
+        let report = serde_json::json!({
+            "files": files,
+            "restore_files": plan.len() - skipped,
+            "restore_bytes": total,
+            "skip_files": skipped,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    for (to_path, size, unchanged) in &plan {
+        if *unchanged {
+            println!(
+                "{:12} {} (unchanged, skipped)",
+                "-",
+                to_path.to_string_lossy()
+            );
+        } else {
+            println!(
+                "{:12} {}",
+                format_size(*size, DECIMAL),
+                to_path.to_string_lossy()
+            );
+        }
+    }
+    println!(
+        "{} files, {} to write ({} skipped)",
+        plan.len() - skipped,
+        format_size(total, DECIMAL),
+        skipped
+    );
+    Ok(())
+}
+
+/// The files restoring `from` to `to` would write, with their size and whether they
+/// would be skipped as unchanged, sorted by path.
+fn plan_restore(
+    from: &Path,
+    to: &Path,
+    index: &Index,
     trust: bool,
+) -> Result<Vec<(PathBuf, u64, bool)>> {
+    let mut paths = if index.is_file(from) {
+        vec![(from.to_path_buf(), to.to_path_buf())]
+    } else if index.is_dir(from) {
+        let mut paths = vec![];
+        for p in index.subindex(from)?.mapping.into_keys() {
+            let to_path = confined_join(to, &p)?;
+            paths.push((from.join(p), to_path));
+        }
+        paths
+    } else {
+        return Err(anyhow!("Path not found"));
+    };
+    paths.sort();
+    let mut plan = vec![];
+    for (from_path, to_path) in paths {
+        let size = index.du(&from_path)?;
+        let (_, _, hash_ref) = index.index_length_and_hash(&from_path)?;
+        let unchanged = trust
+            && to_path.exists()
+            && blake3_hash_streaming(&mut fs::File::open(&to_path)?)? == hash_ref;
+        plan.push((to_path, size, unchanged));
+    }
+    Ok(plan)
+}
+
+/// Writes `from` as a tar archive with paths relative to it. Besides the content, only the
+/// stored permission bits are kept. Without them files get mode 644 and directories 755,
+/// and all times are zero. Special files (except sockets) and symbolic links are included.
+/// Each file is streamed into the tarball with the size from the index, so a chunk that
+/// decodes to another size is an error.
+fn write_tarball<W: Write>(
+    archive: &mut GenericFile,
+    from: &Path,
+    out: W,
+    index: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<W> {
+    let mut builder = tar::Builder::new(out);
+    let (entries, modes) = if index.is_file(from) {
+        let name = PathBuf::from(from.file_name().context("File has no name")?);
+        let (offset, len, _) = index.index_length_and_hash(from)?;
+        let size = index.du(from)?;
+        let modes = index
+            .modes
+            .get(from)
+            .map(|m| HashMap::from([(name.clone(), *m)]))
+            .unwrap_or_default();
+        (vec![(name, EntryKind::File { size, offset, len })], modes)
+    } else if index.is_dir(from) {
+        let subindex = index.subindex(from)?;
+        let mut entries = subindex.entries()?;
+        // Directories that contain something only get an entry if they have a mode to keep.
+        let listed = entries
+            .iter()
+            .map(|(p, _)| p.clone())
+            .collect::<HashSet<_>>();
+        let dirs = subindex
+            .modes
+            .keys()
+            .filter(|p| !listed.contains(*p))
+            .map(|p| (p.clone(), EntryKind::Dir))
+            .collect::<Vec<_>>();
+        entries.extend(dirs);
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        (entries, subindex.modes)
+    } else {
+        return Err(anyhow!("Path not found"));
+    };
+    for (name, kind) in &entries {
+        let mut header = tar::Header::new_gnu();
+        let mode = modes.get(name).map(|m| *m as u32);
+        match kind {
+            EntryKind::File { size, offset, len } => {
+                archive.seek(std::io::SeekFrom::Start(*offset))?;
+                // One byte more than expected, to notice a chunk that is too long.
+                let mut content = HashingReader {
+                    inner: decoding_reader(archive, *len, ids)?.take(size + 1),
+                    hasher: blake3::Hasher::new(),
+                };
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(mode.unwrap_or(0o644));
+                header.set_size(*size);
+                builder.append_data(&mut header, name, (&mut content).take(*size))?;
+                // Tar pads short entries, so a short one would still look complete.
+                if content.hasher.count() != *size || content.read(&mut [0_u8; 1])? != 0 {
+                    return Err(anyhow!(
+                        "{} does not match its size in the index",
+                        name.to_string_lossy()
+                    ));
+                }
+            }
+            EntryKind::Dir => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(mode.unwrap_or(0o755));
+                header.set_size(0);
+                builder.append_data(&mut header, name, std::io::empty())?;
+            }
+            EntryKind::Special { kind, rdev } => {
+                let entry_type = match *kind {
+                    SPECIAL_FIFO => tar::EntryType::Fifo,
+                    SPECIAL_CHAR_DEVICE => tar::EntryType::Char,
+                    SPECIAL_BLOCK_DEVICE => tar::EntryType::Block,
+                    // Tar has no sockets.
+                    _ => continue,
+                };
+                header.set_entry_type(entry_type);
+                header.set_mode(0o644);
+                header.set_size(0);
+                header.set_device_major(libc::major(*rdev as libc::dev_t))?;
+                header.set_device_minor(libc::minor(*rdev as libc::dev_t))?;
+                builder.append_data(&mut header, name, std::io::empty())?;
+            }
+            EntryKind::Symlink { target } => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                builder.append_link(&mut header, name, target)?;
+            }
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Reads newline-separated archive paths from a file or stdin (`-`). Paths that are not in
+/// the archive are an error, or a warning with `skip_missing`.
+fn read_path_list(list: &Path, index: &Index, skip_missing: bool) -> Result<HashSet<PathBuf>> {
+    let content = if list == Path::new("-") {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        fs::read_to_string(list).context("Path list could not be read")?
+    };
+    let mut paths = HashSet::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let path = PathBuf::from(line);
+        if index.is_file(&path) || index.is_dir(&path) {
+            paths.insert(path);
+        } else if skip_missing {
+            warn!("{}:\n{}", "Not in the archive".yellow().bold(), line);
+        } else {
+            return Err(anyhow!("Not in the archive: {line}"));
+        }
+    }
+    Ok(paths)
+}
+
+fn is_pattern(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Writes a JSON list of the restored files with their size and stored hash.
+fn write_manifest(manifest: &Path, from: &Path, index: &Index) -> Result<()> {
+    let mut paths = if index.is_file(from) {
+        vec![from.to_path_buf()]
+    } else {
+        index
+            .subindex(from)?
+            .mapping
+            .into_keys()
+            .map(|p| from.join(p))
+            .collect()
+    };
+    paths.sort();
+    let entries = paths
+        .iter()
+        .map(|p| -> Result<serde_json::Value> {
+            let (_, _, hash) = index.index_length_and_hash(p)?;
+            Ok(serde_json::json!({
+                "path": p.to_string_lossy(),
+                "size": index.du(p)?,
+                "hash": blake3::Hash::from(hash).to_hex().to_string(),
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    fs::write(manifest, serde_json::to_string_pretty(&entries)?)
+        .context("Manifest could not be written")?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn restore_from_index(
+    archive: &mut GenericFile,
+    from: &Path,
+    to: &Path,
+    index: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+    trust: bool,
+    reflink: bool,
+    events: bool,
+    pool: Option<&mut ArchivePool>,
 ) -> Result<()> {
-    let index = Index::parse(archive, ids)?;
+    if to == Path::new("-") {
+        if !index.is_file(from) {
+            return Err(anyhow!("Only a single file can be restored to stdout"));
+        }
+        return stream_file(archive, from, &mut std::io::stdout().lock(), index, ids);
+    }
     if index.is_file(from) {
-        copy_file(archive, from, to, &index, ids)
+        let started = Instant::now();
+        copy_file(archive, from, to, index, ids)?;
+        // `to` may also be a FIFO or a device, whose permissions are left alone.
+        if let Some(mode) = index.modes.get(from) {
+            if fs::metadata(to).is_ok_and(|m| m.is_file()) {
+                apply_mode(to, *mode);
+            }
+        }
+        if events {
+            print_event(from, index, started);
+        }
+        Ok(())
     } else if index.is_dir(from) {
-        copy_directory(archive, from, to, &index, ids, trust)
+        copy_directory(archive, from, to, index, ids, trust, reflink, events, pool)
+    } else if let Some(target) = index.symlink_target(from) {
+        std::os::unix::fs::symlink(target, to).context("Symbolic link could not be created")
     } else {
         return Err(anyhow!("Path not found"));
     }
@@ -42,6 +431,45 @@ pub fn stream_file<W: Write>(
     decrypt_and_decompress(archive, to, len, ids)?;
     Ok(())
 }
+/// The content of a file as a reader, decoded while it is read.
+pub fn open_file<'a>(
+    archive: &'a mut GenericFile,
+    path: &Path,
+    index: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<Box<dyn Read + 'a>> {
+    let (i, len, _) = index.index_length_and_hash(path)?;
+    archive.seek(std::io::SeekFrom::Start(i))?;
+    decoding_reader(archive, len, ids)
+}
+pub fn page_file(
+    archive: &mut GenericFile,
+    path: &Path,
+    index: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<()> {
+    page(&mut open_file(archive, path, index, ids)?)
+}
+/// Shows `content` through $PAGER (or less).
+pub fn page<R: Read>(content: &mut R) -> Result<()> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&pager)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Pager could not be started")?;
+    let mut stdin = child.stdin.take().context("Pager has no stdin")?;
+    let result = std::io::copy(content, &mut stdin);
+    drop(stdin);
+    child.wait()?;
+    match result {
+        Ok(_) => Ok(()),
+        // The pager was closed before the whole file was written.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
 pub fn stream_file_head<W: Write>(
     archive: &mut GenericFile,
     from: &Path,
@@ -55,6 +483,7 @@ pub fn stream_file_head<W: Write>(
     decrypt_and_decompress_head(archive, to, len, write_only, ids)?;
     Ok(())
 }
+/// The output is only written sequentially, so `to` can also be a FIFO or a device.
 pub fn copy_file(
     archive: &mut GenericFile,
     from: &Path,
@@ -66,6 +495,7 @@ pub fn copy_file(
     stream_file(archive, from, &mut file, index, ids)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn copy_directory(
     archive: &mut GenericFile,
     from: &Path,
@@ -73,33 +503,128 @@ fn copy_directory(
     index: &Index,
     ids: &Vec<Box<dyn age::Identity>>,
     trust: bool,
+    reflink: bool,
+    events: bool,
+    pool: Option<&mut ArchivePool>,
 ) -> Result<()> {
     let subindex = index.subindex(from)?;
-    let children = subindex.mapping.keys().collect::<Vec<_>>();
-    let pb = ProgressBar::new(children.len() as u64);
-    pb.set_style(ProgressStyle::with_template("{bar:40} {pos:>7}/{len:7}\nfile: {msg}").unwrap());
+    // Hard links are created once the files they link to are written.
+    let links = subindex
+        .hardlinks
+        .iter()
+        .map(|(link, _)| link)
+        .collect::<HashSet<_>>();
+    let children = subindex
+        .mapping
+        .keys()
+        .filter(|c| !links.contains(c))
+        .collect::<Vec<_>>();
+    // Events replace the progress bar as the output for automation.
+    let pb = if events {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(children.len() as u64)
+    };
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{bar:40} {pos:>7}/{len:7} [{elapsed_precise}, eta {eta}]\nfile: {msg}",
+        )
+        .unwrap(),
+    );
+    // With a pool, the files are only collected here and fetched concurrently afterwards.
+    let mut pending = vec![];
+    // With reflink, only the first file of each chunk is decrypted, the others are cloned from it.
+    let mut written: HashMap<u64, PathBuf> = HashMap::new();
+    let mut clones = vec![];
 
     for (i, c) in children.iter().enumerate() {
         let from_path = from.join(c);
         pb.set_position(i as u64);
-        let (_, size, hash_ref) = index.index_length_and_hash(&from_path)?;
+        let (chunk, size, hash_ref) = index.index_length_and_hash(&from_path)?;
         pb.set_message(format!(
             "{} ({})",
             &c.to_string_lossy(),
             format_size(size, DECIMAL)
         ));
 
-        let to_path = to.join(c);
+        let to_path = confined_join(to, c)?;
         if trust && to_path.exists() {
             let hash_disk = blake3_hash_streaming(&mut fs::File::open(&to_path)?)?;
             if hash_ref == hash_disk {
+                if reflink {
+                    written.entry(chunk).or_insert(to_path);
+                }
                 continue;
             }
         }
         if let Some(parent) = to_path.parent() {
             fs::create_dir_all(parent)?;
         }
-        copy_file(archive, &from_path, &to_path, index, ids)?;
+        if reflink {
+            if let Some(source) = written.get(&chunk) {
+                clones.push((source.clone(), to_path, from_path));
+                continue;
+            }
+            written.insert(chunk, to_path.clone());
+        }
+        if pool.is_some() {
+            pending.push((from_path, to_path));
+        } else {
+            let started = Instant::now();
+            copy_file(archive, &from_path, &to_path, index, ids)?;
+            if events {
+                print_event(&from_path, index, started);
+            }
+        }
+    }
+    if let Some(pool) = pool {
+        let chunks = pending
+            .iter()
+            .map(|(f, _)| index.index(f).context("File not in index"))
+            .collect::<Result<Vec<_>>>()?;
+        pb.set_length(pending.len() as u64);
+        pb.set_position(0);
+        pool.for_each_chunk(&chunks, |j, chunk| {
+            let (from_path, to_path) = &pending[j];
+            pb.set_message(from_path.to_string_lossy().to_string());
+            let started = Instant::now();
+            let mut file = fs::File::create(to_path)?;
+            let mut source = chunk;
+            decrypt_and_decompress(&mut source, &mut file, chunk.len() as u64, ids)?;
+            if events {
+                print_event(from_path, index, started);
+            }
+            pb.inc(1);
+            Ok(())
+        })?;
+    }
+    for (source, target, from_path) in &clones {
+        let started = Instant::now();
+        clone_file(source, target)?;
+        if events {
+            print_event(from_path, index, started);
+        }
+    }
+    for (link, target) in &subindex.hardlinks {
+        let link_path = confined_join(to, link)?;
+        let target_path = confined_join(to, target)?;
+        if let Some(parent) = link_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if link_path.exists() {
+            fs::remove_file(&link_path)?;
+        }
+        if let Err(e) = fs::hard_link(&target_path, &link_path) {
+            warn!(
+                "{}:\n{} ({})",
+                "Could not create hard link, copying instead"
+                    .yellow()
+                    .bold(),
+                link_path.to_string_lossy(),
+                e
+            );
+            fs::copy(&target_path, &link_path)?;
+        }
     }
     pb.finish_and_clear();
     let empties = index
@@ -110,8 +635,374 @@ fn copy_directory(
         .collect::<std::result::Result<Vec<_>, _>>()?;
 
     for e in empties {
-        let to_path = to.join(e);
+        let to_path = confined_join(to, e)?;
         fs::create_dir_all(to_path)?;
     }
+    for (path, (kind, rdev)) in &index.special_files {
+        let Ok(relative) = path.strip_prefix(from) else {
+            continue;
+        };
+        let to_path = confined_join(to, relative)?;
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(e) = make_special(&to_path, *kind, *rdev) {
+            warn!(
+                "{}:\n{} ({})",
+                "Could not create special file".yellow().bold(),
+                to_path.to_string_lossy(),
+                e
+            );
+        }
+    }
+    for (path, target) in &index.symlinks {
+        let Ok(relative) = path.strip_prefix(from) else {
+            continue;
+        };
+        let to_path = confined_join(to, relative)?;
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(e) = std::os::unix::fs::symlink(target, &to_path) {
+            warn!(
+                "{}:\n{} ({})",
+                "Could not create symbolic link".yellow().bold(),
+                to_path.to_string_lossy(),
+                e
+            );
+        }
+    }
+    // Deepest paths first, so directories without write permission are restricted last.
+    let mut modes = subindex.modes.iter().collect::<Vec<_>>();
+    modes.sort();
+    for (path, mode) in modes.into_iter().rev() {
+        apply_mode(&confined_join(to, path)?, *mode);
+    }
+    Ok(())
+}
+
+/// Sets the stored permission bits of a restored path. Failing to do so is only a warning.
+fn apply_mode(path: &Path, mode: u64) {
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode as u32)) {
+        warn!(
+            "{}:\n{} ({})",
+            "Could not set permissions".yellow().bold(),
+            path.to_string_lossy(),
+            e
+        );
+    }
+}
+
+/// Prints a JSON line for a restored file.
+fn print_event(path: &Path, index: &Index, started: Instant) {
+    println!(
+        "{}",
+        serde_json::json!({
+            "path": path.to_string_lossy(),
+            "size": index.du(path).unwrap_or(0),
+            "duration_ms": started.elapsed().as_millis() as u64,
+        })
+    );
+}
+
+/// Copies a file using copy-on-write cloning (`FICLONE`), or a normal copy where the
+/// filesystem does not support it.
+fn clone_file(source: &Path, target: &Path) -> Result<()> {
+    let src = fs::File::open(source)?;
+    let dst = fs::File::create(target)?;
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret != 0 {
+        drop(dst);
+        fs::copy(source, target)?;
+    }
     Ok(())
 }
+
+fn make_special(path: &Path, kind: u64, rdev: u64) -> Result<()> {
+    let kind = match kind {
+        SPECIAL_FIFO => SFlag::S_IFIFO,
+        SPECIAL_CHAR_DEVICE => SFlag::S_IFCHR,
+        SPECIAL_BLOCK_DEVICE => SFlag::S_IFBLK,
+        SPECIAL_SOCKET => SFlag::S_IFSOCK,
+        _ => return Err(anyhow!("Unknown special file type")),
+    };
+    mknod(
+        path,
+        kind,
+        Mode::from_bits_truncate(0o644),
+        rdev as libc::dev_t,
+    )?;
+    Ok(())
+}
+
+/// Joins a path from the archive onto the restore target.
+/// Stored paths that would escape the target (`..`, absolute paths) are rejected.
+fn confined_join(to: &Path, relative: &Path) -> Result<PathBuf> {
+    let escapes = relative.components().any(|c| {
+        matches!(
+            c,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_)
+        )
+    });
+    if escapes {
+        return Err(anyhow!(
+            "Refusing to restore {} outside of the target directory",
+            relative.to_string_lossy()
+        ));
+    }
+    Ok(to.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archiver::BuildSettings;
+    use crate::index::BASE_CHUNK;
+    use crate::testutil::{
+        create_archive, create_archive_with, open_archive, replace_index, scratch_dir,
+        test_settings, write_files,
+    };
+    use crate::utils::Codec;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn refuse_paths_outside_the_target() {
+        let dir = scratch_dir("traversal");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"a"), ("sub/b.txt", b"b")]);
+        let archive = dir.join("archive.zprt");
+        let original = create_archive(&source, &archive, &[]).unwrap();
+        let escape = dir.join("escape.txt");
+        for crafted in ["sub/../../escape.txt", escape.to_str().unwrap()] {
+            let mut index = original.clone();
+            let chunk = index.mapping.remove(Path::new("sub/b.txt")).unwrap();
+            index.mapping.insert(PathBuf::from(crafted), chunk);
+            replace_index(&archive, &index).unwrap();
+            let index = Index::parse(&mut open_archive(&archive), &vec![]).unwrap();
+            let to = dir.join("restored");
+            let error = restore_from_index(
+                &mut open_archive(&archive),
+                Path::new(""),
+                &to,
+                &index,
+                &vec![],
+                false,
+                false,
+                true,
+                None,
+            )
+            .unwrap_err();
+            assert!(format!("{error:#}").contains("outside of the target directory"));
+            assert!(!escape.exists());
+        }
+    }
+
+    #[test]
+    fn glob_restore_keeps_empty_directories() {
+        let dir = scratch_dir("glob-empty-dirs");
+        let source = dir.join("source");
+        write_files(
+            &source,
+            &[
+                ("photos/2020/a.jpg", b"a"),
+                ("photos/2020/b.txt", b"b"),
+                ("photos/notes/n.txt", b"n"),
+                ("other/x.jpg", b"x"),
+            ],
+        );
+        fs::create_dir_all(source.join("photos/empty")).unwrap();
+        let archive = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive, &[]).unwrap();
+        let matched = index.matching(&Pattern::new("photos/*/*.jpg").unwrap());
+        let to = dir.join("restored");
+        restore_from_index(
+            &mut open_archive(&archive),
+            Path::new(""),
+            &to,
+            &matched,
+            &vec![],
+            false,
+            false,
+            true,
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read(to.join("photos/2020/a.jpg")).unwrap(), b"a");
+        assert!(!to.join("photos/2020/b.txt").exists());
+        // Empty in the archive, and empty after filtering.
+        assert!(to.join("photos/empty").is_dir());
+        assert!(to.join("photos/notes").is_dir());
+        assert!(!to.join("photos/notes/n.txt").exists());
+        assert!(!to.join("other").exists());
+    }
+
+    #[test]
+    fn restore_symlinks() {
+        let dir = scratch_dir("symlinks");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello")]);
+        std::os::unix::fs::symlink("a.txt", source.join("link")).unwrap();
+        std::os::unix::fs::symlink("../missing", source.join("dangling")).unwrap();
+        let archive = dir.join("archive.zprt");
+        let index = create_archive(&source, &archive, &[]).unwrap();
+        assert!(!index.is_file(Path::new("link")));
+        let to = dir.join("restored");
+        restore_command(
+            &mut open_archive(&archive),
+            Path::new(""),
+            &to,
+            &vec![],
+            &RestoreSettings::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("a.txt"));
+        assert_eq!(fs::read(to.join("link")).unwrap(), b"hello");
+        assert_eq!(
+            fs::read_link(to.join("dangling")).unwrap(),
+            Path::new("../missing")
+        );
+    }
+
+    #[test]
+    fn restore_hardlinks() {
+        let dir = scratch_dir("hardlinks");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"hello"), ("copy.txt", b"hello")]);
+        fs::hard_link(source.join("a.txt"), source.join("sub-link.txt")).unwrap();
+        let archive = dir.join("archive.zprt");
+        let settings = BuildSettings {
+            preserve_hardlinks: true,
+            ..test_settings()
+        };
+        let index = create_archive_with(&source, &archive, &settings).unwrap();
+        assert_eq!(index.hardlinks.len(), 1);
+        let to = dir.join("restored");
+        restore_command(
+            &mut open_archive(&archive),
+            Path::new(""),
+            &to,
+            &vec![],
+            &RestoreSettings::default(),
+            None,
+        )
+        .unwrap();
+        let inode = |name: &str| fs::metadata(to.join(name)).unwrap().ino();
+        assert_eq!(inode("a.txt"), inode("sub-link.txt"));
+        // Same content, but not a link in the source.
+        assert_ne!(inode("a.txt"), inode("copy.txt"));
+        assert_eq!(fs::read(to.join("sub-link.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn restore_a_delta_archive() {
+        let dir = scratch_dir("delta");
+        let source = dir.join("source");
+        write_files(&source, &[("a.txt", b"unchanged"), ("b.txt", b"old")]);
+        let base_archive = dir.join("base.zprt");
+        let base = create_archive(&source, &base_archive, &[]).unwrap();
+        write_files(&source, &[("b.txt", b"new"), ("c.txt", b"added")]);
+        let delta_archive = dir.join("delta.zprt");
+        let settings = BuildSettings {
+            base: Some(&base),
+            ..test_settings()
+        };
+        let delta = create_archive_with(&source, &delta_archive, &settings).unwrap();
+        assert_ne!(delta.mapping[Path::new("a.txt")].0 & BASE_CHUNK, 0);
+        assert_eq!(delta.mapping[Path::new("b.txt")].0 & BASE_CHUNK, 0);
+        assert_eq!(delta.mapping[Path::new("c.txt")].0 & BASE_CHUNK, 0);
+
+        let to = dir.join("restored");
+        let mut archive = open_archive(&delta_archive).with_base(open_archive(&base_archive));
+        restore_command(
+            &mut archive,
+            Path::new(""),
+            &to,
+            &vec![],
+            &RestoreSettings::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read(to.join("a.txt")).unwrap(), b"unchanged");
+        assert_eq!(fs::read(to.join("b.txt")).unwrap(), b"new");
+        assert_eq!(fs::read(to.join("c.txt")).unwrap(), b"added");
+
+        // Without the base, the unchanged file can not be read.
+        let error = restore_command(
+            &mut open_archive(&delta_archive),
+            Path::new("a.txt"),
+            &dir.join("without-base.txt"),
+            &vec![],
+            &RestoreSettings::default(),
+            None,
+        )
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("use --base"), "{error:#}");
+    }
+
+    #[test]
+    fn restore_every_codec() {
+        let dir = scratch_dir("codecs");
+        let source = dir.join("source");
+        let text = b"compressible text ".repeat(100);
+        write_files(&source, &[("a.txt", &text), ("empty.txt", b"")]);
+        let heads: [(Codec, &[u8]); 3] = [
+            (Codec::Zstd, &[0x28, 0xB5, 0x2F, 0xFD]),
+            (Codec::Gzip, &[0x1F, 0x8B]),
+            (Codec::None, b"ZPS\0"),
+        ];
+        for (codec, head) in heads {
+            let archive = dir.join(format!("{codec:?}.zprt"));
+            let settings = BuildSettings {
+                codec,
+                ..test_settings()
+            };
+            let index = create_archive_with(&source, &archive, &settings).unwrap();
+            // Chunks are read back by their first bytes, not by a setting in the index.
+            let content = fs::read(&archive).unwrap();
+            let (offset, _) = index.mapping[Path::new("a.txt")];
+            assert!(content[offset as usize..].starts_with(head), "{codec:?}");
+            let to = dir.join(format!("restored-{codec:?}"));
+            restore_command(
+                &mut open_archive(&archive),
+                Path::new(""),
+                &to,
+                &vec![],
+                &RestoreSettings::default(),
+                None,
+            )
+            .unwrap();
+            assert_eq!(fs::read(to.join("a.txt")).unwrap(), text);
+            assert_eq!(fs::read(to.join("empty.txt")).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn special_bits_only_on_request() {
+        let dir = scratch_dir("special-bits");
+        let source = dir.join("source");
+        write_files(&source, &[("tool", b"#!/bin/sh\n")]);
+        fs::set_permissions(source.join("tool"), fs::Permissions::from_mode(0o4755)).unwrap();
+        let archive = dir.join("archive.zprt");
+        create_archive(&source, &archive, &[]).unwrap();
+        for (special_bits, expected) in [(false, 0o755), (true, 0o4755)] {
+            let to = dir.join(format!("restored-{special_bits}"));
+            let settings = RestoreSettings {
+                events: true,
+                special_bits,
+                ..Default::default()
+            };
+            restore_command(
+                &mut open_archive(&archive),
+                Path::new(""),
+                &to,
+                &vec![],
+                &settings,
+                None,
+            )
+            .unwrap();
+            let mode = fs::metadata(to.join("tool")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, expected);
+        }
+    }
+}
@@ -5,12 +5,11 @@ use std::{
     path::PathBuf,
 };
 
-use crate::index::Index;
+use crate::index::{DEDUP_CONTENT, Index};
 
 pub trait SimpleBinRepr: Sized {
     fn read_bin<R: Read>(reader: &mut R) -> Result<Self>;
     fn write_bin<W: Write>(&self, writer: &mut W) -> Result<()>;
-    #[allow(unused)]
     fn simple_bin_vec(&self) -> Result<Vec<u8>> {
         let mut buffer = vec![];
         self.write_bin(&mut buffer)?;
@@ -118,6 +117,12 @@ impl SimpleBinRepr for Index {
         let hash_indices: Vec<u64> = Vec::read_bin(reader)?;
         let hashes: Vec<[u8; 32]> = Vec::read_bin(reader)?;
         let sizes: Vec<u64> = Vec::read_bin(reader)?;
+        let special_files = read_optional(reader)?.unwrap_or_default();
+        let dedup_mode = read_optional(reader)?.unwrap_or(DEDUP_CONTENT);
+        let metadata = read_optional(reader)?.unwrap_or_default();
+        let modes: Vec<(PathBuf, u64)> = read_optional(reader)?.unwrap_or_default();
+        let symlinks = read_optional(reader)?.unwrap_or_default();
+        let hardlinks = read_optional(reader)?.unwrap_or_default();
 
         if hash_indices.len() != hashes.len() {
             return Err(anyhow!("Malformed index"));
@@ -140,6 +145,12 @@ impl SimpleBinRepr for Index {
             mapping: hm_mapping,
             magic_number,
             empty_dirs,
+            special_files,
+            dedup_mode,
+            metadata,
+            modes: modes.into_iter().collect(),
+            symlinks,
+            hardlinks,
         })
     }
 
@@ -149,7 +160,12 @@ impl SimpleBinRepr for Index {
         let mut hashes = vec![];
         let mut sizes = vec![];
         let mut maps = vec![];
-        for (hi, hash) in &self.hashes {
+        // Sorted, so the same index is always written the same way.
+        let mut chunk_hashes: Vec<_> = self.hashes.iter().collect();
+        chunk_hashes.sort();
+        let mut files: Vec<_> = self.mapping.iter().collect();
+        files.sort();
+        for (hi, hash) in chunk_hashes {
             hash_indices.push(*hi);
             let size = self
                 .sizes
@@ -158,7 +174,7 @@ impl SimpleBinRepr for Index {
             sizes.push(*size);
             hashes.push(*hash);
         }
-        for (path, mi) in &self.mapping {
+        for (path, mi) in files {
             map_indices.push(*mi);
             maps.push(path.clone());
         }
@@ -168,10 +184,30 @@ impl SimpleBinRepr for Index {
         self.empty_dirs.write_bin(writer)?;
         hash_indices.write_bin(writer)?;
         hashes.write_bin(writer)?;
-        sizes.write_bin(writer)
+        sizes.write_bin(writer)?;
+        self.special_files.write_bin(writer)?;
+        self.dedup_mode.write_bin(writer)?;
+        self.metadata.write_bin(writer)?;
+        let mut modes: Vec<(PathBuf, u64)> =
+            self.modes.iter().map(|(p, m)| (p.clone(), *m)).collect();
+        modes.sort();
+        modes.write_bin(writer)?;
+        self.symlinks.write_bin(writer)?;
+        self.hardlinks.write_bin(writer)
     }
 }
 
+/// Reads an optional trailing section. Sections added to the format later are optional,
+/// so older indices simply end before them.
+pub(crate) fn read_optional<R: Read, B: SimpleBinRepr>(reader: &mut R) -> Result<Option<B>> {
+    let mut first = [0_u8; 1];
+    if reader.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let mut chained = first.as_slice().chain(reader);
+    Ok(Some(B::read_bin(&mut chained)?))
+}
+
 fn read_bytes_const<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
     let mut buffer = [0_u8; N];
     reader.read_exact(&mut buffer)?;
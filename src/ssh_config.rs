@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use glob::Pattern;
+use std::path::PathBuf;
+
+/// What `~/.ssh/config` says about a host. Unset values are None.
+#[derive(Default, Debug)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<PathBuf>,
+}
+
+/// Looks up `alias` in `~/.ssh/config`. Like ssh, the first value found for each keyword
+/// wins. Only `Host` blocks are understood; `Match` blocks and `Include` are ignored.
+pub fn lookup(alias: &str) -> Result<HostConfig> {
+    let Some(path) = dirs::home_dir().map(|h| h.join(".ssh").join("config")) else {
+        return Ok(HostConfig::default());
+    };
+    if !path.exists() {
+        return Ok(HostConfig::default());
+    }
+    let content = std::fs::read_to_string(&path).context("~/.ssh/config could not be read")?;
+    parse(&content, alias)
+}
+
+fn parse(content: &str, alias: &str) -> Result<HostConfig> {
+    let mut config = HostConfig::default();
+    // Lines before the first Host apply to all hosts.
+    let mut active = true;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, value) = line
+            .split_once(|c: char| c.is_whitespace() || c == '=')
+            .map(|(k, v)| {
+                (
+                    k,
+                    v.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                )
+            })
+            .unwrap_or((line, ""));
+        let value = value.trim().trim_matches('"');
+        match keyword.to_lowercase().as_str() {
+            "host" => active = host_matches(value, alias),
+            "match" => active = false,
+            _ if !active => {}
+            "hostname" if config.host_name.is_none() => {
+                config.host_name = Some(value.replace("%h", alias))
+            }
+            "user" if config.user.is_none() => config.user = Some(value.to_string()),
+            "port" if config.port.is_none() => {
+                config.port = Some(value.parse().context("Invalid Port in ~/.ssh/config")?)
+            }
+            "identityfile" if config.identity_file.is_none() => {
+                config.identity_file = Some(expand_home(value))
+            }
+            _ => {}
+        }
+    }
+    Ok(config)
+}
+
+/// Whether the patterns of a `Host` line match `alias`. A matching negated pattern (`!name`)
+/// rules the block out.
+fn host_matches(patterns: &str, alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        if Pattern::new(pattern).is_ok_and(|p| p.matches(alias)) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
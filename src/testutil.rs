@@ -0,0 +1,89 @@
+//! Helpers for the tests: scratch directories and small unencrypted archives.
+
+use crate::{
+    archiver::{BuildSettings, SourceTree, build_archive, build_excludes, write_index},
+    index::{Index, locate_footer, read_footer},
+    utils::GenericFile,
+};
+use anyhow::Result;
+use std::{
+    fs,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// An empty directory below the system temp directory, unique per test name and process.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zipurat-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("scratch directory could not be created");
+    dir
+}
+
+/// Writes files (and their parent directories) below `root`.
+pub fn write_files(root: &Path, files: &[(&str, &[u8])]) {
+    for (path, content) in files {
+        let path = root.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, content).unwrap();
+    }
+}
+
+/// Archives `source` into `archive` without encryption, on one thread and in sorted order.
+pub fn create_archive(source: &Path, archive: &Path, excludes: &[&str]) -> Result<Index> {
+    let excludes = build_excludes(&excludes.iter().map(|e| e.to_string()).collect::<Vec<_>>())?;
+    create_archive_with(
+        source,
+        archive,
+        &BuildSettings {
+            excludes,
+            ..test_settings()
+        },
+    )
+}
+
+/// The settings of `create_archive`, to be adjusted by tests of other options.
+pub fn test_settings() -> BuildSettings<'static> {
+    BuildSettings {
+        deterministic: true,
+        index_level: 3,
+        ..Default::default()
+    }
+}
+
+/// Like `create_archive`, with the given settings.
+pub fn create_archive_with(
+    source: &Path,
+    archive: &Path,
+    settings: &BuildSettings,
+) -> Result<Index> {
+    let mut file = GenericFile::Local(fs::File::create(archive)?);
+    build_archive(
+        &SourceTree::Local(source.to_path_buf()),
+        &mut file,
+        &vec![],
+        settings,
+    )
+}
+
+/// Opens an archive written by `create_archive` for reading.
+pub fn open_archive(archive: &Path) -> GenericFile {
+    GenericFile::Local(fs::File::open(archive).expect("archive could not be opened"))
+}
+
+/// Writes `index` in place of the index of an archive written by `create_archive`, for
+/// tests with tampered indices.
+pub fn replace_index(archive: &Path, index: &Index) -> Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(archive)?;
+    let mut file = GenericFile::Local(file);
+    let footer = locate_footer(&mut file)?;
+    let index_start = footer - read_footer(&mut file, footer)?.index_len;
+    file.set_len(index_start)?;
+    file.seek(SeekFrom::Start(index_start))?;
+    write_index(index, &mut file, 3, &vec![])
+}
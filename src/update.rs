@@ -0,0 +1,223 @@
+use crate::{
+    archiver::{
+        SourceTree, dir_modes, files_equal, list_all_empty_dirs, list_all_files_recursive,
+        write_index,
+    },
+    index::{DEDUP_CONTENT, DEDUP_NONE, Index},
+    recompress::HashingReader,
+    utils::{Codec, GenericFile, blake3_hash_streaming, compress_and_encrypt, decoding_reader},
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use colored::*;
+use globset::GlobSet;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha20Rng;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Brings an archive up to date with its source directory, in place. Files whose path and
+/// hash are unchanged keep their chunks, and so do new files with the hash of a stored one
+/// (unless the archive was created with `--no-dedup`). The dedup mode of the archive is
+/// kept: with `DEDUP_CONTENT`, the stored chunk is compared with the file first. Everything
+/// else is compressed and appended. Deleted files are dropped from the index, their chunks
+/// stay as dead space.
+///
+/// The new chunks and the new index are written behind the old end of the archive, and
+/// the index and footer with a single write at the very end. Until then, the old footer is
+/// the last one in the file. The caller cuts the archive back to its old length if
+/// anything fails.
+#[allow(clippy::too_many_arguments)]
+pub fn update_archive(
+    source: &Path,
+    archive: &mut GenericFile,
+    old: &Index,
+    ids: &Vec<Box<dyn age::Identity>>,
+    recipients: &Vec<Box<dyn age::Recipient + Send>>,
+    level: i32,
+    index_level: i32,
+    skip_errors: bool,
+    preserve_special: bool,
+    no_hidden: bool,
+    excludes: &GlobSet,
+    dereference: bool,
+) -> Result<Index> {
+    let mut pruned = 0;
+    let (mut file_list, specials, symlinks) =
+        list_all_files_recursive(source, no_hidden, excludes, dereference, &mut pruned)
+            .context("Directory could not be listed")?;
+    let empty_dirs = list_all_empty_dirs(source, no_hidden, excludes, dereference)
+        .context("Directory could not be listed")?;
+    if !excludes.is_empty() {
+        info!("{} files excluded", pruned);
+    }
+    let tree = SourceTree::Local(source.to_path_buf());
+    let mut modes = dir_modes(&tree, &file_list, &empty_dirs, &symlinks);
+    file_list.shuffle(&mut ChaCha20Rng::from_os_rng());
+
+    // Stored chunks by hash, with their size.
+    let mut by_hash = HashMap::new();
+    for (i, len) in old.mapping.values() {
+        if let (Some(hash), Some(size)) = (old.hashes.get(i), old.sizes.get(i)) {
+            by_hash.insert(*hash, ((*i, *len), *size));
+        }
+    }
+    let dedup = old.dedup_mode != DEDUP_NONE;
+    // New chunks use the codec the archive was created with.
+    let codec = old
+        .metadata
+        .iter()
+        .find(|(key, _)| key == "codec")
+        .and_then(|(_, name)| Codec::from_str(name, true).ok())
+        .unwrap_or(Codec::Zstd);
+
+    let mut mapping = HashMap::new();
+    let mut hashes = HashMap::new();
+    let mut sizes = HashMap::new();
+    let (mut unchanged, mut added, mut changed, mut skipped) = (0, 0, 0, 0);
+    let mut current_index = archive.seek(SeekFrom::End(0))?;
+    let pb = ProgressBar::new(file_list.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{bar:40} {pos:>7}/{len:7} [{elapsed_precise}, eta {eta}]\nfile: {msg}",
+        )
+        .context("Progress bar error")?,
+    );
+    for (n, in_path) in file_list.iter().enumerate() {
+        pb.set_position(n as u64);
+        pb.set_message(in_path.to_string_lossy().to_string());
+        let previous = old.mapping.get(in_path).copied();
+        let hashed = tree.file_len(in_path).and_then(|(size, mode)| {
+            Ok((size, mode, blake3_hash_streaming(&mut tree.open(in_path)?)?))
+        });
+        let (size, mode, hash) = match hashed {
+            Ok(hashed) => hashed,
+            Err(e) if skip_errors => {
+                pb.suspend(|| {
+                    warn!(
+                        "{}:\n{} ({})",
+                        "Skipping unreadable file".yellow().bold(),
+                        in_path.to_string_lossy(),
+                        e
+                    )
+                });
+                // What was archived before is kept.
+                if let Some((i, len)) = previous {
+                    mapping.insert(in_path.clone(), (i, len));
+                    hashes.insert(i, old.hashes[&i]);
+                    sizes.insert(i, old.sizes[&i]);
+                }
+                skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(mode) = mode {
+            modes.insert(in_path.clone(), mode);
+        }
+        let ((i, len), hash, size) = match previous
+            .filter(|(i, _)| old.hashes.get(i) == Some(&hash))
+        {
+            Some(chunk) => {
+                unchanged += 1;
+                (chunk, hash, old.sizes[&chunk.0])
+            }
+            None => {
+                if previous.is_some() {
+                    changed += 1;
+                } else {
+                    added += 1;
+                }
+                let stored = by_hash
+                    .get(&hash)
+                    .filter(|(_, stored)| dedup && *stored == size)
+                    .map(|(chunk, _)| *chunk);
+                let same = match stored {
+                    Some((i, len)) if old.dedup_mode == DEDUP_CONTENT => {
+                        archive.seek(SeekFrom::Start(i))?;
+                        files_equal(tree.open(in_path)?, decoding_reader(archive, len, ids)?)?
+                    }
+                    Some(_) => true,
+                    None => false,
+                };
+                match stored {
+                    Some(chunk) if same => (chunk, hash, size),
+                    _ => {
+                        archive.seek(SeekFrom::Start(current_index))?;
+                        let mut reader = HashingReader {
+                            inner: tree.open(in_path)?,
+                            hasher: blake3::Hasher::new(),
+                        };
+                        compress_and_encrypt(&mut reader, archive, codec, level, None, recipients)?;
+                        let end = archive.stream_position()?;
+                        let chunk = (current_index, end - current_index);
+                        current_index = end;
+                        // The index describes what was written.
+                        let written = *reader.hasher.finalize().as_bytes();
+                        let size = reader.hasher.count();
+                        if written != hash {
+                            pb.suspend(|| {
+                                warn!(
+                                    "{}:\n{}",
+                                    "File changed while it was archived".yellow().bold(),
+                                    in_path.to_string_lossy()
+                                )
+                            });
+                        }
+                        by_hash.insert(written, (chunk, size));
+                        (chunk, written, size)
+                    }
+                }
+            }
+        };
+        mapping.insert(in_path.clone(), (i, len));
+        hashes.insert(i, hash);
+        sizes.insert(i, size);
+    }
+    pb.finish_and_clear();
+    let removed = old
+        .mapping
+        .keys()
+        .filter(|p| !mapping.contains_key(*p))
+        .count();
+    // Links stay links as long as both files still share their chunk.
+    let hardlinks = old
+        .hardlinks
+        .iter()
+        .filter(|(p, t)| mapping.get(p).is_some_and(|m| mapping.get(t) == Some(m)))
+        .cloned()
+        .collect();
+    let mut index = Index {
+        mapping,
+        hashes,
+        sizes,
+        magic_number: old.magic_number,
+        empty_dirs,
+        special_files: if preserve_special { specials } else { vec![] },
+        dedup_mode: old.dedup_mode,
+        metadata: old.metadata.clone(),
+        modes,
+        symlinks,
+        hardlinks,
+    };
+    index.prune_modes();
+
+    let mut tail = Cursor::new(vec![]);
+    write_index(&index, &mut tail, index_level, recipients)?;
+    archive.write_all(tail.get_ref())?;
+    archive.flush()?;
+    info!(
+        "{} files unchanged, {} added, {} changed, {} removed",
+        unchanged, added, changed, removed
+    );
+    if skipped > 0 {
+        warn!("{}: {} files", "Skipped".yellow().bold(), skipped);
+    }
+    Ok(index)
+}
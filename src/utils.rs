@@ -1,22 +1,52 @@
 use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use flate2::{Compression, read::GzEncoder, read::MultiGzDecoder};
 use std::{
-    io::{Read, Seek, Write},
+    io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
     net::TcpStream,
-    path::Path,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 use zstd::stream::read::{Decoder, Encoder};
 
+use crate::index::{BASE_CHUNK, LAYER_SHIFT};
+use crate::volumes::{Volumes, volume_path};
+
+const ALREADY_EXISTS: &str = "Archive already exists (use --force to overwrite)";
+
+/// Largest zstd window we accept when decoding (needed for `--long` archives).
+/// Decoding a file needs as much memory as the window it was written with.
+pub const WINDOW_LOG_MAX: u32 = 31;
+
+/// The first bytes of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// How the content of a chunk is compressed, chosen with `create --codec`.
+/// Chunks describe themselves by their first bytes, so archives can mix codecs.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    /// Levels above 9 are treated as 9.
+    Gzip,
+    /// Stored as is, behind `STORED_MAGIC`.
+    None,
+}
+
+/// The first bytes of every gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Marks a chunk that is stored without compression (`--codec none`). It cannot be
+/// confused with the start of a zstd frame or a gzip member.
+const STORED_MAGIC: [u8; 4] = *b"ZPS\0";
+
+/// Without identities, the chunk is expected to be only compressed (unencrypted archives).
 pub fn decrypt_and_decompress<R: Read, W: Write>(
     source: &mut R,
     sink: &mut W,
     len: u64,
     ids: &Vec<Box<dyn age::Identity>>,
 ) -> Result<()> {
-    let decryptor = age::Decryptor::new(source.take(len))?;
-    let mut decrypted_reader =
-        decryptor.decrypt(ids.iter().map(|k| k.as_ref() as &dyn age::Identity))?;
-    let mut decoder = Decoder::new(&mut decrypted_reader)?;
-    std::io::copy(&mut decoder, sink)?;
+    std::io::copy(&mut decoding_reader(source, len, ids)?, sink)?;
     Ok(())
 }
 pub fn decrypt_and_decompress_head<R: Read, W: Write>(
@@ -26,68 +56,221 @@ pub fn decrypt_and_decompress_head<R: Read, W: Write>(
     write_only: u64,
     ids: &Vec<Box<dyn age::Identity>>,
 ) -> Result<()> {
-    let decryptor = age::Decryptor::new(source.take(len))?;
-    let mut decrypted_reader =
-        decryptor.decrypt(ids.iter().map(|k| k.as_ref() as &dyn age::Identity))?;
-    let decoder = Decoder::new(&mut decrypted_reader)?;
-    std::io::copy(&mut decoder.take(write_only), sink)?;
+    std::io::copy(
+        &mut decoding_reader(source, len, ids)?.take(write_only),
+        sink,
+    )?;
     Ok(())
 }
 
+/// Without recipients, the chunk is only compressed (unencrypted archives).
 pub fn compress_and_encrypt<R: Read, W: Write>(
     source: &mut R,
     sink: &mut W,
+    codec: Codec,
     level: i32,
+    long_window: Option<u32>,
     recipients: &Vec<Box<dyn age::Recipient + Send>>,
 ) -> Result<()> {
-    let reps: Vec<Box<&dyn age::Recipient>> = recipients
-        .iter()
-        .map(|r| r.as_ref() as &dyn age::Recipient)
-        .map(Box::new)
-        .collect();
+    if recipients.is_empty() {
+        return compress(source, sink, codec, level, long_window);
+    }
+    let encryptor = age::Encryptor::with_recipients(
+        recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+    )?;
+    let mut encrypted_writer = encryptor.wrap_output(sink)?;
+    compress(source, &mut encrypted_writer, codec, level, long_window)?;
+    encrypted_writer.finish()?;
+    Ok(())
+}
+
+/// Only compresses, for chunks that are encrypted later (see `encrypt_only`).
+/// The long window only applies to zstd.
+pub fn compress<R: Read, W: Write>(
+    source: &mut R,
+    sink: &mut W,
+    codec: Codec,
+    level: i32,
+    long_window: Option<u32>,
+) -> Result<()> {
+    match codec {
+        Codec::Zstd => {
+            let mut compressor = Encoder::new(source, level)?;
+            if let Some(window_log) = long_window {
+                compressor.long_distance_matching(true)?;
+                compressor.window_log(window_log)?;
+            }
+            std::io::copy(&mut compressor, sink)?;
+        }
+        Codec::Gzip => {
+            let level = Compression::new(level.clamp(1, 9) as u32);
+            std::io::copy(&mut GzEncoder::new(source, level), sink)?;
+        }
+        Codec::None => {
+            sink.write_all(&STORED_MAGIC)?;
+            std::io::copy(source, sink)?;
+        }
+    }
+    Ok(())
+}
+
+/// The decoded content of a chunk as a reader, so it can be streamed into a new chunk.
+/// The codec is read from the first bytes of the chunk.
+pub fn decoding_reader<'a, R: Read>(
+    source: &'a mut R,
+    len: u64,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<Box<dyn Read + 'a>> {
+    owned_decoding_reader(source, len, ids)
+}
 
-    let mut compressor = Encoder::new(source, level)?;
+/// Like `decoding_reader`, but takes the source by value so the reader can be kept around.
+pub fn owned_decoding_reader<'a, R: Read + 'a>(
+    source: R,
+    len: u64,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<Box<dyn Read + 'a>> {
+    let mut plain: Box<dyn Read + 'a> = if ids.is_empty() {
+        Box::new(source.take(len))
+    } else {
+        let decryptor = age::Decryptor::new(source.take(len))?;
+        Box::new(decryptor.decrypt(ids.iter().map(|k| k.as_ref() as &dyn age::Identity))?)
+    };
+    let mut head = [0_u8; 4];
+    plain.read_exact(&mut head)?;
+    if head == STORED_MAGIC {
+        return Ok(plain);
+    }
+    let content = Cursor::new(head).chain(plain);
+    if head[..2] == GZIP_MAGIC {
+        return Ok(Box::new(MultiGzDecoder::new(content)));
+    }
+    let mut decoder = Decoder::new(content)?;
+    decoder.window_log_max(WINDOW_LOG_MAX)?;
+    Ok(Box::new(decoder))
+}
 
-    let encryptor = age::Encryptor::with_recipients(reps.iter().map(|k| *k.as_ref()))?;
+/// Like `compress_and_encrypt`, but stores the data without compression.
+pub fn encrypt_only<R: Read, W: Write>(
+    source: &mut R,
+    sink: &mut W,
+    recipients: &Vec<Box<dyn age::Recipient + Send>>,
+) -> Result<()> {
+    if recipients.is_empty() {
+        std::io::copy(source, sink)?;
+        return Ok(());
+    }
+    let encryptor = age::Encryptor::with_recipients(
+        recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+    )?;
     let mut encrypted_writer = encryptor.wrap_output(sink)?;
-    std::io::copy(&mut compressor, &mut encrypted_writer)?;
+    std::io::copy(source, &mut encrypted_writer)?;
     encrypted_writer.finish()?;
-
     Ok(())
 }
 
+/// Decodes the index, which is either compressed or stored as is (`--index-level 0`).
+/// A stored index starts with the magic number of the archive instead of a zstd frame.
+/// At most `write_only` bytes are written. Returns whether the index was compressed.
+pub fn decrypt_index<R: Read, W: Write>(
+    source: &mut R,
+    sink: &mut W,
+    len: u64,
+    write_only: u64,
+    ids: &Vec<Box<dyn age::Identity>>,
+) -> Result<bool> {
+    let mut plain: Box<dyn Read + '_> = if ids.is_empty() {
+        Box::new(source.take(len))
+    } else {
+        let decryptor = age::Decryptor::new(source.take(len))?;
+        Box::new(decryptor.decrypt(ids.iter().map(|k| k.as_ref() as &dyn age::Identity))?)
+    };
+    let mut head = [0_u8; 4];
+    plain.read_exact(&mut head)?;
+    let content = head.as_slice().chain(plain);
+    if head == ZSTD_MAGIC {
+        let mut decoder = Decoder::new(content)?;
+        decoder.window_log_max(WINDOW_LOG_MAX)?;
+        std::io::copy(&mut decoder.take(write_only), sink)?;
+        Ok(true)
+    } else {
+        std::io::copy(&mut content.take(write_only), sink)?;
+        Ok(false)
+    }
+}
+
+/// Opens a local archive, or the volumes of a split one (`<filename>.001`, ...).
 pub fn open_local_archive_read(filename: &str) -> Result<GenericFile> {
+    let path = Path::new(filename);
+    if !path.exists() && volume_path(path, 0).exists() {
+        return Ok(GenericFile::Volumes(Volumes::open(path)?));
+    }
     let f = std::fs::File::open(filename)?;
     let file = GenericFile::Local(f);
     Ok(file)
 }
-pub fn open_local_archive_write(filename: &str) -> Result<GenericFile> {
-    let f = std::fs::File::create_new(filename)?;
-    let file = GenericFile::Local(f);
-    Ok(file)
+pub fn open_local_archive_write(filename: &str, force: bool) -> Result<GenericFile> {
+    if !force {
+        return match std::fs::File::create_new(filename) {
+            Ok(f) => Ok(GenericFile::Local(f)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(anyhow!(ALREADY_EXISTS)),
+            Err(e) => Err(e.into()),
+        };
+    }
+    // Overwriting: write next to the target and only replace it once the archive is complete.
+    let temp = PathBuf::from(format!("{filename}.tmp"));
+    let f = std::fs::File::create(&temp)?;
+    Ok(GenericFile::Staged(f, temp, PathBuf::from(filename)))
 }
 
-pub fn open_remote_archive_read(
-    host: &str,
-    user: &str,
-    filename: &str,
-    port: u64,
-) -> Result<GenericFile> {
-    let tcp = TcpStream::connect(format!("{}:{}", host, port))?;
+/// Opens an existing local archive for reading and writing in place (for `update`).
+pub fn open_local_archive_update(filename: &str) -> Result<GenericFile> {
+    let path = Path::new(filename);
+    if !path.exists() && volume_path(path, 0).exists() {
+        return Err(anyhow!("Archives split into volumes can not be updated"));
+    }
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(filename)?;
+    Ok(GenericFile::Local(f))
+}
+
+/// Connects and logs in with the keys of the ssh agent. If none of them is accepted, the
+/// identity file (from `~/.ssh/config`) is tried. It must not have a passphrase.
+fn ssh_session(target: &RemoteTarget) -> Result<ssh2::Session> {
+    let tcp = TcpStream::connect(format!("{}:{}", target.host, target.port))?;
     let mut sess = ssh2::Session::new()?;
     sess.set_tcp_stream(tcp);
     sess.handshake()?;
-    // sess.userauth_agent(user)?;
-    let mut agent = sess.agent()?;
-    agent.connect()?;
-    agent.list_identities()?;
-    for identity in agent.identities()? {
-        if agent.userauth(user, &identity).is_ok() {
-            break;
+    if let Ok(mut agent) = sess.agent() {
+        if agent.connect().is_ok() && agent.list_identities().is_ok() {
+            for identity in agent.identities()? {
+                if agent.userauth(&target.user, &identity).is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+    if !sess.authenticated() {
+        if let Some(key) = &target.identity {
+            sess.userauth_pubkey_file(&target.user, None, key, None)?;
         }
     }
+    if !sess.authenticated() {
+        return Err(anyhow!(
+            "SSH authentication failed for {}@{}",
+            target.user,
+            target.host
+        ));
+    }
+    Ok(sess)
+}
+
+pub fn open_remote_archive_read(target: &RemoteTarget) -> Result<GenericFile> {
+    let sess = ssh_session(target)?;
     let sftp = sess.sftp()?;
-    let path = Path::new(filename);
+    let path = Path::new(&target.path);
     let path = if path.is_absolute() {
         path
     } else {
@@ -95,73 +278,425 @@ pub fn open_remote_archive_read(
     };
     let remote_file = sftp.open(path)?;
 
-    Ok(GenericFile::Remote(remote_file))
+    Ok(GenericFile::Remote(remote_file, Some(target.clone())))
 }
 
-pub fn open_remote_archive_write(
-    host: &str,
-    user: &str,
-    filename: &str,
-    port: u64,
-) -> Result<GenericFile> {
-    let tcp = TcpStream::connect(format!("{}:{}", host, port))?;
-    let mut sess = ssh2::Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
-    sess.userauth_agent(user)?;
+/// Opens an existing remote archive for reading and writing in place (for `update`).
+/// It is not reconnected, since a write may have been cut off.
+pub fn open_remote_archive_update(target: &RemoteTarget) -> Result<GenericFile> {
+    let sess = ssh_session(target)?;
     let sftp = sess.sftp()?;
-    let path = Path::new(filename);
+    let path = Path::new(&target.path);
     let path = if path.is_absolute() {
         path
     } else {
         &sftp.realpath(Path::new("."))?.join(path)
     };
-    if sftp.open(path).is_ok() {
-        return Err(anyhow!("Archive already exists"));
+    let remote_file = sftp.open_mode(
+        path,
+        ssh2::OpenFlags::READ | ssh2::OpenFlags::WRITE,
+        0o644,
+        ssh2::OpenType::File,
+    )?;
+    Ok(GenericFile::Remote(remote_file, None))
+}
+
+/// An SFTP session and the absolute path of the directory `target` points to.
+pub fn open_remote_dir(target: &RemoteTarget) -> Result<(ssh2::Sftp, PathBuf)> {
+    let sess = ssh_session(target)?;
+    let sftp = sess.sftp()?;
+    let path = sftp.realpath(Path::new(&target.path))?;
+    if !sftp.stat(&path)?.is_dir() {
+        return Err(anyhow!("{} is not a directory", target.path));
+    }
+    Ok((sftp, path))
+}
+
+pub fn open_remote_archive_write(target: &RemoteTarget, force: bool) -> Result<GenericFile> {
+    let sess = ssh_session(target)?;
+    let sftp = sess.sftp()?;
+    let path = Path::new(&target.path);
+    let path = if path.is_absolute() {
+        path
+    } else {
+        &sftp.realpath(Path::new("."))?.join(path)
+    };
+    if !force && sftp.open(path).is_ok() {
+        return Err(anyhow!(ALREADY_EXISTS));
+    }
+    // Like local overwrites, the archive only replaces the target once it is complete.
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let remote_file = sftp.create(&temp)?;
+
+    Ok(GenericFile::RemoteStaged(
+        remote_file,
+        sftp,
+        temp,
+        path.to_path_buf(),
+    ))
+}
+
+/// Where a remote archive is, and where one that was opened for reading can be opened again.
+#[derive(Clone, Debug)]
+pub struct RemoteTarget {
+    pub host: String,
+    pub user: String,
+    pub path: String,
+    pub port: u16,
+    /// A private key to log in with if the ssh agent has none that is accepted.
+    pub identity: Option<PathBuf>,
+}
+
+/// Marks I/O errors of writing a remote archive, the only ones worth starting an upload
+/// again for (see `is_upload_error`).
+#[derive(Debug)]
+struct UploadError(std::io::Error);
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
     }
-    let remote_file = sftp.create(path)?;
+}
+
+impl std::error::Error for UploadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
 
-    Ok(GenericFile::Remote(remote_file))
+fn upload_error(e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), UploadError(e))
+}
+
+/// Whether writing a remote archive failed, as opposed to reading the source files.
+pub fn is_upload_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|inner| inner.is::<UploadError>())
+    })
 }
 
 pub enum GenericFile {
     Local(std::fs::File),
-    Remote(ssh2::File),
+    /// A remote archive and, if it is read-only, where to reconnect to.
+    Remote(ssh2::File, Option<RemoteTarget>),
+    Throttled(Box<GenericFile>, TokenBucket),
+    /// A local file written under a temporary name that replaces the target on `persist`.
+    Staged(std::fs::File, PathBuf, PathBuf),
+    /// The same for a remote archive, with the SFTP session needed for the rename.
+    RemoteStaged(ssh2::File, ssh2::Sftp, PathBuf, PathBuf),
+    /// A delta archive and its base. Offsets flagged with `BASE_CHUNK` are read from the base.
+    Layered(Box<GenericFile>, Box<GenericFile>, bool),
+    /// Several archives read through one merged index (see `Index::union`), with the
+    /// layer that is currently read from. Read-only.
+    Union(Vec<GenericFile>, usize),
+    /// A local archive split into volumes of a fixed size.
+    Volumes(Volumes),
+}
+
+impl GenericFile {
+    /// Moves a staged archive into place. Other files are already where they belong.
+    /// With `fsync`, local archives (and the directory of a renamed one) are synced to disk
+    /// first, so a crash right afterwards cannot leave an incomplete archive behind.
+    pub fn persist(mut self, fsync: bool) -> Result<()> {
+        self.flush()?;
+        match self {
+            GenericFile::Local(f) if fsync => f.sync_all()?,
+            GenericFile::Staged(f, temp, target) => {
+                if fsync {
+                    f.sync_all()?;
+                }
+                drop(f);
+                std::fs::rename(&temp, &target)?;
+                if fsync {
+                    let dir = match target.parent() {
+                        Some(dir) if dir != Path::new("") => dir,
+                        _ => Path::new("."),
+                    };
+                    std::fs::File::open(dir)?.sync_all()?;
+                }
+            }
+            GenericFile::RemoteStaged(f, sftp, temp, target) => {
+                drop(f);
+                // SFTP servers usually refuse to rename onto an existing file.
+                if sftp.stat(&target).is_ok() {
+                    sftp.unlink(&target)?;
+                }
+                sftp.rename(&temp, &target, None)?;
+            }
+            GenericFile::Throttled(inner, _) => inner.persist(fsync)?,
+            GenericFile::Volumes(v) => v.persist(fsync)?,
+            _ => {}
+        }
+        Ok(())
+    }
+    /// Removes the temporary file of a staged archive that could not be completed.
+    pub fn discard(self) {
+        match self {
+            GenericFile::Staged(f, temp, _) => {
+                drop(f);
+                let _ = std::fs::remove_file(temp);
+            }
+            GenericFile::RemoteStaged(f, sftp, temp, _) => {
+                drop(f);
+                let _ = sftp.unlink(&temp);
+            }
+            GenericFile::Volumes(v) => v.remove(),
+            _ => {}
+        }
+    }
+
+    /// Cuts the archive off at `len` bytes, for example after a chunk was written over again.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        match self {
+            GenericFile::Local(f) | GenericFile::Staged(f, _, _) => f.set_len(len)?,
+            GenericFile::Remote(f, _) | GenericFile::RemoteStaged(f, _, _, _) => {
+                let mut stat = f.stat()?;
+                stat.size = Some(len);
+                f.setstat(stat)?;
+            }
+            GenericFile::Throttled(f, _) | GenericFile::Layered(f, _, _) => f.set_len(len)?,
+            GenericFile::Union(_, _) => return Err(anyhow!("Archive unions are read-only")),
+            GenericFile::Volumes(v) => v.set_len(len)?,
+        }
+        Ok(())
+    }
+
+    /// Resolves chunks of a delta archive that are stored in its base archive.
+    pub fn with_base(self, base: GenericFile) -> Self {
+        GenericFile::Layered(Box::new(self), Box::new(base), false)
+    }
+
+    /// Whether any part of the archive is read over SFTP.
+    pub fn is_remote(&self) -> bool {
+        match self {
+            GenericFile::Remote(_, _) | GenericFile::RemoteStaged(_, _, _, _) => true,
+            GenericFile::Throttled(f, _) => f.is_remote(),
+            GenericFile::Layered(delta, base, _) => delta.is_remote() || base.is_remote(),
+            GenericFile::Union(layers, _) => layers.iter().any(|l| l.is_remote()),
+            _ => false,
+        }
+    }
+
+    /// Opens new connections for all remote archives that were opened for reading.
+    /// Returns whether there was anything to reconnect.
+    pub fn reconnect(&mut self) -> Result<bool> {
+        match self {
+            GenericFile::Remote(f, Some(target)) => {
+                let reopened = open_remote_archive_read(target)?;
+                if let GenericFile::Remote(new, _) = reopened {
+                    *f = new;
+                }
+                Ok(true)
+            }
+            GenericFile::Throttled(f, _) => f.reconnect(),
+            GenericFile::Layered(delta, base, _) => Ok(delta.reconnect()? | base.reconnect()?),
+            GenericFile::Union(layers, _) => {
+                let mut any = false;
+                for layer in layers {
+                    any |= layer.reconnect()?;
+                }
+                Ok(any)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Limits reads from remote archives to `bytes_per_second`. Local archives are not limited.
+    pub fn rate_limited(self, bytes_per_second: u64) -> Self {
+        match self {
+            GenericFile::Local(_) | GenericFile::Volumes(_) => self,
+            other => GenericFile::Throttled(Box::new(other), TokenBucket::new(bytes_per_second)),
+        }
+    }
+}
+
+/// A token bucket that holds at most one second worth of bytes.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            rate: bytes_per_second as f64,
+            tokens: bytes_per_second as f64,
+            last: Instant::now(),
+        }
+    }
+    fn take(&mut self, n: usize) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
 }
 
 impl Read for GenericFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
-            GenericFile::Remote(f) => f.read(buf),
+            GenericFile::Remote(f, _) => f.read(buf),
             GenericFile::Local(f) => f.read(buf),
+            GenericFile::Staged(f, _, _) => f.read(buf),
+            GenericFile::RemoteStaged(f, _, _, _) => f.read(buf),
+            GenericFile::Throttled(f, bucket) => {
+                let n = f.read(buf)?;
+                bucket.take(n);
+                Ok(n)
+            }
+            GenericFile::Layered(_, base, true) => base.read(buf),
+            GenericFile::Layered(delta, _, false) => delta.read(buf),
+            GenericFile::Union(layers, current) => layers[*current].read(buf),
+            GenericFile::Volumes(v) => v.read(buf),
         }
     }
 }
 
 impl Seek for GenericFile {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        match self {
-            GenericFile::Remote(f) => f.seek(pos),
-            GenericFile::Local(f) => f.seek(pos),
+        let base_pos = match pos {
+            SeekFrom::Start(i) if i & BASE_CHUNK != 0 => Some(i & !BASE_CHUNK),
+            _ => None,
+        };
+        match (self, base_pos) {
+            (GenericFile::Union(layers, current), _) => {
+                let layer_bits = !0_u64 << LAYER_SHIFT;
+                if let SeekFrom::Start(i) = pos {
+                    *current = (i >> LAYER_SHIFT) as usize;
+                }
+                let tag = (*current as u64) << LAYER_SHIFT;
+                let layer = layers
+                    .get_mut(*current)
+                    .ok_or_else(|| std::io::Error::other("Unknown archive layer"))?;
+                let pos = match pos {
+                    SeekFrom::Start(i) => SeekFrom::Start(i & !layer_bits),
+                    other => other,
+                };
+                Ok(layer.seek(pos)? | tag)
+            }
+            (GenericFile::Layered(_, base, current), Some(i)) => {
+                *current = true;
+                Ok(base.seek(SeekFrom::Start(i))? | BASE_CHUNK)
+            }
+            (GenericFile::Layered(_, base, true), None) if matches!(pos, SeekFrom::Current(_)) => {
+                Ok(base.seek(pos)? | BASE_CHUNK)
+            }
+            (GenericFile::Layered(delta, _, current), None) => {
+                *current = false;
+                delta.seek(pos)
+            }
+            (GenericFile::Throttled(f, _), _) => f.seek(pos),
+            (_, Some(_)) => Err(std::io::Error::other(
+                "The file is stored in the base archive (use --base)",
+            )),
+            (GenericFile::Remote(f, _), None) => f.seek(pos),
+            (GenericFile::Local(f), None) => f.seek(pos),
+            (GenericFile::Staged(f, _, _), None) => f.seek(pos),
+            (GenericFile::RemoteStaged(f, _, _, _), None) => f.seek(pos).map_err(upload_error),
+            (GenericFile::Volumes(v), None) => v.seek(pos),
         }
     }
 }
 impl Write for GenericFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self {
-            GenericFile::Remote(f) => f.write(buf),
+            GenericFile::Remote(f, _) => f.write(buf),
             GenericFile::Local(f) => f.write(buf),
+            GenericFile::Throttled(f, _) => f.write(buf),
+            GenericFile::Staged(f, _, _) => f.write(buf),
+            GenericFile::RemoteStaged(f, _, _, _) => f.write(buf).map_err(upload_error),
+            GenericFile::Layered(f, _, _) => f.write(buf),
+            GenericFile::Union(_, _) => Err(std::io::Error::other("Archive unions are read-only")),
+            GenericFile::Volumes(v) => v.write(buf),
         }
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         match self {
-            GenericFile::Remote(f) => f.flush(),
+            GenericFile::Remote(f, _) => f.flush(),
             GenericFile::Local(f) => f.flush(),
+            GenericFile::Throttled(f, _) => f.flush(),
+            GenericFile::Staged(f, _, _) => f.flush(),
+            GenericFile::RemoteStaged(f, _, _, _) => f.flush().map_err(upload_error),
+            GenericFile::Layered(f, _, _) => f.flush(),
+            GenericFile::Union(_, _) => Ok(()),
+            GenericFile::Volumes(v) => v.flush(),
         }
     }
 }
 
+/// Independent connections to the same archive, used to fetch chunks concurrently.
+/// Each connection is its own SFTP session, so servers may limit how many can be opened.
+pub struct ArchivePool {
+    files: Vec<GenericFile>,
+}
+
+impl ArchivePool {
+    pub fn new(files: Vec<GenericFile>) -> Self {
+        Self { files }
+    }
+
+    /// Fetches the raw (still encrypted) chunks over all connections and hands them to
+    /// `handle` on the calling thread, in the order they arrive.
+    pub fn for_each_chunk<F>(&mut self, chunks: &[(u64, u64)], mut handle: F) -> Result<()>
+    where
+        F: FnMut(usize, &[u8]) -> Result<()>,
+    {
+        let workers = self.files.len().max(1);
+        std::thread::scope(|scope| {
+            let (tx, rx) = std::sync::mpsc::sync_channel(workers * 2);
+            for (w, file) in self.files.iter_mut().enumerate() {
+                let tx = tx.clone();
+                scope.spawn(move || {
+                    let archive_len = file.seek(std::io::SeekFrom::End(0));
+                    for (j, (start, len)) in chunks.iter().enumerate().skip(w).step_by(workers) {
+                        let chunk = match &archive_len {
+                            Ok(archive_len) => read_chunk(file, *start, *len, *archive_len),
+                            Err(e) => Err(anyhow!("Archive length could not be read: {e}")),
+                        };
+                        let failed = chunk.is_err();
+                        if tx.send((j, chunk)).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+            for (j, chunk) in rx {
+                handle(j, &chunk?)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Reads a chunk whose position comes from the index. It is checked against the length of
+/// the archive (unless it is in a base archive or another layer), and the buffer only
+/// grows with what is actually read.
+fn read_chunk(file: &mut GenericFile, start: u64, len: u64, archive_len: u64) -> Result<Vec<u8>> {
+    let own_chunk = start >> LAYER_SHIFT == 0;
+    if own_chunk && start.checked_add(len).is_none_or(|end| end > archive_len) {
+        return Err(anyhow!("Corrupt archive: chunk at {start} out of range"));
+    }
+    file.seek(std::io::SeekFrom::Start(start))?;
+    let mut chunk = vec![];
+    file.take(len).read_to_end(&mut chunk)?;
+    if chunk.len() as u64 != len {
+        return Err(anyhow!("Archive is truncated"));
+    }
+    Ok(chunk)
+}
+
 pub fn blake3_hash_streaming<R: Read>(source: &mut R) -> Result<[u8; 32]> {
     let mut hasher = blake3::Hasher::new();
     hasher.update_reader(source)?;
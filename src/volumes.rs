@@ -0,0 +1,282 @@
+use anyhow::{Result, anyhow};
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// An archive split into `<name>.001`, `<name>.002`, ... Every volume but the last one is
+/// exactly `volume_size` bytes long. Offsets are the same as in a single file, so the
+/// index does not know about the volumes.
+pub struct Volumes {
+    name: PathBuf,
+    files: Vec<File>,
+    volume_size: u64,
+    position: u64,
+    len: u64,
+    /// New volumes are written as `<volume>.tmp` and only replace an existing set on `persist`.
+    staged: bool,
+}
+
+/// The file name of volume `i` (counted from 0).
+pub fn volume_path(name: &Path, i: usize) -> PathBuf {
+    let mut path = name.as_os_str().to_owned();
+    path.push(format!(".{:03}", i + 1));
+    PathBuf::from(path)
+}
+
+impl Volumes {
+    /// Opens all volumes of `name`, starting with `<name>.001`.
+    pub fn open(name: &Path) -> Result<Self> {
+        let mut files = vec![];
+        let mut sizes = vec![];
+        loop {
+            let path = volume_path(name, files.len());
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            };
+            sizes.push(file.metadata()?.len());
+            files.push(file);
+        }
+        let Some(first) = sizes.first() else {
+            return Err(anyhow!(
+                "{} not found",
+                volume_path(name, 0).to_string_lossy()
+            ));
+        };
+        let volume_size = if sizes.len() == 1 { u64::MAX } else { *first };
+        if volume_size == 0 {
+            return Err(anyhow!(
+                "{} is empty (missing or damaged volume?)",
+                volume_path(name, 0).to_string_lossy()
+            ));
+        }
+        if let Some(i) = sizes[..sizes.len() - 1]
+            .iter()
+            .position(|s| *s != volume_size)
+        {
+            return Err(anyhow!(
+                "{} has a different size than the first volume (missing or damaged volume?)",
+                volume_path(name, i).to_string_lossy()
+            ));
+        }
+        Ok(Self {
+            name: name.to_path_buf(),
+            files,
+            volume_size,
+            position: 0,
+            len: sizes.iter().sum(),
+            staged: false,
+        })
+    }
+
+    /// Starts a new set of volumes. Volumes are created as they are needed.
+    /// Existing volumes are only replaced with `force`, and only once the new set is complete.
+    pub fn create(name: &Path, volume_size: u64, force: bool) -> Result<Self> {
+        if volume_size == 0 {
+            return Err(anyhow!("The volume size must not be 0"));
+        }
+        let staged = volume_path(name, 0).exists();
+        if staged && !force {
+            return Err(anyhow!(
+                "{} already exists (use --force to overwrite)",
+                volume_path(name, 0).to_string_lossy()
+            ));
+        }
+        Ok(Self {
+            name: name.to_path_buf(),
+            files: vec![],
+            volume_size,
+            position: 0,
+            len: 0,
+            staged,
+        })
+    }
+
+    /// Where volume `i` is written.
+    fn file_path(&self, i: usize) -> PathBuf {
+        let path = volume_path(&self.name, i);
+        if !self.staged {
+            return path;
+        }
+        let mut temp = path.into_os_string();
+        temp.push(".tmp");
+        PathBuf::from(temp)
+    }
+
+    /// Moves staged volumes into place and removes the rest of the set they replace.
+    pub fn persist(self, fsync: bool) -> Result<()> {
+        if fsync {
+            self.sync_all()?;
+        }
+        if !self.staged {
+            return Ok(());
+        }
+        for i in 0..self.files.len() {
+            std::fs::rename(self.file_path(i), volume_path(&self.name, i))?;
+        }
+        // Later volumes of an older, larger set would otherwise be read as part of it.
+        let mut i = self.files.len();
+        while volume_path(&self.name, i).exists() {
+            std::fs::remove_file(volume_path(&self.name, i))?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    pub fn sync_all(&self) -> Result<()> {
+        for file in &self.files {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Removes all volumes, for an archive that could not be completed.
+    /// A set that was about to be replaced is kept.
+    pub fn remove(self) {
+        for i in 0..self.files.len() {
+            let _ = std::fs::remove_file(self.file_path(i));
+        }
+    }
+
+    /// Cuts the archive off at `len` bytes, removing volumes that are no longer needed.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        let needed = len.div_ceil(self.volume_size).max(1) as usize;
+        while self.files.len() > needed {
+            self.files.pop();
+            std::fs::remove_file(self.file_path(self.files.len()))?;
+        }
+        if let Some(last) = self.files.last() {
+            last.set_len(len - (self.files.len() as u64 - 1) * self.volume_size)?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// The volume holding the current position and the offset within it.
+    fn locate(&self) -> (usize, u64) {
+        (
+            (self.position / self.volume_size) as usize,
+            self.position % self.volume_size,
+        )
+    }
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (i, offset) = self.locate();
+        let Some(file) = self.files.get_mut(i) else {
+            return Ok(0);
+        };
+        let max = (self.volume_size - offset).min(buf.len() as u64) as usize;
+        file.seek(SeekFrom::Start(offset))?;
+        let n = file.read(&mut buf[..max])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Volumes {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (i, offset) = self.locate();
+        while self.files.len() <= i {
+            let path = self.file_path(self.files.len());
+            self.files.push(
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?,
+            );
+        }
+        let max = (self.volume_size - offset).min(buf.len() as u64) as usize;
+        let file = &mut self.files[i];
+        file.seek(SeekFrom::Start(offset))?;
+        let n = file.write(&buf[..max])?;
+        self.position += n as u64;
+        self.len = self.len.max(self.position);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for file in &mut self.files {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for Volumes {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(i) => Some(i),
+            SeekFrom::End(i) => self.len.checked_add_signed(i),
+            SeekFrom::Current(i) => self.position.checked_add_signed(i),
+        };
+        self.position = new.ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek before the start of the archive",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archiver::{SourceTree, build_archive};
+    use crate::restore::{RestoreSettings, restore_command};
+    use crate::testutil::{scratch_dir, test_settings, write_files};
+    use crate::utils::{GenericFile, open_local_archive_read};
+
+    #[test]
+    fn restore_from_volumes() {
+        let dir = scratch_dir("volumes");
+        let source = dir.join("source");
+        // Barely compressible, so the chunk spans several volumes.
+        let mut state = 0x2545_f491_u32;
+        let noise = (0..5000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        write_files(&source, &[("noise.bin", &noise), ("small.txt", b"small")]);
+        let name = dir.join("split.zprt");
+        let mut archive = GenericFile::Volumes(Volumes::create(&name, 1000, false).unwrap());
+        build_archive(
+            &SourceTree::Local(source),
+            &mut archive,
+            &vec![],
+            &test_settings(),
+        )
+        .unwrap();
+        archive.persist(false).unwrap();
+        assert!(!name.exists());
+        let mut sizes = vec![];
+        while let Ok(metadata) = std::fs::metadata(volume_path(&name, sizes.len())) {
+            sizes.push(metadata.len());
+        }
+        assert!(sizes.len() > 2, "{sizes:?}");
+        assert!(sizes[..sizes.len() - 1].iter().all(|s| *s == 1000));
+
+        let to = dir.join("restored");
+        restore_command(
+            &mut open_local_archive_read(name.to_str().unwrap()).unwrap(),
+            Path::new(""),
+            &to,
+            &vec![],
+            &RestoreSettings::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(to.join("noise.bin")).unwrap(), noise);
+        assert_eq!(std::fs::read(to.join("small.txt")).unwrap(), b"small");
+    }
+}